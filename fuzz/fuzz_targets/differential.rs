@@ -0,0 +1,16 @@
+#![no_main]
+
+use bfc::fuzz;
+use libfuzzer_sys::fuzz_target;
+
+// Compiling with `cc` for every input is far too slow for libFuzzer, so this
+// only compares the evaluator before and after each optimizer pass. The C
+// backend is covered by the runner in `cargo test`.
+fuzz_target!(|data: &[u8]| {
+    let case = fuzz::generate(&mut fuzz::Bytes::new(data));
+    if fuzz::check(&case, false).is_err() {
+        let case = fuzz::shrink(case, |c| fuzz::check(c, false).is_err());
+        let failure = fuzz::check(&case, false).unwrap_err();
+        panic!("{:?} with input {:?}: {}", case.code, case.input, failure);
+    }
+});
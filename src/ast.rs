@@ -8,6 +8,16 @@ pub enum AST {
     Decr,
     Putch,
     Getch,
+    // Extensions
+    Dump,
+    // Run-length encoded `Incr`s and `Decr`s, adding modulo 256, and `PtrAdvance`s
+    // and `PtrRetreat`s. Never zero.
+    Add(u8),
+    PtrMove(i32),
+    // The nodes repeated a number of times
+    Repeat(Vec<AST>, usize),
+    // Text between commands, only kept when asked for
+    Comment(String),
 }
 
 #[derive(Debug, PartialEq)]
@@ -0,0 +1,424 @@
+use crate::ir::{IRProgram, Offset, Value, IR};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Lowers IR back to brainfuck. Brainfuck has no offsets, so the pointer is
+// moved to each cell as it is used and only brought back in line with the IR's
+// pointer at loop boundaries. Large constants and multiplications that keep
+// their source use a scratch cell, which must be known to be zero.
+
+const WIDTH: usize = 80;
+
+pub struct BFEmitter {
+    code: String,
+    // Where the brainfuck pointer is, relative to the IR's
+    cur: Offset,
+    // What is known of the cells around the IR's pointer: `None` if the value
+    // isn't known
+    cells: HashMap<Offset, Option<u8>>,
+    // The tape index of the IR's pointer until the first loop that moves it.
+    // Until then, cells that aren't in `cells` are zero.
+    origin: Option<i64>,
+    mem_size: usize,
+}
+
+impl BFEmitter {
+    pub fn emit(
+        prog: &IRProgram,
+        _nostdlib: bool,
+        mem_size: usize,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut e = Self {
+            code: String::new(),
+            cur: 0,
+            cells: HashMap::new(),
+            origin: Some(0),
+            mem_size,
+        };
+        e.emit_nodes(&prog.0, true)?;
+        for line in e.code.as_bytes().chunks(WIDTH) {
+            out.write_all(line)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn value(&self, off: Offset) -> Option<u8> {
+        match self.cells.get(&off) {
+            Some(val) => *val,
+            None => self.origin.map(|_| 0),
+        }
+    }
+
+    // Whether `off` is free to use as a scratch cell
+    fn is_scratch(&self, off: Offset) -> bool {
+        if self.value(off) != Some(0) {
+            return false;
+        }
+        // Cells that were never used may be off the tape
+        self.cells.contains_key(&off)
+            || self
+                .origin
+                .is_some_and(|o| (0..self.mem_size as i64).contains(&(o + off as i64)))
+    }
+
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.origin = None;
+    }
+
+    fn move_to(&mut self, off: Offset) {
+        let c = if off > self.cur { '>' } else { '<' };
+        self.code
+            .extend(std::iter::repeat_n(c, off.abs_diff(self.cur) as usize));
+        self.cur = off;
+    }
+
+    // `+` or `-` at the brainfuck pointer, the shorter way around
+    fn run(&mut self, amt: i32) {
+        let amt = amt.rem_euclid(256);
+        let (c, n) = if amt > 128 {
+            ('-', 256 - amt)
+        } else {
+            ('+', amt)
+        };
+        self.code.extend(std::iter::repeat_n(c, n as usize));
+    }
+
+    fn add(&mut self, off: Offset, amt: u8) {
+        if amt != 0 {
+            self.add_const(off, amt);
+        }
+        let val = self.value(off).map(|v| v.wrapping_add(amt));
+        self.cells.insert(off, val);
+    }
+
+    // Adds `amt` as `scratch:+a[off:+b scratch:-]off:+c` when that is shorter
+    fn add_const(&mut self, off: Offset, amt: u8) {
+        let plain = (amt as i32).min(256 - amt as i32);
+        let mut best: Option<(i32, Offset, i32, i32, i32)> = None;
+        for scratch in [off + 1, off - 1, off + 2, off - 2] {
+            if !self.is_scratch(scratch) {
+                continue;
+            }
+            for target in [amt as i32, amt as i32 - 256] {
+                for a in 2..=16 {
+                    let b = (target as f64 / a as f64).round() as i32;
+                    let c = target - a * b;
+                    let cost = a + b.abs() + c.abs() + 3 + 2 * (scratch - off).abs();
+                    if b != 0 && cost < best.map_or(plain, |(cost, ..)| cost) {
+                        best = Some((cost, scratch, a, b, c));
+                    }
+                }
+            }
+        }
+        match best {
+            Some((_, scratch, a, b, c)) => {
+                self.move_to(scratch);
+                self.run(a);
+                self.code.push('[');
+                self.move_to(off);
+                self.run(b);
+                self.move_to(scratch);
+                self.code.push_str("-]");
+                if c != 0 {
+                    self.move_to(off);
+                    self.run(c);
+                }
+            }
+            None => {
+                self.move_to(off);
+                self.run(amt as i32);
+            }
+        }
+    }
+
+    fn emit_nodes(&mut self, irs: &[IR], top_level: bool) -> io::Result<()> {
+        let mut i = 0;
+        while i < irs.len() {
+            let ir = &irs[i];
+            i += 1;
+            match ir {
+                IR::PtrChange(amt) => {
+                    self.cur -= amt;
+                    self.cells = self.cells.drain().map(|(k, v)| (k - amt, v)).collect();
+                    self.origin = self.origin.map(|o| o + *amt as i64);
+                }
+                IR::Add(off, amt) => self.add(*off, *amt as u8),
+                IR::MovImm(off, val) => {
+                    let val = *val as u8;
+                    let cur = match self.value(*off) {
+                        Some(cur) => cur,
+                        None => {
+                            self.move_to(*off);
+                            self.code.push_str("[-]");
+                            0
+                        }
+                    };
+                    self.cells.insert(*off, Some(cur));
+                    self.add(*off, val.wrapping_sub(cur));
+                }
+                IR::Putch(off) => {
+                    self.move_to(*off);
+                    self.code.push('.');
+                }
+                IR::Getch(off) => {
+                    self.move_to(*off);
+                    self.code.push(',');
+                    self.cells.insert(*off, None);
+                }
+                IR::Dump => {
+                    self.move_to(0);
+                    self.code.push('#');
+                }
+                IR::Loop(inner) => self.emit_loop(inner, 0)?,
+                IR::SimpleLoop(delta, inner) => self.emit_loop(inner, *delta)?,
+                IR::AddMul(..) => {
+                    let start = i - 1;
+                    while matches!(irs.get(i), Some(IR::AddMul(..))) {
+                        i += 1;
+                    }
+                    let muls: Vec<(Offset, Value)> = irs[start..i]
+                        .iter()
+                        .map(|ir| match ir {
+                            IR::AddMul(off, amt) => (*off, *amt),
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    self.emit_muls(&muls, &irs[i..], top_level)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A `SimpleLoop` is a loop with `delta` added at the end of each iteration
+    // If the body leaves the pointer where it was, only the cells it writes
+    // are forgotten.
+    fn emit_loop(&mut self, inner: &[IR], delta: Value) -> io::Result<()> {
+        self.move_to(0);
+        self.code.push('[');
+        match writes(inner) {
+            Some(offs) => {
+                for off in offs {
+                    self.cells.insert(off, None);
+                }
+                self.cells.insert(0, None);
+            }
+            None => self.forget(),
+        }
+        let (cells, origin) = (self.cells.clone(), self.origin);
+        self.emit_nodes(inner, false)?;
+        self.move_to(0);
+        self.run(delta as i32);
+        self.code.push(']');
+        (self.cells, self.origin) = (cells, origin);
+        self.cells.insert(0, Some(0));
+        Ok(())
+    }
+
+    // Adds multiples of the current cell to others. Counting the cell down
+    // clears it, so unless it is dead in `rest` it is copied back from a
+    // scratch cell, or the multiples are added as constants if it is known.
+    // Without a cell known to be zero, one that `rest` overwrites is cleared.
+    fn emit_muls(
+        &mut self,
+        muls: &[(Offset, Value)],
+        rest: &[IR],
+        top_level: bool,
+    ) -> io::Result<()> {
+        let val = self.value(0);
+        let scratch = match val {
+            _ if is_dead(rest, 0, top_level) => None,
+            Some(val) => {
+                for (off, amt) in muls {
+                    self.add(*off, val.wrapping_mul(*amt as u8));
+                }
+                return Ok(());
+            }
+            None => {
+                let free = |off: &Offset| muls.iter().all(|(o, _)| o != off);
+                let near = || (1..=8).flat_map(|d| [d, -d]).filter(free);
+                match near().find(|off| self.is_scratch(*off)) {
+                    Some(off) => Some(off),
+                    None => match near().find(|off| is_dead(rest, *off, false)) {
+                        Some(off) => {
+                            self.move_to(off);
+                            self.code.push_str("[-]");
+                            self.cells.insert(off, Some(0));
+                            Some(off)
+                        }
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "no scratch cell to keep the source of an addmul",
+                            ))
+                        }
+                    },
+                }
+            }
+        };
+        self.move_to(0);
+        self.code.push_str("[-");
+        for (off, amt) in muls {
+            self.move_to(*off);
+            self.run(*amt as i32);
+            let sum = val.zip(self.value(*off));
+            let sum = sum.map(|(v, t)| t.wrapping_add(v.wrapping_mul(*amt as u8)));
+            self.cells.insert(*off, sum);
+        }
+        if let Some(scratch) = scratch {
+            self.move_to(scratch);
+            self.code.push('+');
+        }
+        self.move_to(0);
+        self.code.push(']');
+        match scratch {
+            Some(scratch) => {
+                self.move_to(scratch);
+                self.code.push_str("[-");
+                self.move_to(0);
+                self.code.push('+');
+                self.move_to(scratch);
+                self.code.push(']');
+            }
+            None => {
+                self.cells.insert(0, Some(0));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The cells that `irs` may write, relative to the pointer, if it leaves the
+// pointer where it was
+fn writes(irs: &[IR]) -> Option<Vec<Offset>> {
+    let mut ret = Vec::new();
+    let mut ptr = 0;
+    for ir in irs {
+        match ir {
+            IR::PtrChange(amt) => ptr += amt,
+            IR::Add(off, _) | IR::MovImm(off, _) | IR::Getch(off) | IR::AddMul(off, _) => {
+                ret.push(ptr + off)
+            }
+            IR::Loop(inner) | IR::SimpleLoop(_, inner) => {
+                ret.push(ptr);
+                ret.extend(writes(inner)?.into_iter().map(|off| ptr + off));
+            }
+            IR::Putch(_) | IR::Dump => {}
+        }
+    }
+    (ptr == 0).then_some(ret)
+}
+
+// Whether the cell at `cell` is written before it is read again by `rest`, or
+// never read again by a program that ends with `rest`
+fn is_dead(rest: &[IR], mut cell: Offset, top_level: bool) -> bool {
+    for ir in rest {
+        match ir {
+            IR::PtrChange(amt) => cell -= amt,
+            IR::MovImm(off, _) | IR::Getch(off) if *off == cell => return true,
+            IR::Add(off, _) | IR::Putch(off) if *off == cell => return false,
+            IR::AddMul(off, _) if cell == 0 || *off == cell => return false,
+            IR::Loop(_) | IR::SimpleLoop(..) | IR::Dump => return false,
+            _ => {}
+        }
+    }
+    top_level
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::{self, Limits, StreamIO};
+    use crate::parser::Parser;
+    use crate::{ir_parser, optimize};
+
+    fn emit(prog: &IRProgram) -> String {
+        let mut out = Vec::new();
+        BFEmitter::emit(prog, false, 30000, &mut out).unwrap();
+        String::from_utf8(out).unwrap().replace('\n', "")
+    }
+
+    fn output(prog: &IRProgram, input: &[u8]) -> Vec<u8> {
+        let mut io = StreamIO::new(input, Vec::new());
+        let limits = Limits {
+            max_steps: Some(1_000_000_000),
+            ..Default::default()
+        };
+        let res = eval::eval_with_io(prog, &mut io, &limits).unwrap();
+        assert_eq!(res.status, eval::Status::Completed);
+        io.output
+    }
+
+    fn reparse(code: &str) -> IRProgram {
+        IRProgram::from_ast_program(&Parser::parse(code).unwrap())
+    }
+
+    // Programs that don't finish, or whose output depends on what happens at
+    // EOF, are run for a bounded number of steps. The brainfuck needs at
+    // least as many steps as the IR, so its output is a prefix of the IR's.
+    #[test]
+    fn test_bounded() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        for (name, input) in [("fib", ""), ("golden", ""), ("sh", "echo hi\nls\n")] {
+            let code = std::fs::read_to_string(root.join(format!("programs/{}.b", name))).unwrap();
+            let prog = optimize::optimize(&reparse(&code));
+            let limits = Limits {
+                max_steps: Some(1_000_000),
+                ..Default::default()
+            };
+            let mut outputs = [&prog, &reparse(&emit(&prog))].map(|prog| {
+                let mut io = StreamIO::new(input.as_bytes(), Vec::new());
+                eval::eval_with_io(prog, &mut io, &limits).unwrap();
+                io.output
+            });
+            let [expected, bf] = &mut outputs;
+            assert!(!bf.is_empty(), "{}", name);
+            assert_eq!(expected.get(..bf.len()), Some(&bf[..]), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        // Large constants are multiplied on fresh cells
+        let prog = ir_parser::Parser::parse("movimm 0, 72\nputch 0\n").unwrap();
+        assert_eq!(emit(&prog), ">++++++++[<+++++++++>-]<.");
+        let prog = ir_parser::Parser::parse("movimm 0, 72\nmovimm +1, 100\n").unwrap();
+        assert_eq!(
+            emit(&prog),
+            ">++++++++[<+++++++++>-]>++++++++++[<++++++++++>-]"
+        );
+        // and set from what is known of the cell
+        let prog = ir_parser::Parser::parse("movimm 0, 3\nputch 0\nmovimm 0, 1\n").unwrap();
+        assert_eq!(emit(&prog), "+++.--");
+        let prog = ir_parser::Parser::parse("getch 0\nmovimm 0, -1\nputch 0\n").unwrap();
+        assert_eq!(emit(&prog), ",[-]-.");
+    }
+
+    #[test]
+    fn test_muls() {
+        // The source is cleared by the `movimm`, so it can be counted down
+        let prog = ir_parser::Parser::parse("getch 0\naddmul +1, 2\nmovimm 0, 0\n").unwrap();
+        assert_eq!(emit(&prog), ",[->++<]");
+        // It is read again, so it's copied back through a scratch cell
+        let code = "getch 0\naddmul +1, -1\nputch 0\nputch +1\n";
+        let prog = ir_parser::Parser::parse(code).unwrap();
+        let bf = emit(&prog);
+        assert_eq!(bf, ",[->->+<<]>>[-<<+>>]<<.>.");
+        assert_eq!(output(&reparse(&bf), &[3]), output(&prog, &[3]));
+        // After a loop that leaves the pointer where it was, cells it doesn't
+        // write are still zero
+        let code = "getch 0\nloop {\n  addmul +1, 2\n  putch 0\n}\n";
+        let prog = ir_parser::Parser::parse(code).unwrap();
+        let bf = emit(&prog);
+        assert_eq!(bf, ",[[->++>+<<]>>[-<<+>>]<<.]");
+        // Otherwise a cell that is overwritten before it is read is cleared
+        let code = "getch 0\nloop {\n  ptrchange +1\n}\ngetch 0\naddmul +1, 2\nputch 0\ngetch -1\n";
+        let prog = ir_parser::Parser::parse(code).unwrap();
+        let bf = emit(&prog);
+        assert_eq!(bf, ",[>],<[-]>[->++<<+>]<[->+<]>.<,");
+        assert_eq!(output(&reparse(&bf), &[3, 4]), output(&prog, &[3, 4]));
+    }
+}
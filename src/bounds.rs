@@ -0,0 +1,233 @@
+use crate::ir::{IRProgram, Offset, IR};
+
+// Static tape-bounds analysis: runs the program on an interval of possible
+// pointer positions instead of a pointer. A loop whose body has no net
+// movement leaves the pointer where it was, so those are exact. Any other
+// loop widens the interval to infinity in the directions it moves in.
+
+// A range of tape positions, where `None` is unbounded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: Option<i64>,
+    pub hi: Option<i64>,
+}
+
+impl Interval {
+    fn shift(self, off: Offset) -> Self {
+        Self {
+            lo: self.lo.map(|lo| lo + off as i64),
+            hi: self.hi.map(|hi| hi + off as i64),
+        }
+    }
+
+    fn join(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.zip(other.lo).map(|(a, b)| a.min(b)),
+            hi: self.hi.zip(other.hi).map(|(a, b)| a.max(b)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Bounds {
+    // Every cell that may be accessed, `None` if there are none
+    pub cells: Option<Interval>,
+}
+
+impl Bounds {
+    // Whether every access is provably on a tape of `mem_size` cells
+    pub fn fits(&self, mem_size: usize) -> bool {
+        self.cells.is_none_or(|c| {
+            c.lo.is_some_and(|lo| lo >= 0) && c.hi.is_some_and(|hi| hi < mem_size as i64)
+        })
+    }
+
+    // The smallest tape the program provably fits on, if any
+    pub fn min_mem_size(&self) -> Option<usize> {
+        match self.cells {
+            None => Some(0),
+            Some(Interval {
+                lo: Some(lo),
+                hi: Some(hi),
+            }) if lo >= 0 => Some(hi as usize + 1),
+            Some(_) => None,
+        }
+    }
+
+    pub fn warnings(&self, mem_size: usize) -> Vec<String> {
+        let Some(cells) = self.cells else {
+            return Vec::new();
+        };
+        let mut ret = Vec::new();
+        match cells.lo {
+            Some(lo) if lo >= 0 => {}
+            Some(lo) => ret.push(format!(
+                "the program may access cell {}, left of cell 0",
+                lo
+            )),
+            None => ret.push("the program may move left of cell 0".to_string()),
+        }
+        match cells.hi {
+            Some(hi) if hi < mem_size as i64 => {}
+            Some(hi) => ret.push(format!(
+                "the program may access cell {}, beyond --mem-size {}; use at least --mem-size {}",
+                hi,
+                mem_size,
+                hi + 1
+            )),
+            None => ret.push(format!(
+                "the program may move beyond --mem-size {}, and no size is provably enough",
+                mem_size
+            )),
+        }
+        ret
+    }
+}
+
+struct Analysis {
+    cells: Option<Interval>,
+}
+
+impl Analysis {
+    fn touch(&mut self, ptr: Interval, off: Offset) {
+        let cells = ptr.shift(off);
+        self.cells = Some(self.cells.map_or(cells, |c| c.join(cells)));
+    }
+
+    // Where the pointer may be after `irs`, starting in `ptr`
+    fn series(&mut self, irs: &[IR], mut ptr: Interval) -> Interval {
+        for ir in irs {
+            match ir {
+                IR::PtrChange(amt) => ptr = ptr.shift(*amt),
+                IR::Add(off, _) | IR::Putch(off) | IR::Getch(off) | IR::MovImm(off, _) => {
+                    self.touch(ptr, *off)
+                }
+                IR::AddMul(off, _) => {
+                    self.touch(ptr, 0);
+                    self.touch(ptr, *off);
+                }
+                // The backends round the tape up to whole windows
+                IR::Dump => self.touch(ptr, 0),
+                IR::SimpleLoop(_, inner) => {
+                    self.touch(ptr, 0);
+                    self.series(inner, ptr);
+                }
+                IR::Loop(inner) => {
+                    // Widen until the body ends where it may start, which
+                    // takes at most one step per direction
+                    loop {
+                        self.touch(ptr, 0);
+                        let end = ptr.join(self.series(inner, ptr));
+                        if end == ptr {
+                            break;
+                        }
+                        ptr = Interval {
+                            lo: end.lo.filter(|_| end.lo == ptr.lo),
+                            hi: end.hi.filter(|_| end.hi == ptr.hi),
+                        };
+                    }
+                }
+            }
+        }
+        ptr
+    }
+}
+
+pub fn analyze(prog: &IRProgram) -> Bounds {
+    let mut a = Analysis { cells: None };
+    let start = Interval {
+        lo: Some(0),
+        hi: Some(0),
+    };
+    a.series(&prog.0, start);
+    Bounds { cells: a.cells }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir_parser;
+    use crate::parser::Parser;
+
+    fn analyze_bf(code: &str) -> Bounds {
+        analyze(&IRProgram::from_ast_program(&Parser::parse(code).unwrap()))
+    }
+
+    fn interval(lo: Option<i64>, hi: Option<i64>) -> Option<Interval> {
+        Some(Interval { lo, hi })
+    }
+
+    #[test]
+    fn test_balanced() {
+        let bounds = analyze_bf(">>+[->>+<<]<.");
+        assert_eq!(bounds.cells, interval(Some(1), Some(4)));
+        assert!(bounds.fits(5));
+        assert!(!bounds.fits(4));
+        assert_eq!(bounds.min_mem_size(), Some(5));
+        assert_eq!(
+            bounds.warnings(4),
+            ["the program may access cell 4, beyond --mem-size 4; use at least --mem-size 5"]
+        );
+        assert_eq!(analyze_bf(">>>").cells, None);
+        assert!(analyze_bf("").fits(0));
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let bounds = analyze_bf("+[>+]");
+        assert_eq!(bounds.cells, interval(Some(0), None));
+        assert_eq!(bounds.min_mem_size(), None);
+        let bounds = analyze_bf(">>+[<]<+");
+        assert_eq!(bounds.cells, interval(None, Some(2)));
+        assert_eq!(
+            bounds.warnings(30000),
+            ["the program may move left of cell 0"]
+        );
+        // Unbalanced in both directions
+        let bounds = analyze_bf("+[[>]<<]");
+        assert_eq!(bounds.cells, interval(None, None));
+        assert_eq!(bounds.warnings(30000).len(), 2);
+    }
+
+    #[test]
+    fn test_optimized() {
+        let prog = ir_parser::Parser::parse("addmul -2, 3\nsimpleloop -1 {\n  putch +7\n}\n");
+        let bounds = analyze(&prog.unwrap());
+        assert_eq!(bounds.cells, interval(Some(-2), Some(7)));
+        assert_eq!(
+            bounds.warnings(30000),
+            ["the program may access cell -2, left of cell 0"]
+        );
+    }
+
+    #[test]
+    fn test_sound() {
+        // Programs the analysis says fit a tape never step off it
+        let mut rng = crate::fuzz::XorShift::new(0xb0);
+        let mut fitted = 0;
+        for _ in 0..2000 {
+            let case = crate::fuzz::generate(&mut rng);
+            let prog = IRProgram::from_ast_program(&Parser::parse(&case.code).unwrap());
+            for prog in [crate::optimize::optimize(&prog), prog] {
+                let Some(size) = analyze(&prog).min_mem_size() else {
+                    continue;
+                };
+                fitted += 1;
+                let mut io = crate::eval::StreamIO::new(&case.input[..], Vec::new());
+                let limits = crate::eval::Limits {
+                    max_steps: Some(10_000),
+                    tape_size: Some(size),
+                    ..Default::default()
+                };
+                // Without the analysis `eval_with_io` would skip the checks on
+                let res = crate::eval::eval_with_hook(&prog, &mut io, &limits, &mut ()).unwrap();
+                assert!(
+                    !matches!(res.status, crate::eval::Status::TapeError(_)),
+                    "{}",
+                    case.code
+                );
+            }
+        }
+        assert!(fitted > 100);
+    }
+}
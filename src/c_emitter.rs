@@ -1,56 +1,90 @@
 use crate::ir::{IRProgram, IR};
+use std::io::{self, Write};
 
-pub struct CEmitter {}
+pub struct CEmitter<'a, W: Write> {
+    out: &'a mut W,
+}
 
-impl CEmitter {
-    pub fn emit(prog: &IRProgram, nostdlib: bool, mem_size: usize) {
-        let mut e = Self {};
-        println!("#include <stdio.h>");
-        println!("char arr[{}];", mem_size);
-        println!("int idx = 0;");
-        println!("int main() {{");
+impl<'a, W: Write> CEmitter<'a, W> {
+    pub fn emit(
+        prog: &IRProgram,
+        _nostdlib: bool,
+        mem_size: usize,
+        out: &'a mut W,
+    ) -> io::Result<()> {
+        let mut e = Self { out };
+        writeln!(e.out, "#include <stdio.h>")?;
+        if prog.has_dump() {
+            // Whole windows, so a dump never reads past the end
+            writeln!(e.out, "char arr[{}];", mem_size.next_multiple_of(16))?;
+            e.emit_dump()?;
+        } else {
+            writeln!(e.out, "char arr[{}];", mem_size)?;
+        }
+        writeln!(e.out, "int idx = 0;")?;
+        writeln!(e.out, "int main() {{")?;
 
         for n in &prog.0 {
-            e.emit_inner(&n, nostdlib);
+            e.emit_inner(n)?;
         }
-        println!("return 0;");
-        println!("}}");
+        writeln!(e.out, "return 0;")?;
+        writeln!(e.out, "}}")?;
+        Ok(())
     }
 
-    fn emit_inner(&mut self, node: &IR, nostdlib: bool) {
+    // Prints the same line as `eval::dump_line`
+    fn emit_dump(&mut self) -> io::Result<()> {
+        writeln!(self.out, "static void dump(int idx) {{")?;
+        writeln!(self.out, "  int start = idx & ~15;")?;
+        writeln!(self.out, "  fprintf(stderr, \"%08x:\", start);")?;
+        writeln!(self.out, "  for (int i = start; i < start + 16; i++)")?;
+        writeln!(
+            self.out,
+            "    fprintf(stderr, \"%c%02x\", i == idx ? '>' : ' ', (unsigned char)arr[i]);"
+        )?;
+        writeln!(self.out, "  fputc('\\n', stderr);")?;
+        writeln!(self.out, "}}")?;
+        Ok(())
+    }
+
+    fn emit_inner(&mut self, node: &IR) -> io::Result<()> {
         match node {
             IR::PtrChange(amt) => {
-                println!("  idx += {};", amt);
+                writeln!(self.out, "  idx += {};", amt)?;
             }
             IR::Add(add_off, amt) => {
-                println!("  arr[idx + {}] += {};", add_off, amt);
+                writeln!(self.out, "  arr[idx + {}] += {};", add_off, amt)?;
             }
             IR::Putch(off) => {
-                println!("  putchar(arr[idx + {}]);", off);
+                writeln!(self.out, "  putchar(arr[idx + {}]);", off)?;
             }
             IR::Getch(off) => {
-                println!("  arr[idx + {}] = (char)getchar();", off);
+                writeln!(self.out, "  arr[idx + {}] = (char)getchar();", off)?;
             }
             IR::Loop(nodes) => {
-                println!("  while (arr[idx]) {{");
+                writeln!(self.out, "  while (arr[idx]) {{")?;
                 for n in nodes {
-                    self.emit_inner(n, nostdlib);
+                    self.emit_inner(n)?;
                 }
-                println!("  }}");
+                writeln!(self.out, "  }}")?;
             }
             IR::SimpleLoop(delta, nodes) => {
-                println!("  for ( ; arr[idx]; arr[idx] += {}) {{", delta);
+                writeln!(self.out, "  for ( ; arr[idx]; arr[idx] += {}) {{", delta)?;
                 for n in nodes {
-                    self.emit_inner(n, nostdlib);
+                    self.emit_inner(n)?;
                 }
-                println!("  }}");
+                writeln!(self.out, "  }}")?;
             }
             IR::AddMul(off, amt) => {
-                println!("  arr[idx + {}] += (arr[idx] * {});", off, amt);
+                writeln!(self.out, "  arr[idx + {}] += (arr[idx] * {});", off, amt)?;
             }
             IR::MovImm(off, imm) => {
-                println!("  arr[idx + {}] = {};", off, imm);
+                writeln!(self.out, "  arr[idx + {}] = {};", off, imm)?;
+            }
+            IR::Dump => {
+                writeln!(self.out, "  dump(idx);")?;
             }
         }
+        Ok(())
     }
 }
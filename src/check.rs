@@ -0,0 +1,412 @@
+use crate::eval::{self, IO};
+use crate::ir::{self, IRProgram};
+use crate::optimize::{self, Pass};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+// Translation validation: run the unoptimized program and the output of each
+// optimizer pass on the same input and compare what they do. This only proves
+// anything about the given input, but it is a cheap way to catch miscompiles.
+
+#[derive(Debug, PartialEq)]
+pub enum Mismatch {
+    Output {
+        pos: usize,
+        expected: Option<ir::Value>,
+        actual: Option<ir::Value>,
+    },
+    InputConsumed {
+        expected: usize,
+        actual: usize,
+    },
+    // The line of the `pos`th `IR::Dump`
+    Dump {
+        pos: usize,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+    Tape {
+        cell: ir::Offset,
+        expected: ir::Value,
+        actual: ir::Value,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    // Index into `optimize::PASSES` of the pass that introduced the mismatch
+    pub pass_idx: usize,
+    pub pass: &'static str,
+    pub mismatch: Mismatch,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn byte(v: &Option<ir::Value>) -> String {
+            v.map_or("nothing".to_string(), |v| (v as u8).to_string())
+        }
+        match self {
+            Mismatch::Output {
+                pos,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "output byte {} is {}, expected {}",
+                pos,
+                byte(actual),
+                byte(expected)
+            ),
+            Mismatch::InputConsumed { expected, actual } => write!(
+                f,
+                "consumed {} bytes of input, expected {}",
+                actual, expected
+            ),
+            Mismatch::Dump {
+                pos,
+                expected,
+                actual,
+            } => {
+                let line = |l: &Option<String>| {
+                    l.as_ref()
+                        .map_or("nothing".to_string(), |l| format!("`{}`", l.trim_end()))
+                };
+                write!(
+                    f,
+                    "dump {} is {}, expected {}",
+                    pos,
+                    line(actual),
+                    line(expected)
+                )
+            }
+            Mismatch::Tape {
+                cell,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "final tape cell {} is {}, expected {}",
+                cell, *actual as u8, *expected as u8
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "divergence after pass {} ({}): {}",
+            self.pass_idx + 1,
+            self.pass,
+            self.mismatch
+        )
+    }
+}
+
+struct RecordingIO<'a> {
+    input: &'a [ir::Value],
+    consumed: usize,
+    output: Vec<ir::Value>,
+    dumps: Vec<String>,
+}
+
+impl IO for RecordingIO<'_> {
+    fn putchar(&mut self, val: ir::Value) -> io::Result<()> {
+        self.output.push(val);
+        Ok(())
+    }
+    fn getchar(&mut self) -> io::Result<Option<ir::Value>> {
+        let ret = self.input.get(self.consumed).copied();
+        self.consumed = (self.consumed + 1).min(self.input.len());
+        Ok(ret)
+    }
+    fn dump(&mut self, line: &str) -> io::Result<()> {
+        self.dumps.push(line.to_string());
+        Ok(())
+    }
+}
+
+struct Run {
+    output: Vec<ir::Value>,
+    consumed: usize,
+    dumps: Vec<String>,
+    // `None` if the step budget ran out
+    tape: Option<BTreeMap<ir::Offset, ir::Value>>,
+}
+
+fn run(prog: &IRProgram, input: &[ir::Value], max_steps: u64) -> Run {
+    let mut io = RecordingIO {
+        input,
+        consumed: 0,
+        output: Vec::new(),
+        dumps: Vec::new(),
+    };
+    let limits = eval::Limits {
+        max_steps: Some(max_steps),
+        ..Default::default()
+    };
+    let res = eval::eval_with_io(prog, &mut io, &limits).expect("RecordingIO can't fail");
+    let tape = (res.status == eval::Status::Completed).then(|| res.state.cells());
+    Run {
+        output: io.output,
+        consumed: io.consumed,
+        dumps: io.dumps,
+        tape,
+    }
+}
+
+fn compare(expected: &Run, actual: &Run, compare_tape: bool) -> Option<Mismatch> {
+    let (exp_tape, act_tape) = match (&expected.tape, &actual.tape) {
+        (Some(e), Some(a)) => (e, a),
+        _ => {
+            // At least one side was cut short, so only the output produced by
+            // both can be compared.
+            let n = expected.output.len().min(actual.output.len());
+            let output = (0..n)
+                .find(|i| expected.output[*i] != actual.output[*i])
+                .map(|pos| Mismatch::Output {
+                    pos,
+                    expected: Some(expected.output[pos]),
+                    actual: Some(actual.output[pos]),
+                });
+            let n = expected.dumps.len().min(actual.dumps.len());
+            return output.or_else(|| {
+                (0..n)
+                    .find(|i| expected.dumps[*i] != actual.dumps[*i])
+                    .map(|pos| Mismatch::Dump {
+                        pos,
+                        expected: Some(expected.dumps[pos].clone()),
+                        actual: Some(actual.dumps[pos].clone()),
+                    })
+            });
+        }
+    };
+
+    let n = expected.output.len().max(actual.output.len());
+    if let Some(pos) = (0..n).find(|i| expected.output.get(*i) != actual.output.get(*i)) {
+        return Some(Mismatch::Output {
+            pos,
+            expected: expected.output.get(pos).copied(),
+            actual: actual.output.get(pos).copied(),
+        });
+    }
+    let n = expected.dumps.len().max(actual.dumps.len());
+    if let Some(pos) = (0..n).find(|i| expected.dumps.get(*i) != actual.dumps.get(*i)) {
+        return Some(Mismatch::Dump {
+            pos,
+            expected: expected.dumps.get(pos).cloned(),
+            actual: actual.dumps.get(pos).cloned(),
+        });
+    }
+    if expected.consumed != actual.consumed {
+        return Some(Mismatch::InputConsumed {
+            expected: expected.consumed,
+            actual: actual.consumed,
+        });
+    }
+    if compare_tape {
+        let cells = exp_tape.keys().chain(act_tape.keys());
+        let get = |t: &BTreeMap<ir::Offset, ir::Value>, c| t.get(c).copied().unwrap_or(0);
+        if let Some(cell) = cells.filter(|c| get(exp_tape, c) != get(act_tape, c)).min() {
+            return Some(Mismatch::Tape {
+                cell: *cell,
+                expected: get(exp_tape, cell),
+                actual: get(act_tape, cell),
+            });
+        }
+    }
+    None
+}
+
+// Compares `prog` against the fully optimized program and, if they diverge,
+// bisects `optimize::PASSES` for the first pass whose output diverges.
+//
+// The optimizer drops stores that are never read, so the final tape is
+// compared on a copy of the program that ends with a dump, which the
+// optimizer keeps every store for.
+pub fn check_optimization(
+    prog: &IRProgram,
+    input: &[ir::Value],
+    max_steps: u64,
+) -> Result<(), Divergence> {
+    check_passes(prog, optimize::PASSES, input, max_steps, false)?;
+    let mut observed = prog.0.clone();
+    observed.push(ir::IR::Dump);
+    check_passes(
+        &IRProgram(observed),
+        optimize::PASSES,
+        input,
+        max_steps,
+        true,
+    )
+}
+
+fn check_passes(
+    prog: &IRProgram,
+    passes: &[(&'static str, Pass)],
+    input: &[ir::Value],
+    max_steps: u64,
+    compare_tape: bool,
+) -> Result<(), Divergence> {
+    // stages[i] is the program after the first i passes
+    let mut stages = vec![prog.0.clone()];
+    for (_, pass) in passes {
+        stages.push(pass(stages.last().unwrap()));
+    }
+
+    let reference = run(prog, input, max_steps);
+    let diverges = |i: usize| {
+        let actual = run(&IRProgram(stages[i].clone()), input, max_steps);
+        compare(&reference, &actual, compare_tape)
+    };
+
+    let mut mismatch = match diverges(passes.len()) {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    // Invariant: stage `lo` agrees with the reference, stage `hi` does not
+    let (mut lo, mut hi) = (0, passes.len());
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        match diverges(mid) {
+            Some(m) => {
+                hi = mid;
+                mismatch = m;
+            }
+            None => lo = mid,
+        }
+    }
+    Err(Divergence {
+        pass_idx: hi - 1,
+        pass: passes[hi - 1].0,
+        mismatch,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::IR;
+
+    fn prog(code: &str) -> IRProgram {
+        IRProgram::from_ast_program(&crate::parser::Parser::parse(code).unwrap())
+    }
+
+    fn identity(irs: &[IR]) -> Vec<IR> {
+        irs.to_vec()
+    }
+
+    // Miscompiles every `Putch` to print the next cell over
+    fn broken(irs: &[IR]) -> Vec<IR> {
+        irs.iter()
+            .map(|ir| match ir {
+                IR::Putch(off) => IR::Putch(off + 1),
+                IR::Loop(inner) => IR::Loop(broken(inner)),
+                _ => ir.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches() {
+        let input = [5, 6];
+        assert_eq!(
+            check_optimization(&prog(",>,<[->+<]>."), &input, 1000),
+            Ok(())
+        );
+        // Both sides run out of steps, but agree on the output so far
+        assert_eq!(check_optimization(&prog("+[.]"), &[], 1000), Ok(()));
+    }
+
+    #[test]
+    fn test_bisect() {
+        let passes: &[(&str, Pass)] = &[
+            ("first", identity),
+            ("second", identity),
+            ("broken", broken),
+            ("fourth", identity),
+        ];
+        assert_eq!(
+            check_passes(&prog("+>++<."), passes, &[], 1000, false),
+            Err(Divergence {
+                pass_idx: 2,
+                pass: "broken",
+                mismatch: Mismatch::Output {
+                    pos: 0,
+                    expected: Some(1),
+                    actual: Some(2)
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_tape() {
+        // Stores that are never read are dropped, but the tape still matches
+        assert_eq!(check_optimization(&prog("+>+"), &[], 1000), Ok(()));
+        assert_eq!(check_optimization(&prog(",>++[-<+>]>"), &[3], 1000), Ok(()));
+
+        let passes: &[(&str, Pass)] = &[("drop", |_| Vec::new())];
+        assert_eq!(check_passes(&prog(">++"), passes, &[], 1000, false), Ok(()));
+        assert_eq!(
+            check_passes(&prog(">++"), passes, &[], 1000, true),
+            Err(Divergence {
+                pass_idx: 0,
+                pass: "drop",
+                mismatch: Mismatch::Tape {
+                    cell: 1,
+                    expected: 2,
+                    actual: 0
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_input_consumed() {
+        let passes: &[(&str, Pass)] = &[("drop", |_| Vec::new())];
+        assert_eq!(
+            check_passes(&prog(",,"), passes, &[1, 2], 1000, false),
+            Err(Divergence {
+                pass_idx: 0,
+                pass: "drop",
+                mismatch: Mismatch::InputConsumed {
+                    expected: 2,
+                    actual: 0
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_dumps() {
+        let dialect = crate::parser::Dialect {
+            dump: true,
+            ..Default::default()
+        };
+        // Dumps are barriers for the stores and constants around them
+        let code = "++>+++#[-<+>]<#>>,[>++#<-]#";
+        let prog = IRProgram::from_ast_program(
+            &crate::parser::Parser::parse_dialect(code, dialect).unwrap(),
+        );
+        assert_eq!(check_optimization(&prog, &[3], 1000), Ok(()));
+
+        let passes: &[(&str, Pass)] = &[("drop", |irs| irs[..1].to_vec())];
+        assert_eq!(
+            check_passes(&prog, passes, &[3], 1000, false),
+            Err(Divergence {
+                pass_idx: 0,
+                pass: "drop",
+                mismatch: Mismatch::Dump {
+                    pos: 0,
+                    expected: Some(
+                        "00000000: 02>03 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n".to_string()
+                    ),
+                    actual: None
+                }
+            })
+        );
+    }
+}
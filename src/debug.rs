@@ -0,0 +1,486 @@
+// An interactive debugger over the evaluator. It stops before IR nodes run,
+// and reads commands while stopped.
+
+use crate::eval::{Hook, State};
+use crate::ir::{IRProgram, Offset, Value, IR};
+use crate::parser::{Dialect, Span};
+use crate::profile;
+use crate::trace::header;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+use std::io::{BufRead, Write};
+use std::ops::ControlFlow;
+
+// Where the IR nodes of the unoptimized translation of `code` come from. That
+// translation has one node per command, in source order. Input after a `!`
+// must already be split off.
+pub struct SourceMap {
+    code: String,
+    // The character offset of each node's command, by preorder index
+    nodes: Vec<usize>,
+    // The character offsets of `#`s
+    hashes: Vec<usize>,
+}
+
+impl SourceMap {
+    pub fn new(code: &str, dialect: Dialect) -> Self {
+        let mut nodes = Vec::new();
+        let mut hashes = Vec::new();
+        for (i, c) in code.chars().enumerate() {
+            match c {
+                '+' | '-' | '<' | '>' | '.' | ',' | '[' => nodes.push(i),
+                // Stops before the dump itself when it is one
+                '#' => {
+                    if dialect.dump {
+                        nodes.push(i);
+                    }
+                    hashes.push(i);
+                }
+                _ => {}
+            }
+        }
+        Self {
+            code: code.to_string(),
+            nodes,
+            hashes,
+        }
+    }
+
+    // The character offset of 1-based `line` and `col`
+    fn offset(&self, line: usize, col: usize) -> Option<usize> {
+        let (mut l, mut c) = (1, 1);
+        for (i, ch) in self.code.chars().enumerate() {
+            if (l, c) == (line, col) {
+                return Some(i);
+            }
+            if ch == '\n' {
+                l += 1;
+                c = 1;
+            } else {
+                c += 1;
+            }
+        }
+        None
+    }
+
+    fn loc(&self, start: usize) -> (usize, usize) {
+        Span {
+            start,
+            end: start + 1,
+        }
+        .line_col(&self.code)
+    }
+}
+
+enum Mode {
+    Step,
+    // Runs until a node outside of the subtree `start..end`, other than
+    // `start` itself, is reached
+    Over(usize, usize),
+    Continue,
+}
+
+const HELP: &str = "\
+step, s               run until the next node
+next, n               like step, but runs loops to completion
+continue, c           run until a breakpoint, watchpoint or `#`
+break, b              list breakpoints
+break, b LINE[:COL]   break at the first command from LINE:COL on
+delete, d LINE[:COL]  delete a breakpoint
+watch, w CELL         stop when CELL changes
+unwatch CELL          stop watching CELL
+print, p [CELL]       print the pointer's cell, or CELL
+set CELL VALUE        change a cell
+ptr CELL              move the pointer
+tape                  print the tape
+where                 print the current position
+quit, q               stop the program";
+
+pub struct Debugger<R: BufRead, W: Write> {
+    input: R,
+    out: W,
+    source: Option<SourceMap>,
+    // The character offset in the source of each node, by preorder index
+    positions: Vec<Option<usize>>,
+    // The preorder index and the end of the subtree of each node
+    index: HashMap<*const IR, (usize, usize)>,
+    breakpoints: BTreeSet<usize>,
+    // Nodes following a `#`
+    hashes: BTreeSet<usize>,
+    // Watched cells and the values they had when last checked
+    watches: BTreeMap<Offset, Value>,
+    mode: Mode,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    // Commands are read from `input` and responses written to `out`. Source
+    // positions are only available with a `source` for `prog`, which must be
+    // its unoptimized translation, or an optimization of `unoptimized` if
+    // that is given. Then only loops have positions.
+    pub fn new(
+        prog: &IRProgram,
+        source: Option<SourceMap>,
+        unoptimized: Option<&IRProgram>,
+        input: R,
+        out: W,
+    ) -> Result<Self, &'static str> {
+        fn walk(irs: &[IR], index: &mut HashMap<*const IR, (usize, usize)>) {
+            for ir in irs {
+                let start = index.len();
+                index.insert(ir, (start, start));
+                if let IR::Loop(inner) | IR::SimpleLoop(_, inner) = ir {
+                    walk(inner, index);
+                }
+                index.insert(ir, (start, index.len()));
+            }
+        }
+        let mut index = HashMap::new();
+        walk(&prog.0, &mut index);
+        let mut unoptimized_index = HashMap::new();
+        walk(&unoptimized.unwrap_or(prog).0, &mut unoptimized_index);
+        if source
+            .as_ref()
+            .is_some_and(|s| s.nodes.len() != unoptimized_index.len())
+        {
+            return Err("the source doesn't match the unoptimized program");
+        }
+
+        let mut positions = vec![None; index.len()];
+        if let Some(ref s) = source {
+            match unoptimized {
+                None => positions = s.nodes.iter().copied().map(Some).collect(),
+                Some(unoptimized) => {
+                    // Loops are matched up, by their preorder index among
+                    // loops. Their nodes come from the same walks as `index`.
+                    let loop_nodes =
+                        |prog: &IRProgram, index: &HashMap<*const IR, (usize, usize)>| {
+                            let mut nodes: Vec<usize> = Vec::new();
+                            loops(&prog.0, &mut |ir| nodes.push(index[&(ir as *const IR)].0));
+                            nodes
+                        };
+                    let optimized = loop_nodes(prog, &index);
+                    let original = loop_nodes(unoptimized, &unoptimized_index);
+                    for (node, origin) in optimized.iter().zip(profile::origins(prog, unoptimized))
+                    {
+                        positions[*node] = origin.map(|o| s.nodes[original[o]]);
+                    }
+                }
+            }
+        }
+        let first_at = |off: usize| positions.iter().position(|p| p.is_some_and(|p| p >= off));
+        let hashes = source.as_ref().map_or(BTreeSet::new(), |s| {
+            s.hashes.iter().filter_map(|h| first_at(*h)).collect()
+        });
+        Ok(Self {
+            input,
+            out,
+            source,
+            positions,
+            index,
+            breakpoints: BTreeSet::new(),
+            hashes,
+            watches: BTreeMap::new(),
+            mode: Mode::Step,
+        })
+    }
+
+    // The debugger is interactive, so write errors are dropped
+    fn say(&mut self, msg: impl Display) {
+        let _ = writeln!(self.out, "{}", msg);
+    }
+
+    fn describe(&self, node: usize) -> String {
+        match (&self.source, self.positions[node]) {
+            (Some(s), Some(off)) => {
+                let (line, col) = s.loc(off);
+                format!("{}:{}", line, col)
+            }
+            _ => format!("node {}", node),
+        }
+    }
+
+    // The first node with a position at or after `pos`. In optimized IR,
+    // that is the first loop there.
+    fn parse_pos(&self, pos: &str) -> Result<usize, &'static str> {
+        let source = self.source.as_ref().ok_or("no source positions")?;
+        let (line, col) = match pos.split_once(':') {
+            Some((line, col)) => (line, col),
+            None => (pos, "1"),
+        };
+        let line = line.parse().map_err(|_| "bad line")?;
+        let col = col.parse().map_err(|_| "bad column")?;
+        let off = source.offset(line, col).ok_or("no such position")?;
+        self.positions
+            .iter()
+            .position(|p| p.is_some_and(|p| p >= off))
+            .ok_or("no command at or after that position")
+    }
+
+    fn event(&mut self, node: &IR, state: &mut State, iteration: bool) -> ControlFlow<()> {
+        let (idx, end) = self.index[&(node as *const IR)];
+        let mut reasons = Vec::new();
+        for (cell, last) in &mut self.watches {
+            let val = state.get(*cell);
+            if val != *last {
+                reasons.push(format!(
+                    "cell {} changed from {} to {}",
+                    cell, *last as u8, val as u8
+                ));
+                *last = val;
+            }
+        }
+        if !iteration && self.breakpoints.contains(&idx) {
+            reasons.push("breakpoint".to_string());
+        }
+        if !iteration && self.hashes.contains(&idx) {
+            reasons.push("#".to_string());
+        }
+        let stop = !reasons.is_empty()
+            || match self.mode {
+                Mode::Step => !iteration,
+                Mode::Over(start, end) => !(iteration || (start < idx && idx < end)),
+                Mode::Continue => false,
+            };
+        if !stop {
+            return ControlFlow::Continue(());
+        }
+
+        for r in reasons {
+            self.say(r);
+        }
+        let what = if iteration {
+            format!("{} iteration", node.name())
+        } else {
+            header(node)
+        };
+        let here = format!("at {}: {}", self.describe(idx), what);
+        self.say(&here);
+        self.repl(state, idx, end, &here)
+    }
+
+    fn repl(&mut self, state: &mut State, idx: usize, end: usize, here: &str) -> ControlFlow<()> {
+        loop {
+            let _ = write!(self.out, "(bfc) ");
+            let _ = self.out.flush();
+            let mut line = String::new();
+            if self.input.read_line(&mut line).unwrap_or(0) == 0 {
+                return ControlFlow::Break(());
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                [] => {}
+                ["s" | "step"] => {
+                    self.mode = Mode::Step;
+                    return ControlFlow::Continue(());
+                }
+                ["n" | "next"] => {
+                    self.mode = Mode::Over(idx, end);
+                    return ControlFlow::Continue(());
+                }
+                ["c" | "continue"] => {
+                    self.mode = Mode::Continue;
+                    return ControlFlow::Continue(());
+                }
+                ["q" | "quit"] => return ControlFlow::Break(()),
+                ["b" | "break"] => {
+                    let list: Vec<String> =
+                        self.breakpoints.iter().map(|b| self.describe(*b)).collect();
+                    for b in list {
+                        self.say(b);
+                    }
+                }
+                ["b" | "break", pos] => match self.parse_pos(pos) {
+                    Ok(node) => {
+                        self.breakpoints.insert(node);
+                        let msg = format!("breakpoint at {}", self.describe(node));
+                        self.say(msg);
+                    }
+                    Err(e) => self.say(e),
+                },
+                ["d" | "delete", pos] => match self.parse_pos(pos) {
+                    Ok(node) if self.breakpoints.remove(&node) => {}
+                    Ok(_) => self.say("no breakpoint there"),
+                    Err(e) => self.say(e),
+                },
+                ["w" | "watch", cell] => match cell.parse() {
+                    Ok(cell) => {
+                        self.watches.insert(cell, state.get(cell));
+                    }
+                    Err(_) => self.say("bad cell"),
+                },
+                ["unwatch", cell] => match cell.parse() {
+                    Ok(cell) => {
+                        self.watches.remove(&cell);
+                    }
+                    Err(_) => self.say("bad cell"),
+                },
+                ["p" | "print"] => {
+                    let msg = format!(
+                        "ptr {}, cell {} = {}",
+                        state.ptr(),
+                        state.ptr(),
+                        state.get(state.ptr()) as u8
+                    );
+                    self.say(msg);
+                }
+                ["p" | "print", cell] => match cell.parse() {
+                    Ok(cell) => self.say(format!("cell {} = {}", cell, state.get(cell) as u8)),
+                    Err(_) => self.say("bad cell"),
+                },
+                ["set", cell, val] => match (cell.parse(), val.parse::<i16>()) {
+                    (Ok(cell), Ok(val @ -128..=255)) => state.set(cell, val as u8 as Value),
+                    _ => self.say("usage: set CELL VALUE"),
+                },
+                ["ptr", cell] => match cell.parse() {
+                    Ok(cell) => state.set_ptr(cell),
+                    Err(_) => self.say("bad cell"),
+                },
+                ["tape"] => {
+                    let _ = state.dump(&mut self.out);
+                }
+                ["where"] => self.say(here),
+                ["h" | "help"] => self.say(HELP),
+                _ => self.say("unknown command, try `help`"),
+            }
+        }
+    }
+}
+
+// Calls `f` on the loops of `irs` in preorder
+fn loops<'a>(irs: &'a [IR], f: &mut impl FnMut(&'a IR)) {
+    for ir in irs {
+        if let IR::Loop(inner) | IR::SimpleLoop(_, inner) = ir {
+            f(ir);
+            loops(inner, f);
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> Hook for Debugger<R, W> {
+    fn node(&mut self, node: &IR, state: &mut State) -> ControlFlow<()> {
+        self.event(node, state, false)
+    }
+    fn iteration(&mut self, node: &IR, state: &mut State) -> ControlFlow<()> {
+        self.event(node, state, true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::{self, Limits, Status, StreamIO};
+    use crate::optimize;
+    use crate::parser::Parser;
+
+    // Runs `code` under the debugger with `commands`, and returns the
+    // debugger's output, the program's output and how it ended
+    fn debug(code: &str, optimized: bool, commands: &str) -> (String, String, Status) {
+        let unoptimized = IRProgram::from_ast_program(&Parser::parse(code).unwrap());
+        let prog = match optimized {
+            true => optimize::optimize(&unoptimized),
+            false => IRProgram(unoptimized.0.clone()),
+        };
+        let source = Some(SourceMap::new(code, Dialect::default()));
+        let unoptimized = optimized.then_some(&unoptimized);
+        let mut out = Vec::new();
+        let mut debugger =
+            Debugger::new(&prog, source, unoptimized, commands.as_bytes(), &mut out).unwrap();
+        let mut io = StreamIO::new(&b""[..], Vec::new());
+        let res = eval::eval_with_hook(&prog, &mut io, &Limits::default(), &mut debugger).unwrap();
+        let out = String::from_utf8(out).unwrap().replace("(bfc) ", "");
+        (out, String::from_utf8(io.output).unwrap(), res.status)
+    }
+
+    #[test]
+    fn test_step() {
+        let (out, _, status) = debug("+\n[-]>", false, "s\nn\ns\nq\n");
+        assert_eq!(
+            out,
+            [
+                "at 1:1: add 0, 1",
+                "at 2:1: loop",
+                "at 2:4: ptrchange +1",
+                // `step` ran off the end
+            ]
+            .join("\n")
+                + "\n"
+        );
+        assert_eq!(status, Status::Completed);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // `#` stops at the following command
+        let (out, _, status) = debug("++#[->+<]>.", false, "b 1:6\nc\nc\np\nc\nq\n");
+        assert_eq!(
+            out,
+            [
+                "at 1:1: add 0, 1",
+                "breakpoint at 1:6",
+                "#",
+                "at 1:4: loop",
+                "breakpoint",
+                "at 1:6: ptrchange +1",
+                "ptr 0, cell 0 = 1",
+                "breakpoint",
+                "at 1:6: ptrchange +1",
+            ]
+            .join("\n")
+                + "\n"
+        );
+        assert_eq!(status, Status::Stopped);
+    }
+
+    #[test]
+    fn test_edit_and_watch() {
+        let (out, output, status) = debug(
+            ",[>+<-]>.",
+            true,
+            "b 1:1\nw 1\ns\ns\nset 0 65\nc\nset 1 66\nunwatch 1\nc\n",
+        );
+        assert_eq!(
+            out,
+            [
+                "at node 0: getch 0",
+                "no command at or after that position",
+                "at node 1: movimm +1, 0",
+                "at node 2: addmul +1, 1",
+                "cell 1 changed from 0 to 65",
+                "at node 3: putch +1",
+            ]
+            .join("\n")
+                + "\n"
+        );
+        assert_eq!(output, "B");
+        assert_eq!(status, Status::Completed);
+    }
+
+    #[test]
+    fn test_optimized_positions() {
+        // Only loops have positions, and a `#` stops at the next one
+        let (out, output, _) = debug("+++>#\n[-]<[.-]", true, "b 1:1\nb\nc\nc\nq\n");
+        assert_eq!(
+            out,
+            [
+                "at node 0: movimm 0, 3",
+                "breakpoint at 2:5",
+                "2:5",
+                "breakpoint",
+                "#",
+                "at 2:5: simpleloop -1",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(output, "\x03\x02\x01");
+    }
+
+    #[test]
+    fn test_source_mismatch() {
+        let code = ",[-]";
+        let prog = optimize::optimize(&IRProgram::from_ast_program(&Parser::parse(code).unwrap()));
+        let source = SourceMap::new(code, Dialect::default());
+        let res = Debugger::new(&prog, Some(source), None, &b""[..], Vec::new());
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::ir::{self, IRProgram, Offset, Value, IR};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Prints IR as structured pseudo-code. While the pointer is statically known,
+// cells are variables named in the order they are first used. After a loop
+// that moves the pointer, cells are `p[off]` relative to the pointer `p`.
+
+// Whether a loop with this body runs at most once: every iteration ends with
+// the condition cleared
+fn is_if(inner: &[IR]) -> bool {
+    if ir::movement(inner) != Some(0) {
+        return false;
+    }
+    let mut cleared = false;
+    let mut ptr = 0;
+    for ir in inner {
+        match ir {
+            IR::PtrChange(amt) => ptr += amt,
+            IR::MovImm(off, val) if ptr + off == 0 => cleared = *val == 0,
+            IR::Add(off, _) | IR::Getch(off) | IR::AddMul(off, _) if ptr + off == 0 => {
+                cleared = false
+            }
+            IR::Loop(_) | IR::SimpleLoop(..) if ir::may_write(std::slice::from_ref(ir), -ptr) => {
+                cleared = false
+            }
+            _ => {}
+        }
+    }
+    cleared
+}
+
+struct Decompiler {
+    out: String,
+    // The tape index of the pointer, while it is known
+    ptr: Option<Offset>,
+    names: HashMap<Offset, usize>,
+    // Cells in the order they were named
+    cells: Vec<Offset>,
+    depth: usize,
+}
+
+impl Decompiler {
+    fn line(&mut self, text: &str) {
+        writeln!(self.out, "{}{}", "    ".repeat(self.depth), text).unwrap();
+    }
+
+    fn cell(&mut self, off: Offset) -> String {
+        match self.ptr {
+            Some(ptr) => {
+                let next = self.cells.len();
+                let name = *self.names.entry(ptr + off).or_insert(next);
+                if name == next {
+                    self.cells.push(ptr + off);
+                }
+                format!("v{}", name)
+            }
+            None => format!("p[{}]", off),
+        }
+    }
+
+    fn add(&mut self, target: String, amt: i32, what: &str) {
+        let (op, amt) = if amt < 0 { ("-=", -amt) } else { ("+=", amt) };
+        self.line(&format!("{} {} {}{}", target, op, what, amt));
+    }
+
+    fn nodes(&mut self, irs: &[IR]) {
+        let mut irs = irs.iter().peekable();
+        while let Some(ir) = irs.next() {
+            match ir {
+                IR::PtrChange(amt) => match &mut self.ptr {
+                    Some(ptr) => *ptr += amt,
+                    None => self.add("p".to_string(), *amt, ""),
+                },
+                IR::Add(off, amt) => {
+                    // Runs of adds to the same cell, which the optimizer
+                    // leaves in loops, are one statement
+                    let mut amt = *amt;
+                    while let Some(IR::Add(next_off, next)) = irs.peek() {
+                        if next_off != off {
+                            break;
+                        }
+                        amt = amt.wrapping_add(*next);
+                        irs.next();
+                    }
+                    let cell = self.cell(*off);
+                    self.add(cell, amt as i32, "");
+                }
+                IR::MovImm(off, val) => {
+                    let cell = self.cell(*off);
+                    self.line(&format!("{} = {}", cell, val));
+                }
+                IR::Putch(off) => {
+                    let cell = self.cell(*off);
+                    self.line(&format!("putchar({})", cell));
+                }
+                IR::Getch(off) => {
+                    let cell = self.cell(*off);
+                    self.line(&format!("{} = getchar()", cell));
+                }
+                IR::AddMul(off, amt) => {
+                    let (cell, src) = (self.cell(*off), self.cell(0));
+                    match amt {
+                        1 => self.line(&format!("{} += {}", cell, src)),
+                        -1 => self.line(&format!("{} -= {}", cell, src)),
+                        _ => self.add(cell, *amt as i32, &format!("{} * ", src)),
+                    }
+                }
+                IR::Dump => self.line("dump()"),
+                IR::Loop(inner) => {
+                    if let (Some(ptr), None) = (self.ptr, ir::movement(std::slice::from_ref(ir))) {
+                        // From here on the pointer depends on the tape
+                        self.line(&format!("p = {}", ptr));
+                        self.ptr = None;
+                    }
+                    let keyword = if is_if(inner) { "if" } else { "while" };
+                    self.block(keyword, inner, 0);
+                }
+                IR::SimpleLoop(delta, inner) => self.block("while", inner, *delta),
+            }
+        }
+    }
+
+    // A loop on the current cell, which a `SimpleLoop` adds `delta` to at the
+    // end of each iteration
+    fn block(&mut self, keyword: &str, inner: &[IR], delta: Value) {
+        let cond = self.cell(0);
+        self.line(&format!("{} {} {{", keyword, cond));
+        self.depth += 1;
+        self.nodes(inner);
+        if delta != 0 {
+            self.add(cond, delta as i32, "");
+        }
+        self.depth -= 1;
+        self.line("}");
+    }
+}
+
+pub fn decompile(prog: &IRProgram) -> String {
+    let mut d = Decompiler {
+        out: String::new(),
+        ptr: Some(0),
+        names: HashMap::new(),
+        cells: Vec::new(),
+        depth: 0,
+    };
+    d.nodes(&prog.0);
+    let mut ret = String::new();
+    for (name, cell) in d.cells.iter().enumerate() {
+        writeln!(ret, "// v{} is cell {}", name, cell).unwrap();
+    }
+    if d.ptr.is_none() {
+        writeln!(ret, "// p is the pointer").unwrap();
+    }
+    ret + &d.out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ir_parser::Parser;
+
+    fn decompile(code: &str) -> String {
+        super::decompile(&Parser::parse(code).unwrap())
+    }
+
+    #[test]
+    fn test_names() {
+        let code = "ptrchange +3\nmovimm 0, 8\nsimpleloop -1 {\n  addmul -2, 9\n}\n\
+                    ptrchange -2\nputch 0\ngetch +2\n";
+        assert_eq!(
+            decompile(code),
+            "// v0 is cell 3\n// v1 is cell 1\n\
+             v0 = 8\nwhile v0 {\n    v1 += v0 * 9\n    v0 -= 1\n}\n\
+             putchar(v1)\nv0 = getchar()\n"
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        // The condition is cleared at the end of every iteration
+        let code = "getch 0\nloop {\n  putch +1\n  movimm 0, 0\n}\n\
+                    loop {\n  movimm 0, 0\n  add 0, 1\n}\n";
+        assert_eq!(
+            decompile(code),
+            "// v0 is cell 0\n// v1 is cell 1\n\
+             v0 = getchar()\nif v0 {\n    putchar(v1)\n    v0 = 0\n}\n\
+             while v0 {\n    v0 = 0\n    v0 += 1\n}\n"
+        );
+        let code = "add 0, 2\nadd 0, 3\nadd +1, 1\n";
+        assert_eq!(
+            decompile(code),
+            "// v0 is cell 0\n// v1 is cell 1\nv0 += 5\nv1 += 1\n"
+        );
+        // A nested loop may leave it set
+        let code = "loop {\n  movimm 0, 0\n  ptrchange +1\n  loop {\n    add -1, 1\n  }\n\
+                    ptrchange -1\n}\n";
+        assert!(decompile(code).contains("while v0 {"));
+    }
+
+    #[test]
+    fn test_moving_pointer() {
+        let code = "add +2, -3\nptrchange +1\nloop {\n  ptrchange +1\n}\nputch -1\n";
+        assert_eq!(
+            decompile(code),
+            "// v0 is cell 2\n// p is the pointer\n\
+             v0 -= 3\np = 1\nwhile p[0] {\n    p += 1\n}\nputchar(p[-1])\n"
+        );
+    }
+}
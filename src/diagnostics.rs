@@ -0,0 +1,114 @@
+use crate::json::string;
+use crate::parser::Span;
+
+// Diagnostics for tools: with `--error-format json` every error and warning
+// is a JSON object on a line of its own on stderr, like
+//
+//   {"file":"prog.b","severity":"error","code":"unterminated-loop",
+//    "message":"`[` without a matching `]`","line":1,"column":3,
+//    "span":{"start":2,"end":3}}
+//
+// (on one line). `line` and `column` are 1-based and `span` is a range of
+// characters in the file, `end` exclusive. Any of them is `null` when the
+// diagnostic isn't about a place in the file, and `column` and `span` are when
+// only the line is known.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub file: String,
+    pub severity: Severity,
+    // A stable name for the kind of diagnostic, like `infinite-loop`
+    pub code: &'static str,
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+}
+
+impl Record {
+    pub fn new(file: &str, severity: Severity, code: &'static str, message: String) -> Self {
+        Self {
+            file: file.to_string(),
+            severity,
+            code,
+            message,
+            line: None,
+            column: None,
+            span: None,
+        }
+    }
+
+    // Places the record at `span` of `source`
+    pub fn at(mut self, span: Span, source: &str) -> Self {
+        let (line, column) = span.line_col(source);
+        self.line = Some(line);
+        self.column = Some(column);
+        self.span = Some(span);
+        self
+    }
+
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        fn number(n: Option<usize>) -> String {
+            n.map_or("null".to_string(), |n| n.to_string())
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let span = match self.span {
+            Some(s) => format!("{{\"start\":{},\"end\":{}}}", s.start, s.end),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"file\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\
+             \"line\":{},\"column\":{},\"span\":{}}}",
+            string(&self.file),
+            severity,
+            self.code,
+            string(&self.message),
+            number(self.line),
+            number(self.column),
+            span
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let code = "+\n [";
+        let record = Record::new(
+            "dir/a \"b\".b",
+            Severity::Error,
+            "unterminated-loop",
+            "`[` without\ta `]`".to_string(),
+        )
+        .at(Span { start: 3, end: 4 }, code);
+        assert_eq!(
+            record.to_json(),
+            "{\"file\":\"dir/a \\\"b\\\".b\",\"severity\":\"error\",\
+             \"code\":\"unterminated-loop\",\"message\":\"`[` without\\ta `]`\",\
+             \"line\":2,\"column\":2,\"span\":{\"start\":3,\"end\":4}}"
+        );
+        let record = Record::new("-", Severity::Warning, "x", "\u{1}".to_string());
+        assert_eq!(
+            record.to_json(),
+            "{\"file\":\"-\",\"severity\":\"warning\",\"code\":\"x\",\"message\":\"\\u0001\",\
+             \"line\":null,\"column\":null,\"span\":null}"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// Turns emitted code into an executable with the system toolchain, and runs
+// it. Targets other than the host are cross-compiled statically and run
+// under qemu user-mode emulation.
+
+use crate::ir::IRProgram;
+use crate::Arch;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+#[derive(Debug)]
+pub enum Error {
+    Unsupported(&'static str),
+    Io(&'static str, io::Error),
+    Toolchain(String, ExitStatus),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unsupported(what) => write!(f, "cannot build {}", what),
+            Error::Io(what, e) => write!(f, "failed to run {}: {}", what, e),
+            Error::Toolchain(cmd, status) => write!(f, "{} failed: {}", cmd, status),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+struct Toolchain {
+    cc: &'static str,
+    lang: &'static str,
+    // Runs foreign binaries, `None` for the host
+    emulator: Option<&'static str>,
+}
+
+fn toolchain(arch: &Arch) -> Result<Toolchain> {
+    let host_or_cross = |cc, lang, host, emulator| {
+        if std::env::consts::ARCH == host {
+            Toolchain {
+                cc: "cc",
+                lang,
+                emulator: None,
+            }
+        } else {
+            Toolchain {
+                cc,
+                lang,
+                emulator: Some(emulator),
+            }
+        }
+    };
+    match arch {
+        Arch::X86_64 => Ok(host_or_cross(
+            "x86_64-linux-gnu-gcc",
+            "assembler",
+            "x86_64",
+            "qemu-x86_64",
+        )),
+        Arch::RiscV => Ok(host_or_cross(
+            "riscv64-linux-gnu-gcc",
+            "assembler",
+            "riscv64",
+            "qemu-riscv64",
+        )),
+        Arch::C => Ok(Toolchain {
+            cc: "cc",
+            lang: "c",
+            emulator: None,
+        }),
+        Arch::Ir => Err(Error::Unsupported("--arch ir")),
+        Arch::Bf => Err(Error::Unsupported("--arch bf")),
+        Arch::Decompile => Err(Error::Unsupported("--arch decompile")),
+    }
+}
+
+// Emits `prog` and feeds it to the compiler on stdin
+pub fn build(
+    prog: &IRProgram,
+    arch: &Arch,
+    nostdlib: bool,
+    mem_size: usize,
+    output: &Path,
+) -> Result<()> {
+    let tc = toolchain(arch)?;
+    let mut code = Vec::new();
+    crate::emit(prog, arch, nostdlib, mem_size, &mut code).map_err(|e| Error::Io("emitter", e))?;
+
+    let mut cmd = Command::new(tc.cc);
+    // The C backend always goes through stdio
+    if nostdlib && !matches!(arch, Arch::C) {
+        cmd.arg("-nostdlib");
+    }
+    if tc.emulator.is_some() {
+        cmd.arg("-static");
+    }
+    cmd.args(["-x", tc.lang, "-o"])
+        .arg(output)
+        .arg("-")
+        .stdin(Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| Error::Io(tc.cc, e))?;
+    let written = child.stdin.take().unwrap().write_all(&code);
+    // A compiler that exits early closes the pipe, and its status and
+    // diagnostics say why
+    if let Err(e) = &written {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            let _ = child.kill();
+            let _ = child.wait();
+            return written.map_err(|e| Error::Io(tc.cc, e));
+        }
+    }
+    let status = child.wait().map_err(|e| Error::Io(tc.cc, e))?;
+    if !status.success() {
+        return Err(Error::Toolchain(tc.cc.to_string(), status));
+    }
+    written.map_err(|e| Error::Io(tc.cc, e))
+}
+
+// Removes the directory, and everything in it, when dropped
+pub(crate) struct TempDir(pub(crate) PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> io::Result<Self> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let dir = std::env::temp_dir().join(format!(
+            "bfc-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&dir)?;
+        Ok(TempDir(dir))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Builds `prog` into a temporary directory and runs it with this process's
+// stdin, stdout and stderr
+pub fn run(prog: &IRProgram, arch: &Arch, nostdlib: bool, mem_size: usize) -> Result<ExitStatus> {
+    let tc = toolchain(arch)?;
+    let dir = TempDir::new().map_err(|e| Error::Io("mkdir", e))?;
+    let exe = dir.0.join("prog");
+    build(prog, arch, nostdlib, mem_size, &exe)?;
+
+    let mut cmd = match tc.emulator {
+        Some(emulator) => {
+            let mut cmd = Command::new(emulator);
+            cmd.arg(&exe);
+            cmd
+        }
+        None => Command::new(&exe),
+    };
+    cmd.status().map_err(|e| Error::Io("program", e))
+}
@@ -1,80 +1,360 @@
+use crate::bounds;
 use crate::ir::{self, IR};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
 
 #[derive(Default)]
-struct State {
+pub struct State {
     mem: HashMap<ir::Offset, ir::Value>,
     idx: ir::Offset,
 }
 
 impl State {
+    // The non-zero cells of the tape, keyed by absolute position
+    pub fn cells(&self) -> BTreeMap<ir::Offset, ir::Value> {
+        self.mem
+            .iter()
+            .filter(|(_, v)| **v != 0)
+            .map(|(k, v)| (*k, *v))
+            .collect()
+    }
+
+    // The absolute position of the pointer
+    pub fn ptr(&self) -> ir::Offset {
+        self.idx
+    }
+
+    // The cell at absolute position `cell`
+    pub fn get(&self, cell: ir::Offset) -> ir::Value {
+        *self.mem.get(&cell).unwrap_or(&0)
+    }
+
+    // Prints the region of the tape holding the non-zero cells and the
+    // pointer, 16 cells a line, with the pointer's cell in brackets
+    pub fn dump(&self, out: &mut impl Write) -> io::Result<()> {
+        const ROW: ir::Offset = 16;
+        let cells = self.cells();
+        let lo = cells.keys().next().map_or(self.idx, |c| (*c).min(self.idx));
+        let hi = cells
+            .keys()
+            .next_back()
+            .map_or(self.idx, |c| (*c).max(self.idx));
+        writeln!(out, "tape (pointer at {}):", self.idx)?;
+        let mut row = lo.div_euclid(ROW) * ROW;
+        while row <= hi {
+            let mut line = format!("{:>8}:", row);
+            for cell in row..(row + ROW).min(hi + 1) {
+                let val = self.get(cell) as u8;
+                if cell == self.idx {
+                    line += &format!(" [{:>3}]", val);
+                } else {
+                    line += &format!("  {:>3} ", val);
+                }
+            }
+            writeln!(out, "{}", line.trim_end())?;
+            row += ROW;
+        }
+        Ok(())
+    }
+
     fn read(&self, off: ir::Offset) -> ir::Value {
-        *self.mem.get(&(self.idx + off)).unwrap_or(&0)
+        self.get(self.idx + off)
     }
 
     fn write(&mut self, off: ir::Offset, val: ir::Value) {
         self.mem.insert(self.idx + off, val);
     }
 
+    pub fn set(&mut self, cell: ir::Offset, val: ir::Value) {
+        self.mem.insert(cell, val);
+    }
+
+    pub fn set_ptr(&mut self, ptr: ir::Offset) {
+        self.idx = ptr;
+    }
+
     fn ptr_change(&mut self, amt: ir::Offset) {
         self.idx += amt;
     }
 }
 
 pub trait IO {
-    fn putchar(&mut self, val: i8);
-    fn getchar(&mut self) -> i8;
+    fn putchar(&mut self, val: ir::Value) -> io::Result<()>;
+    // `None` at the end of input
+    fn getchar(&mut self) -> io::Result<Option<ir::Value>>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    // Shows the output of `IR::Dump`, a line from `dump_line`
+    fn dump(&mut self, line: &str) -> io::Result<()> {
+        io::stderr().write_all(line.as_bytes())
+    }
+}
+
+// Reads input from `R` and writes output to `W`. Neither is buffered here, so
+// wrap files in `BufReader`/`BufWriter`. In-memory IO is `StreamIO<&[u8],
+// Vec<u8>>`.
+pub struct StreamIO<R: Read, W: Write> {
+    pub input: R,
+    pub output: W,
 }
 
-pub struct CIO {}
-impl IO for CIO {
-    fn putchar(&mut self, val: ir::Value) {
-        unsafe {
-            libc::putchar(val as libc::c_int);
+impl<R: Read, W: Write> StreamIO<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+}
+
+impl<R: Read, W: Write> IO for StreamIO<R, W> {
+    fn putchar(&mut self, val: ir::Value) -> io::Result<()> {
+        self.output.write_all(&[val as u8])
+    }
+    fn getchar(&mut self) -> io::Result<Option<ir::Value>> {
+        // Make sure a prompt is visible before blocking on input
+        self.output.flush()?;
+        let mut buf = [0];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0] as ir::Value)),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
         }
     }
-    fn getchar(&mut self) -> ir::Value {
-        unsafe { libc::getchar() as ir::Value }
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+pub type StdIO = StreamIO<io::StdinLock<'static>, io::BufWriter<io::StdoutLock<'static>>>;
+
+pub fn stdio() -> StdIO {
+    StreamIO::new(io::stdin().lock(), io::BufWriter::new(io::stdout().lock()))
+}
+
+// The length of a line from `dump_line`: the window's position, a colon, 16
+// cells of 3 characters and a newline
+pub const DUMP_LEN: usize = 8 + 1 + 16 * 3 + 1;
+
+// What `IR::Dump` prints: the position of the 16-cell aligned window holding
+// the pointer, then the cells in hex, the pointer's marked with `>`. The
+// backends print the same.
+pub fn dump_line(state: &State) -> String {
+    let start = state.ptr() & !15;
+    let mut line = format!("{:08x}:", start as u32);
+    for cell in start..start + 16 {
+        let mark = if cell == state.ptr() { '>' } else { ' ' };
+        line += &format!("{}{:02x}", mark, state.get(cell) as u8);
     }
+    line.push('\n');
+    line
 }
 
-pub fn eval(prog: &ir::IRProgram) {
-    let mut io = CIO {};
-    eval_with_io(prog, &mut io)
+// Bounds on a single evaluation. The default is unbounded.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    // Cells outside `0..tape_size` are a `TapeError`
+    pub tape_size: Option<usize>,
 }
 
-pub fn eval_with_io(prog: &ir::IRProgram, io: &mut impl IO) {
-    let mut state = State::default();
-    fn run_series(irs: &[ir::IR], state: &mut State, io: &mut impl IO) {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Completed,
+    OutOfSteps,
+    Timeout,
+    // Access to the cell at this absolute position
+    TapeError(ir::Offset),
+    // By a `Hook`
+    Stopped,
+}
+
+pub struct EvalResult {
+    pub status: Status,
+    pub steps: u64,
+    pub state: State,
+}
+
+pub fn eval(prog: &ir::IRProgram, limits: &Limits) -> io::Result<EvalResult> {
+    eval_with_io(prog, &mut stdio(), limits)
+}
+
+// Stops at the first IO error and returns it
+pub fn eval_with_io(
+    prog: &ir::IRProgram,
+    io: &mut impl IO,
+    limits: &Limits,
+) -> io::Result<EvalResult> {
+    // Without a hook to move the pointer, programs that provably stay on the
+    // tape need no checks
+    let tape_size = limits
+        .tape_size
+        .filter(|size| !bounds::analyze(prog).fits(*size));
+    let limits = Limits {
+        tape_size,
+        ..limits.clone()
+    };
+    eval_with_hook(prog, io, &limits, &mut ())
+}
+
+// Observes, and may steer, an evaluation. `node` is called as each IR node
+// starts executing, and `iteration` as each iteration of a `Loop` or
+// `SimpleLoop` starts. Either may change the state, or end the evaluation
+// with `Status::Stopped`.
+pub trait Hook {
+    fn node(&mut self, _node: &IR, _state: &mut State) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+    fn iteration(&mut self, _node: &IR, _state: &mut State) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+impl Hook for () {}
+
+pub fn eval_with_hook(
+    prog: &ir::IRProgram,
+    io: &mut impl IO,
+    limits: &Limits,
+    hook: &mut impl Hook,
+) -> io::Result<EvalResult> {
+    let mut m = Machine {
+        hook,
+        state: State::default(),
+        steps: 0,
+        max_steps: limits.max_steps.unwrap_or(u64::MAX),
+        deadline: limits.timeout.map(|t| Instant::now() + t),
+        tape_size: limits.tape_size,
+    };
+    let status = match m.run_series(&prog.0, io) {
+        Ok(()) => Status::Completed,
+        Err(Stop::Status(status)) => status,
+        Err(Stop::Io(e)) => return Err(e),
+    };
+    io.flush()?;
+    Ok(EvalResult {
+        status,
+        steps: m.steps,
+        state: m.state,
+    })
+}
+
+// What `,` stores at the end of input, like C's `getchar`
+pub const EOF: ir::Value = -1;
+
+enum Stop {
+    Status(Status),
+    Io(io::Error),
+}
+
+impl From<Status> for Stop {
+    fn from(status: Status) -> Self {
+        Stop::Status(status)
+    }
+}
+
+impl From<io::Error> for Stop {
+    fn from(e: io::Error) -> Self {
+        Stop::Io(e)
+    }
+}
+
+// How many steps go by between checks of the clock
+const CLOCK_INTERVAL: u64 = 1 << 16;
+
+struct Machine<'h, H: Hook> {
+    hook: &'h mut H,
+    state: State,
+    steps: u64,
+    max_steps: u64,
+    deadline: Option<Instant>,
+    tape_size: Option<usize>,
+}
+
+impl<H: Hook> Machine<'_, H> {
+    // Every executed node and every loop iteration costs one step, so that
+    // empty loops like `+[]` still run out of budget.
+    fn tick(&mut self) -> Result<(), Status> {
+        if self.steps == self.max_steps {
+            return Err(Status::OutOfSteps);
+        }
+        self.steps += 1;
+        if self.steps.is_multiple_of(CLOCK_INTERVAL)
+            && self.deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return Err(Status::Timeout);
+        }
+        Ok(())
+    }
+
+    fn check(&self, off: ir::Offset) -> Result<ir::Offset, Status> {
+        let cell = self.state.idx + off;
+        match self.tape_size {
+            Some(size) if cell < 0 || cell as usize >= size => Err(Status::TapeError(cell)),
+            _ => Ok(off),
+        }
+    }
+
+    fn read(&self, off: ir::Offset) -> Result<ir::Value, Status> {
+        Ok(self.state.read(self.check(off)?))
+    }
+
+    fn write(&mut self, off: ir::Offset, val: ir::Value) -> Result<(), Status> {
+        self.state.write(self.check(off)?, val);
+        Ok(())
+    }
+
+    fn run_series(&mut self, irs: &[ir::IR], io: &mut impl IO) -> Result<(), Stop> {
         for ir in irs {
+            self.tick()?;
+            if self.hook.node(ir, &mut self.state).is_break() {
+                return Err(Status::Stopped.into());
+            }
             match ir {
                 IR::Loop(inner) => {
-                    while state.read(0) != 0 {
-                        run_series(inner, state, io);
+                    while self.read(0)? != 0 {
+                        self.tick()?;
+                        if self.hook.iteration(ir, &mut self.state).is_break() {
+                            return Err(Status::Stopped.into());
+                        }
+                        self.run_series(inner, io)?;
                     }
                 }
                 IR::PtrChange(amt) => {
-                    state.ptr_change(*amt);
+                    self.state.ptr_change(*amt);
                 }
                 IR::Add(add_off, amt) => {
-                    state.write(*add_off, state.read(*add_off) + amt);
+                    self.write(*add_off, self.read(*add_off)?.wrapping_add(*amt))?;
+                }
+                IR::Putch(off) => io.putchar(self.read(*off)?)?,
+                IR::Getch(off) => {
+                    self.check(*off)?;
+                    self.write(*off, io.getchar()?.unwrap_or(EOF))?;
                 }
-                IR::Putch(off) => io.putchar(state.read(*off)),
-                IR::Getch(off) => state.write(*off, io.getchar()),
                 IR::SimpleLoop(delta, inner) => {
-                    while state.read(0) != 0 {
-                        run_series(inner, state, io);
-                        state.write(0, state.read(0) + delta);
+                    while self.read(0)? != 0 {
+                        self.tick()?;
+                        if self.hook.iteration(ir, &mut self.state).is_break() {
+                            return Err(Status::Stopped.into());
+                        }
+                        self.run_series(inner, io)?;
+                        self.write(0, self.read(0)?.wrapping_add(*delta))?;
                     }
                 }
                 IR::AddMul(off, amt) => {
-                    state.write(*off, state.read(*off) + state.read(0) * amt);
+                    let prod = self.read(0)?.wrapping_mul(*amt);
+                    self.write(*off, self.read(*off)?.wrapping_add(prod))?;
                 }
                 IR::MovImm(off, val) => {
-                    state.write(*off, *val);
+                    self.write(*off, *val)?;
                 }
+                IR::Dump => io.dump(&dump_line(&self.state))?,
             }
         }
+        Ok(())
     }
-    run_series(&prog.0, &mut state, io);
 }
@@ -0,0 +1,313 @@
+// Writes an `ASTProgram` back out as brainfuck: `format` lays it out for
+// reading and `minify` makes it short. Both write the extensions they find in
+// the syntax of their dialect, so the output parses with the same one.
+
+use crate::ast::AST;
+
+pub struct Style {
+    // The widest line, including indentation. Runs of commands are wrapped
+    // to fit, but comments are not.
+    pub width: usize,
+    // Spaces per level of loops
+    pub indent: usize,
+    pub comments: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            indent: 2,
+            comments: true,
+        }
+    }
+}
+
+// The text of a node that isn't a loop, group or comment
+fn command(ast: &AST) -> String {
+    match ast {
+        AST::PtrAdvance => ">".to_string(),
+        AST::PtrRetreat => "<".to_string(),
+        AST::Incr => "+".to_string(),
+        AST::Decr => "-".to_string(),
+        AST::Putch => ".".to_string(),
+        AST::Getch => ",".to_string(),
+        AST::Dump => "#".to_string(),
+        AST::Add(amt) if *amt <= 128 => format!("+{}", amt),
+        AST::Add(amt) => format!("-{}", amt.wrapping_neg()),
+        AST::PtrMove(amt) if *amt > 0 => format!(">{}", amt),
+        AST::PtrMove(amt) => format!("<{}", amt.unsigned_abs()),
+        AST::Loop(_) | AST::Repeat(..) | AST::Comment(_) => unreachable!(),
+    }
+}
+
+// `asts` on one line, without comments
+fn flat(asts: &[AST], out: &mut String) {
+    for ast in asts {
+        match ast {
+            AST::Loop(body) => {
+                out.push('[');
+                flat(body, out);
+                out.push(']');
+            }
+            AST::Repeat(body, count) => {
+                out.push('(');
+                flat(body, out);
+                out.push_str(&format!(")*{}", count));
+            }
+            AST::Comment(_) => {}
+            ast => out.push_str(&command(ast)),
+        }
+    }
+}
+
+pub fn format(asts: &[AST], style: &Style) -> String {
+    let mut f = Formatter {
+        style,
+        out: String::new(),
+        line: String::new(),
+        level: 0,
+    };
+    f.nodes(asts);
+    f.newline();
+    f.out
+}
+
+struct Formatter<'s> {
+    style: &'s Style,
+    out: String,
+    // The current line, without indentation
+    line: String,
+    level: usize,
+}
+
+impl Formatter<'_> {
+    fn available(&self) -> usize {
+        self.style
+            .width
+            .saturating_sub(self.level * self.style.indent)
+    }
+
+    fn newline(&mut self) {
+        if !self.line.is_empty() {
+            let indent = " ".repeat(self.level * self.style.indent);
+            self.out.push_str(&format!("{}{}\n", indent, self.line));
+            self.line.clear();
+        }
+    }
+
+    // Appends `text`, which isn't split, wrapping before it if needed
+    fn word(&mut self, text: &str) {
+        if !self.line.is_empty() && self.line.len() + text.len() > self.available() {
+            self.newline();
+        }
+        self.line.push_str(text);
+    }
+
+    // Whether `ast` fits on a line of its own, as it would be written by
+    // `flat`, and has no loops or comments inside
+    fn inline(&self, ast: &AST) -> Option<String> {
+        let body = match ast {
+            AST::Loop(body) | AST::Repeat(body, _) => body,
+            _ => return None,
+        };
+        let simple = body.iter().all(|a| match a {
+            AST::Loop(_) | AST::Repeat(..) => false,
+            AST::Comment(_) => !self.style.comments,
+            _ => true,
+        });
+        let mut text = String::new();
+        flat(std::slice::from_ref(ast), &mut text);
+        (simple && text.len() <= self.available()).then_some(text)
+    }
+
+    fn nodes(&mut self, asts: &[AST]) {
+        for ast in asts {
+            if let Some(text) = self.inline(ast) {
+                self.word(&text);
+                continue;
+            }
+            match ast {
+                AST::Loop(body) => self.block("[", body, "]".to_string()),
+                AST::Repeat(body, count) => self.block("(", body, format!(")*{}", count)),
+                AST::Comment(text) => {
+                    if self.style.comments {
+                        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                            self.newline();
+                            self.line.push_str(line);
+                            self.newline();
+                        }
+                    }
+                }
+                ast => self.word(&command(ast)),
+            }
+        }
+    }
+
+    // The body on its own lines, indented, with `close` on a line after it
+    fn block(&mut self, open: &str, body: &[AST], close: String) {
+        self.word(open);
+        self.newline();
+        self.level += 1;
+        self.nodes(body);
+        self.newline();
+        self.level -= 1;
+        self.word(&close);
+        self.newline();
+    }
+}
+
+// A run of one of `+-<>` in `minify`
+#[derive(PartialEq)]
+enum Token {
+    Run(char, u64),
+    Text(String),
+}
+
+fn inverse(c: char) -> char {
+    match c {
+        '+' => '-',
+        '-' => '+',
+        '>' => '<',
+        '<' => '>',
+        _ => unreachable!(),
+    }
+}
+
+// Without comments and with adjacent `+-` and `<>` cancelled. With `rle`,
+// longer runs are written with counts.
+pub fn minify(asts: &[AST], rle: bool) -> String {
+    let mut tokens = Vec::new();
+    minify_nodes(asts, &mut tokens);
+    let mut ret = String::new();
+    for t in tokens {
+        match t {
+            Token::Run(c, n) if rle && n > 2 => ret.push_str(&format!("{}{}", c, n)),
+            Token::Run(c, n) => ret.extend(std::iter::repeat_n(c, n as usize)),
+            Token::Text(text) => ret.push_str(&text),
+        }
+    }
+    ret
+}
+
+fn minify_nodes(asts: &[AST], tokens: &mut Vec<Token>) {
+    for ast in asts {
+        let (c, n) = match ast {
+            AST::Loop(body) => {
+                tokens.push(Token::Text("[".to_string()));
+                minify_nodes(body, tokens);
+                tokens.push(Token::Text("]".to_string()));
+                continue;
+            }
+            AST::Repeat(body, count) => {
+                tokens.push(Token::Text("(".to_string()));
+                minify_nodes(body, tokens);
+                tokens.push(Token::Text(format!(")*{}", count)));
+                continue;
+            }
+            AST::Comment(_) => continue,
+            AST::Incr => ('+', 1),
+            AST::Decr => ('-', 1),
+            AST::Add(amt) => ('+', *amt as u64),
+            AST::PtrAdvance => ('>', 1),
+            AST::PtrRetreat => ('<', 1),
+            AST::PtrMove(amt) if *amt > 0 => ('>', *amt as u64),
+            AST::PtrMove(amt) => ('<', amt.unsigned_abs() as u64),
+            ast => {
+                tokens.push(Token::Text(command(ast)));
+                continue;
+            }
+        };
+        // Merge into the run before, if any
+        let (c, n) = match tokens.last() {
+            Some(Token::Run(last, m)) if *last == c => (c, m + n),
+            Some(Token::Run(last, m)) if *last == inverse(c) => {
+                if *m >= n {
+                    (*last, m - n)
+                } else {
+                    (c, n - m)
+                }
+            }
+            _ => {
+                tokens.push(Token::Run(c, 0));
+                (c, n)
+            }
+        };
+        tokens.pop();
+        // Cells wrap, so only the shortest way to add counts
+        let (c, n) = match c {
+            '+' | '-' if n % 256 > 128 => (inverse(c), 256 - n % 256),
+            '+' | '-' => (c, n % 256),
+            _ => (c, n),
+        };
+        if n > 0 {
+            tokens.push(Token::Run(c, n));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{Dialect, Parser};
+
+    #[test]
+    fn test_programs() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        for entry in std::fs::read_dir(root.join("programs")).unwrap() {
+            let path = entry.unwrap().path();
+            let code = std::fs::read_to_string(&path).unwrap();
+            let ast = Parser::parse_comments(&code, Dialect::default()).unwrap();
+
+            let formatted = format(&ast.0, &Style::default());
+            assert_eq!(Parser::parse(&formatted), Parser::parse(&code));
+            // Formatting is idempotent
+            let again = Parser::parse_comments(&formatted, Dialect::default()).unwrap();
+            assert_eq!(format(&again.0, &Style::default()), formatted);
+            assert!(formatted.lines().all(|l| !l.ends_with(' ')));
+
+            let minified = minify(&ast.0, false);
+            assert!(minified.len() <= code.chars().filter(|c| "+-<>.,[]".contains(*c)).count());
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let ast = Parser::parse_comments(
+            "clear [-] then, copy\n[->+>+<<]>>[-<<+>>[+]]",
+            Dialect::default(),
+        )
+        .unwrap();
+        let style = Style {
+            width: 12,
+            ..Default::default()
+        };
+        assert_eq!(
+            format(&ast.0, &style),
+            "clear\n[-]\nthen\n,\ncopy\n[->+>+<<]>>[\n  -<<+>>[+]\n]\n"
+        );
+        let style = Style {
+            width: 12,
+            comments: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            format(&ast.0, &style),
+            "[-],\n[->+>+<<]>>[\n  -<<+>>[+]\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_minify() {
+        let ast = Parser::parse_comments("a+-b<><+[+-]>.-+#", Dialect::default()).unwrap();
+        assert_eq!(minify(&ast.0, false), "<+[]>.");
+        let ast = Parser::parse(&"+".repeat(300)).unwrap();
+        assert_eq!(minify(&ast.0, false), "+".repeat(44));
+        let rle = Dialect {
+            rle: true,
+            ..Default::default()
+        };
+        let ast = Parser::parse_dialect("+5-2++>>>>(.)*3-200", rle).unwrap();
+        assert_eq!(minify(&ast.0, true), "+5>4(.)*3+56");
+    }
+}
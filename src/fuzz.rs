@@ -0,0 +1,396 @@
+use crate::driver::TempDir;
+use crate::ir::{self, IRProgram};
+use crate::{bf_emitter, c_emitter, eval, optimize, parser};
+use crate::{bounds, check};
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Differential fuzzing: generate random well-bracketed programs and check that
+// the unoptimized evaluator, every optimizer pass (via `check`) and the C
+// backend built with the system `cc` all agree, as does the brainfuck the BF
+// backend writes when it is parsed and evaluated again. Used by the deterministic
+// runner in `cargo test` and by the cargo-fuzz target in `fuzz/`, which enables
+// the `fuzz` feature to get this module.
+
+const MAX_STEPS: u64 = 10_000;
+const MEM_SIZE: usize = 30000;
+
+pub trait Source {
+    // A number in `0..bound`
+    fn next(&mut self, bound: u32) -> u32;
+}
+
+// xorshift64*, good enough for picking tokens
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+}
+
+impl Source for XorShift {
+    fn next(&mut self, bound: u32) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545f4914f6cdd1d) >> 32) as u32 % bound
+    }
+}
+
+// Draws choices from a fuzzer-provided buffer, so that mutating the buffer
+// mutates the program. Runs out into zeros, which generates nothing.
+pub struct Bytes<'a> {
+    data: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Bytes<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, off: 0 }
+    }
+}
+
+impl Source for Bytes<'_> {
+    fn next(&mut self, bound: u32) -> u32 {
+        let b = self.data.get(self.off).copied().unwrap_or(0);
+        self.off += 1;
+        b as u32 % bound
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    pub code: String,
+    pub input: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Failure {
+    Panic(String),
+    Optimizer(Box<check::Divergence>),
+    CBackend {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    BFBackend {
+        expected: Vec<u8>,
+        actual: Option<Vec<u8>>,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Panic(msg) => write!(f, "panicked: {}", msg),
+            Failure::Optimizer(d) => write!(f, "{}", d),
+            Failure::CBackend { expected, actual } => {
+                write!(f, "C backend printed {:?}, expected {:?}", actual, expected)
+            }
+            Failure::BFBackend { expected, actual } => write!(
+                f,
+                "BF backend printed {:?}, expected {:?}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+// Programs are a sequence of items, each either a run of one command or a
+// loop. Half of the loops are balanced (zero net pointer movement) so the
+// optimizer's `SimpleLoop`/`AddMul` paths get exercised.
+pub fn generate(src: &mut impl Source) -> Case {
+    fn items(src: &mut impl Source, depth: u32, out: &mut String) {
+        let count = src.next(if depth == 0 { 16 } else { 6 });
+        for _ in 0..count {
+            if depth < 3 && src.next(4) == 0 {
+                out.push('[');
+                let start = out.len();
+                items(src, depth + 1, out);
+                if src.next(2) == 0 {
+                    let net = net_movement(&out[start..]);
+                    let fix = if net > 0 { '<' } else { '>' };
+                    out.extend(std::iter::repeat_n(fix, net.unsigned_abs() as usize));
+                }
+                out.push(']');
+            } else {
+                let c = b"+-<>.,"[src.next(6) as usize] as char;
+                // Occasional long runs push cells and offsets past i8 range
+                let max_run = if src.next(8) == 0 { 80 } else { 4 };
+                let run = 1 + src.next(max_run);
+                out.extend(std::iter::repeat_n(c, run as usize));
+            }
+        }
+    }
+    let mut code = String::new();
+    items(src, 0, &mut code);
+    let input = (0..src.next(8)).map(|_| src.next(256) as u8).collect();
+    Case { code, input }
+}
+
+fn net_movement(code: &str) -> i32 {
+    code.chars()
+        .map(|c| match c {
+            '>' => 1,
+            '<' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn to_values(bytes: &[u8]) -> Vec<ir::Value> {
+    bytes.iter().map(|b| *b as ir::Value).collect()
+}
+
+fn eval_output(prog: &IRProgram, input: &[u8], max_steps: u64) -> Option<Vec<u8>> {
+    let mut io = eval::StreamIO::new(input, Vec::new());
+    let limits = eval::Limits {
+        max_steps: Some(max_steps),
+        ..Default::default()
+    };
+    let res = eval::eval_with_io(prog, &mut io, &limits).unwrap();
+    (res.status == eval::Status::Completed).then_some(io.output)
+}
+
+fn run_c(prog: &IRProgram, input: &[u8]) -> Vec<u8> {
+    // Removed even if this panics
+    let dir = TempDir::new().unwrap();
+    let src = dir.0.join("prog.c");
+    let exe = dir.0.join("prog");
+
+    let mut c = Vec::new();
+    c_emitter::CEmitter::emit(prog, false, MEM_SIZE, &mut c).unwrap();
+    std::fs::write(&src, c).unwrap();
+    let status = Command::new("cc")
+        .arg("-o")
+        .arg(&exe)
+        .arg(&src)
+        .status()
+        .unwrap();
+    assert!(status.success(), "cc failed on {}", src.display());
+
+    let mut child = Command::new(&exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit without reading all of its input
+    let _ = child.stdin.take().unwrap().write_all(input);
+    child.wait_with_output().unwrap().stdout
+}
+
+// Runs the brainfuck the BF backend writes for `prog`, whose multiplications
+// take many more steps than the IR's
+fn run_bf(prog: &IRProgram, input: &[u8]) -> Option<Vec<u8>> {
+    let mut code = Vec::new();
+    bf_emitter::BFEmitter::emit(prog, false, MEM_SIZE, &mut code).unwrap();
+    let ast = parser::Parser::parse(std::str::from_utf8(&code).unwrap()).unwrap();
+    eval_output(&IRProgram::from_ast_program(&ast), input, MAX_STEPS * 256)
+}
+
+pub fn has_cc() -> bool {
+    Command::new("cc")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+// Checks one case. The C backend is only run with `use_cc`, and only on
+// programs that finish within the step budget and stay on the tape.
+pub fn check(case: &Case, use_cc: bool) -> Result<(), Failure> {
+    let run = || -> Result<(), Failure> {
+        let ast = parser::Parser::parse(&case.code).unwrap();
+        let prog = IRProgram::from_ast_program(&ast);
+        check::check_optimization(&prog, &to_values(&case.input), MAX_STEPS)
+            .map_err(|d| Failure::Optimizer(Box::new(d)))?;
+
+        let expected = match eval_output(&prog, &case.input, MAX_STEPS) {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+        let optimized = optimize::optimize(&prog);
+        let actual = run_bf(&optimized, &case.input);
+        if actual.as_ref() != Some(&expected) {
+            return Err(Failure::BFBackend { expected, actual });
+        }
+
+        // The C backend has no bounds checks
+        let fits = bounds::analyze(&optimized)
+            .min_mem_size()
+            .is_some_and(|size| size <= MEM_SIZE);
+        if !use_cc || !fits {
+            return Ok(());
+        }
+        let actual = run_c(&optimized, &case.input);
+        if actual != expected {
+            return Err(Failure::CBackend { expected, actual });
+        }
+        Ok(())
+    };
+    std::panic::catch_unwind(run).unwrap_or_else(|e| {
+        let msg = e
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        Err(Failure::Panic(msg))
+    })
+}
+
+// Smaller variants of a well-bracketed program: drop a loop, unwrap a loop,
+// or drop one or two adjacent commands. Bigger cuts come first.
+fn candidates(code: &str) -> Vec<String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut ret = Vec::new();
+    let mut loops = Vec::new();
+    let mut starts = Vec::new();
+    for (i, c) in chars.iter().enumerate() {
+        match c {
+            '[' => starts.push(i),
+            ']' => loops.push((starts.pop().unwrap(), i)),
+            _ => {}
+        }
+    }
+    let without = |skip: &[usize]| -> String {
+        (0..chars.len())
+            .filter(|i| !skip.contains(i))
+            .map(|i| chars[i])
+            .collect()
+    };
+    loops.sort_by_key(|(s, e)| s.wrapping_sub(*e));
+    for (s, e) in &loops {
+        ret.push(without(&(*s..=*e).collect::<Vec<_>>()));
+    }
+    for (s, e) in &loops {
+        ret.push(without(&[*s, *e]));
+    }
+    for i in 1..chars.len() {
+        if !"[]".contains(chars[i - 1]) && !"[]".contains(chars[i]) {
+            ret.push(without(&[i - 1, i]));
+        }
+    }
+    for (i, c) in chars.iter().enumerate() {
+        if *c != '[' && *c != ']' {
+            ret.push(without(&[i]));
+        }
+    }
+    ret
+}
+
+// Greedily applies the first smaller variant that still fails until none do,
+// then trims the input the same way.
+pub fn shrink(mut case: Case, fails: impl Fn(&Case) -> bool) -> Case {
+    'code: loop {
+        for code in candidates(&case.code) {
+            let smaller = Case {
+                code,
+                input: case.input.clone(),
+            };
+            if fails(&smaller) {
+                case = smaller;
+                continue 'code;
+            }
+        }
+        break;
+    }
+    'input: loop {
+        for i in 0..case.input.len() {
+            let mut smaller = case.clone();
+            smaller.input.remove(i);
+            if fails(&smaller) {
+                case = smaller;
+                continue 'input;
+            }
+        }
+        break;
+    }
+    case
+}
+
+// Generates and checks `cases` programs from `seed`, returning the first
+// failure shrunk to a minimal program.
+pub fn run(seed: u64, cases: usize, use_cc: bool) -> Result<(), (Case, Failure)> {
+    let mut rng = XorShift::new(seed);
+    for _ in 0..cases {
+        let case = generate(&mut rng);
+        if check(&case, use_cc).is_err() {
+            let case = shrink(case, |c| check(c, use_cc).is_err());
+            let failure = check(&case, use_cc).unwrap_err();
+            return Err((case, failure));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate_is_well_bracketed() {
+        let mut rng = XorShift::new(1);
+        for _ in 0..100 {
+            let case = generate(&mut rng);
+            assert!(parser::Parser::parse(&case.code).is_ok(), "{}", case.code);
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let prints_three = |c: &Case| {
+            let ast = parser::Parser::parse(&c.code).unwrap();
+            let prog = IRProgram::from_ast_program(&ast);
+            eval_output(&prog, &c.input, MAX_STEPS).is_some_and(|out| out.contains(&3))
+        };
+        let case = Case {
+            code: "+>,[-]<++[>+<-]>[<+>-]<.>>.".to_string(),
+            input: vec![1, 2],
+        };
+        assert_eq!(
+            shrink(case, prints_three),
+            Case {
+                code: "+++.".to_string(),
+                input: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn test_regressions() {
+        // Minimized failures found by this fuzzer
+        let cases = [
+            ",-..".to_string(),
+            ",[+<<<-[.>>>[+<<<->>>]<<<]>>>]".to_string(),
+            "+".repeat(128) + ".",
+        ];
+        for code in cases {
+            let case = Case {
+                code,
+                input: vec![],
+            };
+            if let Err(failure) = check(&case, false) {
+                panic!("{:?}: {}", case.code, failure);
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        if let Err((case, failure)) = run(0x5eed, 1000, false) {
+            panic!("{:?} with input {:?}: {}", case.code, case.input, failure);
+        }
+    }
+
+    #[test]
+    fn test_c_backend() {
+        if !has_cc() {
+            eprintln!("cc not found, skipping");
+            return;
+        }
+        if let Err((case, failure)) = run(0xc0de, 40, true) {
+            panic!("{:?} with input {:?}: {}", case.code, case.input, failure);
+        }
+    }
+}
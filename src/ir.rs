@@ -1,4 +1,5 @@
 use crate::ast::{ASTProgram, AST};
+use std::fmt;
 
 pub type Offset = i32;
 pub type Value = i8;
@@ -14,25 +15,346 @@ pub enum IR {
     SimpleLoop(Value, Vec<IR>),
     AddMul(Offset, Value),
     MovImm(Offset, Value),
+
+    // Prints the 16 cells around the pointer to stderr, see `eval::dump_line`
+    Dump,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct IRProgram(pub Vec<IR>);
 
 impl IRProgram {
     pub fn from_ast_program(prog: &ASTProgram) -> Self {
-        IRProgram(prog.0.iter().map(|a| Self::from_ast_node(a)).collect())
+        let mut ret = Vec::new();
+        Self::from_ast_nodes(&prog.0, &mut ret);
+        IRProgram(ret)
+    }
+
+    // Whether the program has an `IR::Dump`, which needs runtime support
+    pub fn has_dump(&self) -> bool {
+        fn recur(irs: &[IR]) -> bool {
+            irs.iter().any(|ir| match ir {
+                IR::Loop(inner) | IR::SimpleLoop(_, inner) => recur(inner),
+                IR::Dump => true,
+                _ => false,
+            })
+        }
+        recur(&self.0)
+    }
+
+    fn from_ast_nodes(asts: &[AST], out: &mut Vec<IR>) {
+        for ast in asts {
+            let ir = match ast {
+                AST::Loop(asts) => {
+                    let mut inner = Vec::new();
+                    Self::from_ast_nodes(asts, &mut inner);
+                    IR::Loop(inner)
+                }
+                AST::PtrAdvance => IR::PtrChange(1),
+                AST::PtrRetreat => IR::PtrChange(-1),
+                AST::Incr => IR::Add(0, 1),
+                AST::Decr => IR::Add(0, -1),
+                AST::Putch => IR::Putch(0),
+                AST::Getch => IR::Getch(0),
+                AST::Dump => IR::Dump,
+                AST::Add(amt) => IR::Add(0, *amt as Value),
+                AST::PtrMove(amt) => IR::PtrChange(*amt),
+                AST::Repeat(asts, count) => {
+                    let mut body = Vec::new();
+                    Self::from_ast_nodes(asts, &mut body);
+                    Self::repeat(&body, *count, out);
+                    continue;
+                }
+                AST::Comment(_) => continue,
+            };
+            out.push(ir);
+        }
+    }
+}
+
+impl IRProgram {
+    // Appends `body` repeated `count` times. A body of only adds and pointer
+    // changes that either only moves the pointer or ends where it started is
+    // folded into its total effect, other bodies are unrolled.
+    fn repeat(body: &[IR], count: usize, out: &mut Vec<IR>) {
+        let mut adds = std::collections::BTreeMap::new();
+        let mut ptr: Offset = 0;
+        let mut pure = true;
+        for ir in body {
+            match ir {
+                IR::Add(off, amt) => {
+                    let add: &mut Value = adds.entry(ptr + off).or_default();
+                    *add = add.wrapping_add(*amt);
+                }
+                IR::PtrChange(amt) => ptr += amt,
+                _ => pure = false,
+            }
+        }
+        adds.retain(|_, amt| *amt != 0);
+        let moved = Offset::try_from(count)
+            .ok()
+            .and_then(|count| ptr.checked_mul(count));
+        if pure && ptr == 0 {
+            // Values wrap, so only the count modulo 256 matters
+            let count = count as u8;
+            out.extend(adds.into_iter().filter_map(|(off, amt)| {
+                let amt = (amt as u8).wrapping_mul(count) as Value;
+                (amt != 0).then_some(IR::Add(off, amt))
+            }));
+        } else if let Some(moved) = moved.filter(|_| pure && adds.is_empty()) {
+            if moved != 0 {
+                out.push(IR::PtrChange(moved));
+            }
+        } else {
+            for _ in 0..count {
+                out.extend_from_slice(body);
+            }
+        }
+    }
+}
+
+impl IR {
+    // The mnemonic used in the textual IR
+    pub fn name(&self) -> &'static str {
+        match self {
+            IR::Loop(_) => "loop",
+            IR::PtrChange(_) => "ptrchange",
+            IR::Add(..) => "add",
+            IR::Putch(_) => "putch",
+            IR::Getch(_) => "getch",
+            IR::SimpleLoop(..) => "simpleloop",
+            IR::AddMul(..) => "addmul",
+            IR::MovImm(..) => "movimm",
+            IR::Dump => "dump",
+        }
+    }
+}
+
+// Offsets are printed with an explicit sign so they read as relative to the
+// current cell, e.g. `add +3, 5` or `movimm 0, 0`.
+struct Off(Offset);
+
+impl fmt::Display for Off {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            write!(f, "0")
+        } else {
+            write!(f, "{:+}", self.0)
+        }
+    }
+}
+
+fn fmt_nodes(f: &mut fmt::Formatter, irs: &[IR], depth: usize) -> fmt::Result {
+    let indent = "  ".repeat(depth);
+    for ir in irs {
+        match ir {
+            IR::Loop(inner) => {
+                writeln!(f, "{}loop {{", indent)?;
+                fmt_nodes(f, inner, depth + 1)?;
+                writeln!(f, "{}}}", indent)?;
+            }
+            IR::SimpleLoop(delta, inner) => {
+                writeln!(f, "{}simpleloop {} {{", indent, delta)?;
+                fmt_nodes(f, inner, depth + 1)?;
+                writeln!(f, "{}}}", indent)?;
+            }
+            IR::PtrChange(amt) => writeln!(f, "{}ptrchange {}", indent, Off(*amt))?,
+            IR::Add(off, amt) => writeln!(f, "{}add {}, {}", indent, Off(*off), amt)?,
+            IR::Putch(off) => writeln!(f, "{}putch {}", indent, Off(*off))?,
+            IR::Getch(off) => writeln!(f, "{}getch {}", indent, Off(*off))?,
+            IR::AddMul(off, amt) => writeln!(f, "{}addmul {}, {}", indent, Off(*off), amt)?,
+            IR::MovImm(off, val) => writeln!(f, "{}movimm {}, {}", indent, Off(*off), val)?,
+            IR::Dump => writeln!(f, "{}dump", indent)?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_nodes(f, std::slice::from_ref(self), 0)
+    }
+}
+
+impl fmt::Display for IRProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_nodes(f, &self.0, 0)
+    }
+}
+
+fn accesses(irs: &[IR], cell: Offset, include_reads: bool) -> bool {
+    let mut ptr = 0;
+    for ir in irs {
+        let hit = match ir {
+            IR::Loop(_) => true,
+            IR::PtrChange(amt) => {
+                ptr += amt;
+                false
+            }
+            IR::Add(off, _) | IR::Getch(off) | IR::MovImm(off, _) => ptr + off == cell,
+            IR::Putch(off) => include_reads && ptr + off == cell,
+            IR::AddMul(off, _) => ptr + off == cell || (include_reads && ptr == cell),
+            IR::SimpleLoop(_, inner) => ptr == cell || accesses(inner, cell - ptr, include_reads),
+            IR::Dump => include_reads,
+        };
+        if hit {
+            return true;
+        }
     }
+    false
+}
 
-    fn from_ast_node(ast: &AST) -> IR {
-        match ast {
-            AST::Loop(asts) => IR::Loop(asts.iter().map(|a| Self::from_ast_node(a)).collect()),
-            AST::PtrAdvance => IR::PtrChange(1),
-            AST::PtrRetreat => IR::PtrChange(-1),
-            AST::Incr => IR::Add(0, 1),
-            AST::Decr => IR::Add(0, -1),
-            AST::Putch => IR::Putch(0),
-            AST::Getch => IR::Getch(0),
+// Whether running `irs` may write the cell at `cell`, relative to the pointer
+// when it starts. Conservatively true if there is a `Loop`, which may end up
+// anywhere.
+pub fn may_write(irs: &[IR], cell: Offset) -> bool {
+    accesses(irs, cell, false)
+}
+
+// Like `may_write`, but also counts reads of the cell
+pub fn may_access(irs: &[IR], cell: Offset) -> bool {
+    accesses(irs, cell, true)
+}
+
+// The net pointer movement of `irs`, if it doesn't depend on the tape
+pub fn movement(irs: &[IR]) -> Option<Offset> {
+    let mut ret = 0;
+    for ir in irs {
+        match ir {
+            IR::PtrChange(amt) => ret += amt,
+            IR::Loop(inner) if movement(inner) != Some(0) => return None,
+            _ => {}
         }
     }
+    Some(ret)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    pub reason: &'static str,
+    pub node: IR,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} in:\n{}", self.reason, self.node)
+    }
+}
+
+// Checks the invariants the optimizer and backends rely on:
+//  - `PtrChange(0)` never survives (it is always removed by `compress_changes`)
+//  - `AddMul` never targets its own multiplier cell
+//  - a `SimpleLoop` body has zero net pointer movement, contains no `Loop`
+//    and never writes the loop counter, which only changes by `delta`
+pub fn verify(prog: &IRProgram) -> Result<(), VerifyError> {
+    fn err(reason: &'static str, node: &IR) -> Result<(), VerifyError> {
+        Err(VerifyError {
+            reason,
+            node: node.clone(),
+        })
+    }
+
+    fn verify_simple_loop_body(node: &IR, irs: &[IR]) -> Result<(), VerifyError> {
+        if irs.iter().any(|ir| matches!(ir, IR::Loop(_))) {
+            return err("Loop inside SimpleLoop", node);
+        }
+        if may_write(irs, 0) {
+            return err("SimpleLoop body writes its counter", node);
+        }
+        let ptr: Offset = irs
+            .iter()
+            .map(|ir| match ir {
+                IR::PtrChange(amt) => *amt,
+                _ => 0,
+            })
+            .sum();
+        if ptr != 0 {
+            return err("SimpleLoop body moves the pointer", node);
+        }
+        Ok(())
+    }
+
+    fn recur(irs: &[IR]) -> Result<(), VerifyError> {
+        for ir in irs {
+            match ir {
+                IR::PtrChange(0) => return err("PtrChange by zero", ir),
+                IR::AddMul(0, _) => return err("AddMul targets its multiplier", ir),
+                IR::Loop(inner) => recur(inner)?,
+                IR::SimpleLoop(_, inner) => {
+                    verify_simple_loop_body(ir, inner)?;
+                    recur(inner)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    recur(&prog.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn verify_text(text: &str) -> Result<(), &'static str> {
+        verify(&crate::ir_parser::Parser::parse(text).unwrap()).map_err(|e| e.reason)
+    }
+
+    #[test]
+    fn test_repeat() {
+        let ir = |code| {
+            let dialect = crate::parser::Dialect {
+                rle: true,
+                ..Default::default()
+            };
+            let ast = crate::parser::Parser::parse_dialect(code, dialect).unwrap();
+            IRProgram::from_ast_program(&ast).0
+        };
+        assert_eq!(ir("(+>-<)*300"), [IR::Add(0, 44), IR::Add(1, -44)]);
+        assert_eq!(ir("(>>)*1000000"), [IR::PtrChange(2000000)]);
+        assert_eq!(ir("(+)*256(><)*3"), []);
+        assert_eq!(
+            ir("(+>)*2"),
+            [
+                IR::Add(0, 1),
+                IR::PtrChange(1),
+                IR::Add(0, 1),
+                IR::PtrChange(1)
+            ]
+        );
+        assert_eq!(ir("(.)*2"), [IR::Putch(0), IR::Putch(0)]);
+    }
+
+    #[test]
+    fn test_verify() {
+        assert_eq!(verify_text("loop {\n  ptrchange +1\n}"), Ok(()));
+        assert_eq!(
+            verify_text("simpleloop -1 {\n  ptrchange +1\n  add 0, 1\n  ptrchange -1\n}"),
+            Ok(())
+        );
+        assert_eq!(verify_text("ptrchange 0"), Err("PtrChange by zero"));
+        assert_eq!(
+            verify_text("loop {\n  addmul 0, 2\n}"),
+            Err("AddMul targets its multiplier")
+        );
+        assert_eq!(
+            verify_text("simpleloop -1 {\n  ptrchange +1\n}"),
+            Err("SimpleLoop body moves the pointer")
+        );
+        assert_eq!(
+            verify_text("simpleloop -1 {\n  ptrchange +1\n  getch -1\n  ptrchange -1\n}"),
+            Err("SimpleLoop body writes its counter")
+        );
+        assert_eq!(
+            verify_text(
+                "simpleloop -1 {\n  ptrchange +1\n  simpleloop -1 {\n    add -1, 1\n  }\n  ptrchange -1\n}"
+            ),
+            Err("SimpleLoop body writes its counter")
+        );
+        assert_eq!(
+            verify_text("simpleloop -1 {\n  loop {\n  }\n}"),
+            Err("Loop inside SimpleLoop")
+        );
+    }
 }
@@ -0,0 +1,216 @@
+use crate::ir::{self, IRProgram, IR};
+use std::fmt;
+
+// Parses the textual IR printed by `IRProgram`'s `Display` impl. Each line is
+// one instruction, blocks are opened with `{` at the end of a `loop` or
+// `simpleloop` line and closed with a lone `}`. Anything after a `;` is a
+// comment. Line numbers in errors are 1-based.
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    UnknownInstruction(usize, String),
+    BadOperand(usize, String),
+    WrongOperandCount(usize),
+    UnterminatedBlock,
+    UnexpectedBlockTermination(usize),
+}
+
+impl Error {
+    // The 1-based line of the error, if it is about one
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Error::UnknownInstruction(line, _)
+            | Error::BadOperand(line, _)
+            | Error::WrongOperandCount(line)
+            | Error::UnexpectedBlockTermination(line) => Some(*line),
+            Error::UnterminatedBlock => None,
+        }
+    }
+
+    // A stable name for tools, see `diagnostics`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownInstruction(..) => "unknown-instruction",
+            Error::BadOperand(..) => "bad-operand",
+            Error::WrongOperandCount(_) => "wrong-operand-count",
+            Error::UnterminatedBlock => "unterminated-block",
+            Error::UnexpectedBlockTermination(_) => "unexpected-block-end",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownInstruction(_, name) => write!(f, "unknown instruction `{}`", name),
+            Error::BadOperand(_, operand) => write!(f, "bad operand `{}`", operand),
+            Error::WrongOperandCount(_) => write!(f, "wrong number of operands"),
+            Error::UnterminatedBlock => write!(f, "`{{` without a matching `}}`"),
+            Error::UnexpectedBlockTermination(_) => write!(f, "`}}` without a matching `{{`"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Parser<'a> {
+    lines: Vec<(usize, &'a str)>,
+    off: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn parse(text: &'a str) -> Result<IRProgram> {
+        let lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l.split(';').next().unwrap().trim()))
+            .filter(|(_, l)| !l.is_empty())
+            .collect();
+        let mut p = Self { lines, off: 0 };
+        Ok(IRProgram(p.parse_inner(false)?))
+    }
+
+    fn parse_inner(&mut self, is_block: bool) -> Result<Vec<IR>> {
+        let mut ret = Vec::new();
+        while self.off < self.lines.len() {
+            let (lineno, line) = self.lines[self.off];
+            self.off += 1;
+
+            if line == "}" {
+                return if is_block {
+                    Ok(ret)
+                } else {
+                    Err(Error::UnexpectedBlockTermination(lineno))
+                };
+            }
+
+            let (line, opens_block) = match line.strip_suffix('{') {
+                Some(l) => (l.trim_end(), true),
+                None => (line, false),
+            };
+            let (op, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let args: Vec<&str> = if rest.trim().is_empty() {
+                Vec::new()
+            } else {
+                rest.split(',').map(str::trim).collect()
+            };
+
+            let wants_block = matches!(op, "loop" | "simpleloop");
+            if wants_block != opens_block {
+                return Err(Error::BadOperand(lineno, line.to_string()));
+            }
+
+            let ir = match (op, args.as_slice()) {
+                ("loop", []) => IR::Loop(self.parse_inner(true)?),
+                ("simpleloop", [delta]) => {
+                    let delta = Self::value(lineno, delta)?;
+                    IR::SimpleLoop(delta, self.parse_inner(true)?)
+                }
+                ("ptrchange", [amt]) => IR::PtrChange(Self::offset(lineno, amt)?),
+                ("add", [off, amt]) => {
+                    IR::Add(Self::offset(lineno, off)?, Self::value(lineno, amt)?)
+                }
+                ("putch", [off]) => IR::Putch(Self::offset(lineno, off)?),
+                ("getch", [off]) => IR::Getch(Self::offset(lineno, off)?),
+                ("addmul", [off, amt]) => {
+                    IR::AddMul(Self::offset(lineno, off)?, Self::value(lineno, amt)?)
+                }
+                ("movimm", [off, val]) => {
+                    IR::MovImm(Self::offset(lineno, off)?, Self::value(lineno, val)?)
+                }
+                ("dump", []) => IR::Dump,
+                (
+                    "loop" | "simpleloop" | "ptrchange" | "add" | "putch" | "getch" | "addmul"
+                    | "movimm" | "dump",
+                    _,
+                ) => return Err(Error::WrongOperandCount(lineno)),
+                _ => return Err(Error::UnknownInstruction(lineno, op.to_string())),
+            };
+            ret.push(ir);
+        }
+        if is_block {
+            Err(Error::UnterminatedBlock)
+        } else {
+            Ok(ret)
+        }
+    }
+
+    fn offset(lineno: usize, s: &str) -> Result<ir::Offset> {
+        s.parse()
+            .map_err(|_| Error::BadOperand(lineno, s.to_string()))
+    }
+
+    fn value(lineno: usize, s: &str) -> Result<ir::Value> {
+        s.parse()
+            .map_err(|_| Error::BadOperand(lineno, s.to_string()))
+    }
+}
+
+macro_rules! make_test {
+    ($test_name:ident, $text:expr, $prog:expr) => {
+        #[cfg(test)]
+        mod $test_name {
+            use super::*;
+            #[test]
+            fn test_eq() {
+                assert_eq!(Parser::parse($text), $prog)
+            }
+        }
+    };
+}
+
+make_test!(empty, "", Ok(IRProgram(vec![])));
+make_test!(
+    simple,
+    "ptrchange -1\nadd +3, 5\nputch 0\ngetch +2\naddmul -1, -5\nmovimm 0, 0\ndump\n",
+    Ok(IRProgram(vec![
+        IR::PtrChange(-1),
+        IR::Add(3, 5),
+        IR::Putch(0),
+        IR::Getch(2),
+        IR::AddMul(-1, -5),
+        IR::MovImm(0, 0),
+        IR::Dump
+    ]))
+);
+make_test!(
+    blocks,
+    "loop {\n  simpleloop -1 { ; comment\n    add +1, 1\n  }\n}",
+    Ok(IRProgram(vec![IR::Loop(vec![IR::SimpleLoop(
+        -1,
+        vec![IR::Add(1, 1)]
+    )])]))
+);
+make_test!(unterminated, "loop {\n", Err(Error::UnterminatedBlock));
+make_test!(
+    unexpected_termination,
+    "putch 0\n}",
+    Err(Error::UnexpectedBlockTermination(2))
+);
+make_test!(
+    unknown,
+    "jmp 0",
+    Err(Error::UnknownInstruction(1, "jmp".to_string()))
+);
+make_test!(operand_count, "add 1", Err(Error::WrongOperandCount(1)));
+make_test!(
+    value_range,
+    "add 0, 128",
+    Err(Error::BadOperand(1, "128".to_string()))
+);
+
+#[cfg(test)]
+mod round_trip {
+    use super::*;
+
+    #[test]
+    fn test_programs() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let code = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            let ap = crate::parser::Parser::parse(&code).unwrap();
+            let ip = crate::optimize::optimize(&IRProgram::from_ast_program(&ap));
+            assert_eq!(Parser::parse(&ip.to_string()), Ok(ip));
+        }
+    }
+}
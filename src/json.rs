@@ -0,0 +1,367 @@
+use std::fmt::{self, Write};
+
+// A small JSON reader and writer, enough for the messages of `lsp` and
+// `diagnostics`. Objects keep their keys in order.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    UnexpectedEnd,
+    // At a byte offset
+    Unexpected(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// How deeply arrays and objects may nest
+const MAX_DEPTH: usize = 128;
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut p = Parser {
+            text: text.as_bytes(),
+            off: 0,
+            depth: 0,
+        };
+        let ret = p.value()?;
+        p.skip_whitespace();
+        if p.off < p.text.len() {
+            return Err(Error::Unexpected(p.off));
+        }
+        Ok(ret)
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    // The value of `key` if this is an object that has it
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= u32::MAX as f64 => {
+                Some(n as usize)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write!(f, "{}", string(s)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", string(k), v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// `s` as a JSON string
+pub fn string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(ret, "\\u{:04x}", c as u32).unwrap(),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    off: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.off) {
+            self.off += 1;
+        }
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.text.get(self.off).copied().ok_or(Error::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, b: u8) -> Result<()> {
+        if self.peek()? != b {
+            return Err(Error::Unexpected(self.off));
+        }
+        self.off += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json> {
+        for b in word.bytes() {
+            self.expect(b)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => Ok(Json::String(self.string()?)),
+            b'[' | b'{' if self.depth == MAX_DEPTH => Err(Error::Unexpected(self.off)),
+            b'[' => {
+                self.off += 1;
+                self.depth += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek()? != b']' {
+                    loop {
+                        values.push(self.value()?);
+                        self.skip_whitespace();
+                        if self.peek()? != b',' {
+                            break;
+                        }
+                        self.off += 1;
+                    }
+                }
+                self.expect(b']')?;
+                self.depth -= 1;
+                Ok(Json::Array(values))
+            }
+            b'{' => {
+                self.off += 1;
+                self.depth += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.peek()? != b'}' {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.skip_whitespace();
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+                        self.skip_whitespace();
+                        if self.peek()? != b',' {
+                            break;
+                        }
+                        self.off += 1;
+                    }
+                }
+                self.expect(b'}')?;
+                self.depth -= 1;
+                Ok(Json::Object(fields))
+            }
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => Err(Error::Unexpected(self.off)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.off;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.text.get(self.off) {
+            self.off += 1;
+        }
+        // The slice is ASCII
+        let text = std::str::from_utf8(&self.text[start..self.off]).unwrap();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| Error::Unexpected(start))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .text
+            .get(self.off..self.off + 4)
+            .ok_or(Error::UnexpectedEnd)?;
+        let ret = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or(Error::Unexpected(self.off))?;
+        self.off += 4;
+        Ok(ret)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut ret = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.off += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek()?;
+                    self.off += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text.get(self.off..self.off + 2) == Some(b"\\u")
+                            {
+                                self.off += 2;
+                                let low = self.hex4()?;
+                                if (0xdc00..0xe000).contains(&low) {
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                } else {
+                                    // A lone high surrogate, and `low` is read
+                                    // as its own escape
+                                    self.off -= 6;
+                                }
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(Error::Unexpected(self.off - 1)),
+                    };
+                    ret.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                b => ret.push(b),
+            }
+        }
+        // The input is a `str` and escapes add whole characters
+        Ok(String::from_utf8(ret).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let json =
+            Json::parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "x\"\né😀"}, "d": []} "#)
+                .unwrap();
+        assert_eq!(
+            json.get("a").unwrap().as_array().unwrap(),
+            [
+                Json::Number(1.0),
+                Json::Number(-25.0),
+                Json::Bool(true),
+                Json::Null
+            ]
+        );
+        assert_eq!(
+            json.get("b")
+                .and_then(|b| b.get("c"))
+                .and_then(Json::as_str),
+            Some("x\"\né😀")
+        );
+        assert_eq!(json.get("a").unwrap().get("c"), None);
+        let pair = Json::parse(r#""\ud83d\ude00\u00e9""#).unwrap();
+        assert_eq!(pair.as_str(), Some("😀é"));
+        // A high surrogate without a low one is replaced
+        let lone = Json::parse(r#""\ud800\u0000\ud800\ud83d\ude00""#).unwrap();
+        assert_eq!(lone.as_str(), Some("\u{fffd}\0\u{fffd}😀"));
+        assert_eq!(
+            json.to_string(),
+            r#"{"a":[1,-25,true,null],"b":{"c":"x\"\né😀"},"d":[]}"#
+        );
+        assert_eq!(Json::parse("[1,]"), Err(Error::Unexpected(3)));
+        assert_eq!(Json::parse("{\"a\":"), Err(Error::UnexpectedEnd));
+        assert_eq!(Json::parse("1 2"), Err(Error::Unexpected(2)));
+        assert!(Json::parse(&"[".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(string("a\"\\\t\u{1}é"), "\"a\\\"\\\\\\t\\u0001é\"");
+        assert_eq!(Json::Number(1.5).to_string(), "1.5");
+        assert_eq!(Json::from(7usize).as_usize(), Some(7));
+        assert_eq!(Json::Number(-1.0).as_usize(), None);
+    }
+}
@@ -0,0 +1,63 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod ast;
+pub mod bf_emitter;
+pub mod bounds;
+pub mod c_emitter;
+pub mod check;
+pub mod debug;
+pub mod decompile;
+pub mod diagnostics;
+pub mod driver;
+pub mod eval;
+pub mod format;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+pub mod ir;
+pub mod ir_parser;
+pub mod json;
+pub mod lint;
+pub mod lsp;
+pub mod optimize;
+pub mod parser;
+pub mod preprocess;
+pub mod profile;
+pub mod riscv_emitter;
+pub mod stats;
+pub mod syntax;
+mod test;
+pub mod trace;
+pub mod x86_emitter;
+
+use clap::ValueEnum;
+use std::io::Write;
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum Arch {
+    #[value(name = "x86_64")]
+    X86_64,
+    RiscV,
+    C,
+    Ir,
+    // Brainfuck again, from the optimized IR
+    Bf,
+    // Pseudo-code with named cells, for reading
+    Decompile,
+}
+
+pub fn emit(
+    prog: &ir::IRProgram,
+    arch: &Arch,
+    nostdlib: bool,
+    mem_size: usize,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    match arch {
+        Arch::X86_64 => x86_emitter::X86Emitter::emit(prog, nostdlib, mem_size, out),
+        Arch::RiscV => riscv_emitter::RiscVEmitter::emit(prog, nostdlib, mem_size, out),
+        Arch::C => c_emitter::CEmitter::emit(prog, nostdlib, mem_size, out),
+        Arch::Ir => write!(out, "{}", prog),
+        Arch::Bf => bf_emitter::BFEmitter::emit(prog, nostdlib, mem_size, out),
+        Arch::Decompile => write!(out, "{}", decompile::decompile(prog)),
+    }
+}
@@ -0,0 +1,267 @@
+use crate::ir::{self, IRProgram, Offset, IR};
+use crate::parser::{Parser, Span};
+use std::collections::HashMap;
+use std::fmt;
+
+// Lints for loops that can't do what they look like they do. Cell values are
+// tracked through straight-line code from the start of the program, which
+// zeroes the tape, and the lints are reported where a loop is reached with a
+// cell known to be zero, or non-zero.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lint {
+    // The loop is reached with its cell at zero, so its body never runs
+    DeadLoop,
+    // The body can't change the loop's cell, and the cell is known to be
+    // non-zero when the loop is reached
+    InfiniteLoop,
+    // Like `InfiniteLoop`, but the loop might not be entered at all
+    HangsIfEntered,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    // The loop in the source, and its 1-based line and column, when there is
+    // a source
+    pub span: Option<Span>,
+    pub loc: Option<(usize, usize)>,
+    // The loop's source, or its IR if there is no source
+    pub text: String,
+}
+
+impl Lint {
+    // A stable name for tools, see `diagnostics`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::DeadLoop => "dead-loop",
+            Lint::InfiniteLoop => "infinite-loop",
+            Lint::HangsIfEntered => "hangs-if-entered",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Lint::DeadLoop => "this loop never runs, its cell is always zero here",
+            Lint::InfiniteLoop => "this loop never terminates",
+            Lint::HangsIfEntered => "this loop never terminates once it is entered",
+        }
+    }
+}
+
+// How much of a loop's text `Diagnostic` displays
+const TEXT_LEN: usize = 40;
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.chars().count() > TEXT_LEN {
+            text = text.chars().take(TEXT_LEN - 3).collect::<String>() + "...";
+        }
+        match self.loc {
+            Some((line, col)) => write!(f, "{}:{}: {}: {}", line, col, self.lint.message(), text),
+            None => write!(f, "{}: {}", self.lint.message(), text),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Cell {
+    Known(u8),
+    NonZero,
+    Unknown,
+}
+
+struct Linter<'a> {
+    source: Option<&'a str>,
+    spans: Option<Vec<Span>>,
+    // Preorder index of the next loop
+    next_loop: usize,
+    // Cells relative to where the pointer was at the start, or when cells
+    // were last forgotten. Cells that aren't in `cells` are zero while
+    // `fresh`, and unknown otherwise.
+    cells: HashMap<Offset, Cell>,
+    ptr: Offset,
+    fresh: bool,
+    // Preorder indexes of loops that open or close the program, which are
+    // taken for comments when they are dead
+    comments: Vec<usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn get(&self, off: Offset) -> Cell {
+        match self.cells.get(&(self.ptr + off)) {
+            Some(cell) => *cell,
+            None if self.fresh => Cell::Known(0),
+            None => Cell::Unknown,
+        }
+    }
+
+    fn set(&mut self, off: Offset, cell: Cell) {
+        self.cells.insert(self.ptr + off, cell);
+    }
+
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.ptr = 0;
+        self.fresh = false;
+    }
+
+    fn report(&mut self, lint: Lint, idx: usize, ir: &IR) {
+        let span = self.spans.as_ref().and_then(|s| s.get(idx));
+        let (loc, text) = match (self.source, span) {
+            (Some(source), Some(span)) => (
+                Some(span.line_col(source)),
+                source
+                    .chars()
+                    .skip(span.start)
+                    .take(span.end - span.start)
+                    .collect(),
+            ),
+            _ => (None, ir.to_string()),
+        };
+        self.diagnostics.push(Diagnostic {
+            lint,
+            span: span.copied().filter(|_| self.source.is_some()),
+            loc,
+            text,
+        });
+    }
+
+    // Counts the loops in `irs`, which are skipped
+    fn skip(irs: &[IR]) -> usize {
+        irs.iter()
+            .map(|ir| match ir {
+                IR::Loop(inner) | IR::SimpleLoop(_, inner) => 1 + Self::skip(inner),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn series(&mut self, irs: &[IR]) {
+        for ir in irs {
+            match ir {
+                IR::PtrChange(amt) => {
+                    self.ptr += amt;
+                }
+                IR::Add(off, amt) => {
+                    let cell = match self.get(*off) {
+                        Cell::Known(v) => Cell::Known(v.wrapping_add(*amt as u8)),
+                        _ => Cell::Unknown,
+                    };
+                    self.set(*off, cell);
+                }
+                IR::MovImm(off, val) => {
+                    self.set(*off, Cell::Known(*val as u8));
+                }
+                IR::Getch(off) | IR::AddMul(off, _) => {
+                    self.set(*off, Cell::Unknown);
+                }
+                IR::Putch(_) | IR::Dump => {}
+                IR::Loop(inner) | IR::SimpleLoop(_, inner) => {
+                    let idx = self.next_loop;
+                    self.next_loop += 1;
+                    let entry = self.get(0);
+                    if entry == Cell::Known(0) {
+                        // Nothing in a dead loop is worth reporting too
+                        if !self.comments.contains(&idx) {
+                            self.report(Lint::DeadLoop, idx, ir);
+                        }
+                        self.next_loop += Self::skip(inner);
+                        continue;
+                    }
+                    let delta = match ir {
+                        IR::SimpleLoop(delta, _) => *delta,
+                        _ => 0,
+                    };
+                    let stuck =
+                        delta == 0 && ir::movement(inner) == Some(0) && !ir::may_write(inner, 0);
+                    if stuck {
+                        let lint = match entry {
+                            Cell::Unknown => Lint::HangsIfEntered,
+                            _ => Lint::InfiniteLoop,
+                        };
+                        self.report(lint, idx, ir);
+                    }
+                    self.forget();
+                    self.set(0, Cell::NonZero);
+                    self.series(inner);
+                    self.forget();
+                    self.set(0, Cell::Known(0));
+                }
+            }
+        }
+    }
+}
+
+// `source` is the brainfuck `prog` was translated from, before any
+// optimization, and gives the loops their locations.
+pub fn lint(prog: &IRProgram, source: Option<&str>) -> Vec<Diagnostic> {
+    let mut l = Linter {
+        source,
+        spans: source.map(Parser::loop_spans),
+        next_loop: 0,
+        cells: HashMap::new(),
+        ptr: 0,
+        fresh: true,
+        comments: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    if let Some(IR::Loop(_)) = prog.0.first() {
+        l.comments.push(0);
+    }
+    if let Some(IR::Loop(inner)) = prog.0.last() {
+        l.comments
+            .push(Linter::skip(&prog.0) - 1 - Linter::skip(inner));
+    }
+    l.series(&prog.0);
+    l.diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lints(code: &str) -> Vec<(Lint, (usize, usize))> {
+        let prog = IRProgram::from_ast_program(&Parser::parse(code).unwrap());
+        lint(&prog, Some(code))
+            .into_iter()
+            .map(|d| (d.lint, d.loc.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_infinite() {
+        assert_eq!(lints("+[]"), [(Lint::InfiniteLoop, (1, 2))]);
+        assert_eq!(lints("+>+<[>.<]"), [(Lint::InfiniteLoop, (1, 5))]);
+        assert_eq!(lints(",[.]"), [(Lint::HangsIfEntered, (1, 2))]);
+        // The body enters the inner loop with its cell non-zero
+        assert_eq!(lints(",[\n  []-]"), [(Lint::InfiniteLoop, (2, 3))]);
+        // Loops that move or change their cell may end
+        assert_eq!(lints("+[>]+[-]+[[-]]+[,]"), []);
+    }
+
+    #[test]
+    fn test_dead() {
+        assert_eq!(
+            lints("[comment [.]]>+[-][.],[.-]"),
+            [(Lint::DeadLoop, (1, 19))]
+        );
+        // Only loops that open or close the program are taken for comments
+        assert_eq!(lints(">[comment]"), []);
+        assert_eq!(lints(">[comment]+"), [(Lint::DeadLoop, (1, 2))]);
+        // A hanging loop inside a dead one isn't reported
+        assert_eq!(lints("+-[[]]."), [(Lint::DeadLoop, (1, 3))]);
+    }
+
+    #[test]
+    fn test_without_source() {
+        let prog = IRProgram::from_ast_program(&Parser::parse("+[]").unwrap());
+        let diagnostics = lint(&prog, None);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "this loop never terminates: loop { }"
+        );
+    }
+}
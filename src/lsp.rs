@@ -0,0 +1,576 @@
+use crate::format::{self, Style};
+use crate::ir::{self, IRProgram};
+use crate::json::Json;
+use crate::optimize::optimize_loop;
+use crate::parser::{Dialect, Parser, Span};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+// A language server for brainfuck files, speaking LSP over a pair of streams.
+// Documents are synced whole and parsed as plain brainfuck. It offers:
+//  - diagnostics for unmatched brackets
+//  - hover on a loop, with its net pointer movement and optimized IR
+//  - go-to-definition on a bracket, which goes to the matching one
+//  - folding ranges for loops over several lines
+//  - formatting, as `bfc fmt` does
+
+// JSON-RPC error codes
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+type Response = Result<Json, (i32, String)>;
+
+// Reads a message framed by a `Content-Length` header, `None` at EOF
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse().ok();
+            }
+        }
+    }
+    let Some(len) = len else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "a message without a Content-Length",
+        ));
+    };
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf)?;
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(out: &mut impl Write, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+// The LSP position of the character at `offset`: a 0-based line, and a column
+// in UTF-16 code units
+fn position(text: &str, offset: usize) -> Json {
+    let mut line = 0;
+    let mut character = 0;
+    for c in text.chars().take(offset) {
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16();
+        }
+    }
+    Json::object(vec![("line", line.into()), ("character", character.into())])
+}
+
+fn range(text: &str, span: Span) -> Json {
+    Json::object(vec![
+        ("start", position(text, span.start)),
+        ("end", position(text, span.end)),
+    ])
+}
+
+// The character offset of an LSP position, clamped to the end of its line
+fn offset(text: &str, pos: &Json) -> Option<usize> {
+    let line = pos.get("line")?.as_usize()?;
+    let character = pos.get("character")?.as_usize()?;
+    let mut cur = 0;
+    let mut col = 0;
+    for (i, c) in text.chars().enumerate() {
+        if cur == line && (col >= character || c == '\n') {
+            return Some(i);
+        }
+        if c == '\n' {
+            cur += 1;
+        } else if cur == line {
+            col += c.len_utf16();
+        }
+    }
+    Some(text.chars().count())
+}
+
+fn diagnostics(text: &str) -> Json {
+    let dialect = Dialect::default();
+    let Err(e) = Parser::parse_dialect(text, dialect) else {
+        return Json::Array(Vec::new());
+    };
+    let span = Parser::error_span(text, dialect).unwrap_or(Span { start: 0, end: 0 });
+    Json::Array(vec![Json::object(vec![
+        ("range", range(text, span)),
+        ("severity", 1.into()),
+        ("code", e.code().into()),
+        ("source", "bfc".into()),
+        ("message", e.to_string().into()),
+    ])])
+}
+
+// The loops of `text`, if its brackets match
+fn loop_spans(text: &str) -> Option<Vec<Span>> {
+    Parser::parse(text).ok()?;
+    Some(Parser::loop_spans(text))
+}
+
+fn hover(text: &str, offset: usize) -> Json {
+    // Spans are in preorder, so the innermost loop is the last one
+    let Some(span) = loop_spans(text).and_then(|spans| {
+        spans
+            .into_iter()
+            .rfind(|s| s.start <= offset && offset < s.end)
+    }) else {
+        return Json::Null;
+    };
+    let body: String = text
+        .chars()
+        .skip(span.start + 1)
+        .take(span.end - span.start - 2)
+        .collect();
+    let body = IRProgram::from_ast_program(&Parser::parse(&body).unwrap()).0;
+    let movement = match ir::movement(&body) {
+        Some(0) => "none".to_string(),
+        Some(amt) => format!("{:+} per iteration", amt),
+        None => "depends on the tape".to_string(),
+    };
+    let prog = IRProgram(optimize_loop(&body));
+    let value = format!("net pointer movement: {}\n\n```\n{}```", movement, prog);
+    Json::object(vec![
+        (
+            "contents",
+            Json::object(vec![("kind", "markdown".into()), ("value", value.into())]),
+        ),
+        ("range", range(text, span)),
+    ])
+}
+
+// The bracket matching the one at `offset`, or just before it
+fn matching(text: &str, offset: usize) -> Option<usize> {
+    let spans = loop_spans(text)?;
+    [Some(offset), offset.checked_sub(1)]
+        .into_iter()
+        .flatten()
+        .find_map(|off| {
+            spans.iter().find_map(|s| {
+                if s.start == off {
+                    Some(s.end - 1)
+                } else if s.end - 1 == off {
+                    Some(s.start)
+                } else {
+                    None
+                }
+            })
+        })
+}
+
+fn folding_ranges(text: &str) -> Json {
+    let line = |off| {
+        Span {
+            start: off,
+            end: off,
+        }
+        .line_col(text)
+        .0 - 1
+    };
+    let ranges = loop_spans(text)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|s| {
+            let (start, end) = (line(s.start), line(s.end - 1));
+            (end > start)
+                .then(|| Json::object(vec![("startLine", start.into()), ("endLine", end.into())]))
+        })
+        .collect();
+    Json::Array(ranges)
+}
+
+fn formatting(text: &str, indent: usize) -> Json {
+    let Ok(ast) = Parser::parse_comments(text, Dialect::default()) else {
+        return Json::Null;
+    };
+    let style = Style {
+        indent,
+        ..Default::default()
+    };
+    let formatted = format::format(&ast.0, &style);
+    if formatted == text {
+        return Json::Array(Vec::new());
+    }
+    let whole = Span {
+        start: 0,
+        end: text.chars().count(),
+    };
+    Json::Array(vec![Json::object(vec![
+        ("range", range(text, whole)),
+        ("newText", formatted.into()),
+    ])])
+}
+
+struct Server<W> {
+    out: W,
+    // The text of each open document, by URI
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn send(&mut self, fields: Vec<(&str, Json)>) -> io::Result<()> {
+        let mut msg = vec![("jsonrpc", "2.0".into())];
+        msg.extend(fields);
+        write_message(&mut self.out, &Json::object(msg))
+    }
+
+    fn respond(&mut self, id: Json, response: Response) -> io::Result<()> {
+        let body = match response {
+            Ok(result) => ("result", result),
+            Err((code, message)) => (
+                "error",
+                Json::object(vec![
+                    ("code", Json::Number(code.into())),
+                    ("message", message.into()),
+                ]),
+            ),
+        };
+        self.send(vec![("id", id), body])
+    }
+
+    fn publish(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => diagnostics(text),
+            None => Json::Array(Vec::new()),
+        };
+        let params = Json::object(vec![("uri", uri.into()), ("diagnostics", diagnostics)]);
+        self.send(vec![
+            ("method", "textDocument/publishDiagnostics".into()),
+            ("params", params),
+        ])
+    }
+
+    // Handles a notification, returning whether to go on
+    fn notify(&mut self, method: &str, params: &Json) -> io::Result<bool> {
+        let doc = params.get("textDocument");
+        let uri = doc.and_then(|d| d.get("uri")).and_then(Json::as_str);
+        let (uri, text) = match (method, uri) {
+            ("exit", _) => return Ok(false),
+            ("textDocument/didOpen", Some(uri)) => {
+                (uri, doc.and_then(|d| d.get("text")).and_then(Json::as_str))
+            }
+            // With full syncing the last change is the whole text
+            ("textDocument/didChange", Some(uri)) => (
+                uri,
+                params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|c| c.last())
+                    .and_then(|c| c.get("text"))
+                    .and_then(Json::as_str),
+            ),
+            ("textDocument/didClose", Some(uri)) => (uri, None),
+            _ => return Ok(true),
+        };
+        match text {
+            Some(text) => self.documents.insert(uri.to_string(), text.to_string()),
+            None => self.documents.remove(uri),
+        };
+        self.publish(uri)?;
+        Ok(true)
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Response {
+        if self.shut_down {
+            return Err((INVALID_REQUEST, "the server is shut down".to_string()));
+        }
+        match method {
+            "initialize" => return Ok(capabilities()),
+            "shutdown" => {
+                self.shut_down = true;
+                return Ok(Json::Null);
+            }
+            _ => {}
+        }
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str);
+        let text = uri.and_then(|uri| self.documents.get(uri));
+        let offset = text
+            .zip(params.get("position"))
+            .and_then(|(t, p)| offset(t, p));
+        match (method, uri, text, offset) {
+            ("textDocument/hover", _, Some(text), Some(offset)) => Ok(hover(text, offset)),
+            ("textDocument/definition", Some(uri), Some(text), Some(offset)) => {
+                Ok(matching(text, offset).map_or(Json::Null, |target| {
+                    let span = Span {
+                        start: target,
+                        end: target + 1,
+                    };
+                    Json::object(vec![("uri", uri.into()), ("range", range(text, span))])
+                }))
+            }
+            ("textDocument/foldingRange", _, Some(text), _) => Ok(folding_ranges(text)),
+            ("textDocument/formatting", _, Some(text), _) => {
+                let indent = params
+                    .get("options")
+                    .and_then(|o| o.get("tabSize"))
+                    .and_then(Json::as_usize)
+                    .unwrap_or(Style::default().indent);
+                Ok(formatting(text, indent))
+            }
+            (
+                "textDocument/hover"
+                | "textDocument/definition"
+                | "textDocument/foldingRange"
+                | "textDocument/formatting",
+                ..,
+            ) => Err((INVALID_PARAMS, "unknown document or position".to_string())),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        }
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                // Full syncing
+                ("textDocumentSync", 1.into()),
+                ("hoverProvider", true.into()),
+                ("definitionProvider", true.into()),
+                ("foldingRangeProvider", true.into()),
+                ("documentFormattingProvider", true.into()),
+            ]),
+        ),
+        ("serverInfo", Json::object(vec![("name", "bfc".into())])),
+    ])
+}
+
+// Serves a client until it sends `exit` or closes `input`. Returns whether it
+// shut the server down first, which the exit status should say.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<bool> {
+    let mut server = Server {
+        out: output,
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+    while let Some(text) = read_message(&mut input)? {
+        let Ok(msg) = Json::parse(&text) else {
+            server.respond(Json::Null, Err((PARSE_ERROR, "invalid JSON".to_string())))?;
+            continue;
+        };
+        // Responses to requests, which the server never sends, have none
+        let Some(method) = msg.get("method").and_then(Json::as_str) else {
+            continue;
+        };
+        let params = msg.get("params").unwrap_or(&Json::Null);
+        match msg.get("id") {
+            Some(id) => {
+                let response = server.request(method, params);
+                server.respond(id.clone(), response)?;
+            }
+            None => {
+                if !server.notify(method, params)? {
+                    return Ok(server.shut_down);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const URI: &str = "file:///a.b";
+
+    // Runs a session of `(method, params)` messages, with ids for those that
+    // are requests, and returns what the server sent back
+    fn session(msgs: &[(&str, &str)]) -> (bool, Vec<Json>) {
+        let mut input = Vec::new();
+        for (i, (method, params)) in msgs.iter().enumerate() {
+            let id = if method.starts_with("textDocument/did") || *method == "exit" {
+                String::new()
+            } else {
+                format!("\"id\":{},", i)
+            };
+            let body = format!(
+                "{{\"jsonrpc\":\"2.0\",{}\"method\":\"{}\",\"params\":{}}}",
+                id, method, params
+            );
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        let mut output = Vec::new();
+        let clean = serve(&input[..], &mut output).unwrap();
+        let mut output = &output[..];
+        let mut ret = Vec::new();
+        while let Some(msg) = read_message(&mut output).unwrap() {
+            ret.push(Json::parse(&msg).unwrap());
+        }
+        (clean, ret)
+    }
+
+    fn open(text: &str) -> String {
+        format!(
+            "{{\"textDocument\":{{\"uri\":\"{}\",\"languageId\":\"bf\",\"version\":1,\"text\":{}}}}}",
+            URI,
+            crate::json::string(text)
+        )
+    }
+
+    fn at(line: usize, character: usize) -> String {
+        format!(
+            "{{\"textDocument\":{{\"uri\":\"{}\"}},\"position\":{{\"line\":{},\"character\":{}}}}}",
+            URI, line, character
+        )
+    }
+
+    fn result(msg: &Json) -> String {
+        msg.get("result").unwrap().to_string()
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let (clean, out) = session(&[
+            ("initialize", "{}"),
+            ("initialized", "{}"),
+            ("bogus", "{}"),
+            ("shutdown", "null"),
+            ("exit", "null"),
+        ]);
+        assert!(clean);
+        assert_eq!(out.len(), 4);
+        let capabilities = out[0].get("result").unwrap().get("capabilities").unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+        // `initialized` is a request here, and unknown ones fail
+        let code = |msg: &Json| msg.get("error").unwrap().get("code").unwrap().clone();
+        assert_eq!(code(&out[1]), Json::Number(METHOD_NOT_FOUND.into()));
+        assert_eq!(code(&out[2]), Json::Number(METHOD_NOT_FOUND.into()));
+        assert_eq!(result(&out[3]), "null");
+        let (clean, _) = session(&[("exit", "null")]);
+        assert!(!clean);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let change = format!(
+            "{{\"textDocument\":{{\"uri\":\"{}\",\"version\":2}},\"contentChanges\":[{{\"text\":\"[]\"}}]}}",
+            URI
+        );
+        let (_, out) = session(&[
+            ("textDocument/didOpen", &open("+\n é]")),
+            ("textDocument/didChange", &change),
+        ]);
+        assert_eq!(
+            out[0].to_string(),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\
+             \"params\":{\"uri\":\"file:///a.b\",\"diagnostics\":[{\"range\":{\
+             \"start\":{\"line\":1,\"character\":2},\"end\":{\"line\":1,\"character\":3}},\
+             \"severity\":1,\"code\":\"unexpected-loop-end\",\"source\":\"bfc\",\
+             \"message\":\"`]` without a matching `[`\"}]}}"
+        );
+        let params = out[1].get("params").unwrap();
+        assert_eq!(params.get("diagnostics"), Some(&Json::Array(Vec::new())));
+    }
+
+    #[test]
+    fn test_navigation() {
+        let text = "+[\n  ->>[-]<<\n]";
+        let (_, out) = session(&[
+            ("textDocument/didOpen", &open(text)),
+            ("textDocument/definition", &at(0, 1)),
+            ("textDocument/definition", &at(1, 8)),
+            ("textDocument/definition", &at(0, 0)),
+            ("textDocument/foldingRange", &at(0, 0)),
+        ]);
+        assert_eq!(
+            result(&out[1]),
+            "{\"uri\":\"file:///a.b\",\"range\":{\"start\":{\"line\":2,\"character\":0},\
+             \"end\":{\"line\":2,\"character\":1}}}"
+        );
+        // Just after the `]` of `[-]`
+        assert_eq!(
+            result(&out[2]),
+            "{\"uri\":\"file:///a.b\",\"range\":{\"start\":{\"line\":1,\"character\":5},\
+             \"end\":{\"line\":1,\"character\":6}}}"
+        );
+        assert_eq!(result(&out[3]), "null");
+        assert_eq!(result(&out[4]), "[{\"startLine\":0,\"endLine\":2}]");
+    }
+
+    #[test]
+    fn test_hover() {
+        let text = "+[->>+<]";
+        let (_, out) = session(&[
+            ("textDocument/didOpen", &open(text)),
+            ("textDocument/hover", &at(0, 4)),
+            ("textDocument/hover", &at(0, 0)),
+        ]);
+        let hover = out[1].get("result").unwrap();
+        assert_eq!(
+            hover.get("contents").unwrap().get("value").unwrap().as_str(),
+            Some("net pointer movement: +1 per iteration\n\n```\nloop {\n  add 0, -1\n  add +2, 1\n  ptrchange +1\n}\n```")
+        );
+        assert_eq!(result(&out[2]), "null");
+
+        let (_, out) = session(&[
+            ("textDocument/didOpen", &open("[->++<]")),
+            ("textDocument/hover", &at(0, 3)),
+        ]);
+        let hover = out[1].get("result").unwrap();
+        assert_eq!(
+            hover
+                .get("contents")
+                .unwrap()
+                .get("value")
+                .unwrap()
+                .as_str(),
+            Some("net pointer movement: none\n\n```\naddmul +1, 2\nmovimm 0, 0\n```")
+        );
+    }
+
+    #[test]
+    fn test_formatting() {
+        let params = format!(
+            "{{\"textDocument\":{{\"uri\":\"{}\"}},\"options\":{{\"tabSize\":4,\"insertSpaces\":true}}}}",
+            URI
+        );
+        let (_, out) = session(&[
+            ("textDocument/didOpen", &open("a\n[-[.]]")),
+            ("textDocument/formatting", &params),
+            ("textDocument/didClose", &at(0, 0)),
+            ("textDocument/formatting", &params),
+        ]);
+        let edits = out[1].get("result").unwrap().as_array().unwrap();
+        assert_eq!(
+            edits[0].get("range").unwrap().to_string(),
+            "{\"start\":{\"line\":0,\"character\":0},\"end\":{\"line\":1,\"character\":6}}"
+        );
+        let formatted = edits[0].get("newText").unwrap().as_str().unwrap();
+        assert_eq!(
+            formatted,
+            format::format(
+                &Parser::parse_comments("a\n[-[.]]", Dialect::default())
+                    .unwrap()
+                    .0,
+                &Style {
+                    indent: 4,
+                    ..Default::default()
+                }
+            )
+        );
+        assert_eq!(
+            out[3].get("error").unwrap().get("code"),
+            Some(&Json::Number(INVALID_PARAMS.into()))
+        );
+    }
+}
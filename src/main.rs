@@ -1,89 +1,857 @@
-mod ast;
-mod c_emitter;
-mod eval;
-mod ir;
-mod optimize;
-mod parser;
-mod riscv_emitter;
-mod test;
-mod x86_emitter;
-
+use bfc::ast::ASTProgram;
+use bfc::diagnostics::{Record, Severity};
+use bfc::{
+    bounds, check, debug, driver, eval, format, ir, ir_parser, lint, lsp, optimize, parser,
+    preprocess, profile, stats, syntax, trace, Arch,
+};
 use clap::{Parser, ValueEnum};
-use std::io::Read;
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
 #[derive(Debug, Clone, ValueEnum)]
-enum Arch {
-    #[value(name = "x86_64")]
-    X86_64,
-    RiscV,
-    C,
+enum InputFormat {
+    Bf,
+    Ir,
 }
 
-#[derive(clap::Parser)]
-struct Args {
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+enum SurfaceSyntax {
+    Bf,
+    Ook,
+    Blub,
+    /// The tokens given by --token-map
+    Tokens,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+enum Ext {
+    /// `#` prints the 16 cells around the pointer to stderr
+    Hash,
+    /// `!` ends the program, and the rest of the source is its input
+    Bang,
+    /// A count may follow a command, as in `+5`, and `(...)*n` repeats a group
+    Rle,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+enum Warnings {
+    /// Warnings are errors
+    Error,
+    /// Warn about tape accesses that may be out of bounds in compiled code
+    Bounds,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+enum ErrorFormat {
+    Human,
+    /// A JSON object per line, with file, severity, code, message, line,
+    /// column and span
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+// Options shared by the default mode and every subcommand
+#[derive(clap::Args)]
+struct CompileArgs {
     #[arg(long)]
     nostdlib: bool,
+    /// Check the IR after each optimization pass. Textual IR input is always
+    /// checked.
     #[arg(long)]
-    explore: bool,
-    #[arg(long)]
-    eval: bool,
+    verify_ir: bool,
     #[arg(long, value_enum, default_value = "x86_64")]
     arch: Arch,
+    #[arg(long, value_enum, default_value = "bf")]
+    input_format: InputFormat,
+    /// Spelling of the commands of brainfuck input
+    #[arg(long, value_enum, default_value = "bf")]
+    syntax: SurfaceSyntax,
+    /// File with a line per command for --syntax tokens: the command,
+    /// whitespace and its token
+    #[arg(long, required_if_eq("syntax", "tokens"))]
+    token_map: Option<PathBuf>,
+    /// Dialect extension to enable, may be repeated. Only for --syntax bf.
+    #[arg(long, value_enum)]
+    ext: Vec<Ext>,
+    /// Expand macros, includes and named cells first. Only for --syntax bf.
+    #[arg(long)]
+    preprocess: bool,
+
+    /// `-Wbounds` warns about tape accesses that may be out of bounds, and
+    /// `-Werror` fails the compilation on warnings
+    #[arg(short = 'W', value_enum)]
+    warnings: Vec<Warnings>,
+    /// How parse errors, lints and runtime errors are written to stderr
+    #[arg(long, value_enum, default_value = "human")]
+    error_format: ErrorFormat,
 
-    #[arg(short = 'O', default_value = "1")]
-    opt_level: i32,
+    /// Optimization level, 1 by default and 0 for `debug`
+    #[arg(short = 'O')]
+    opt_level: Option<i32>,
 
     #[arg(short, long, default_value = "30000")]
     mem_size: usize,
 
-    path: Option<std::path::PathBuf>,
+    path: Option<PathBuf>,
 }
 
-fn main() -> ExitCode {
-    let args = Args::parse();
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Compile the program into an executable
+    Build {
+        #[command(flatten)]
+        compile: CompileArgs,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Compile the program and run it
+    Run {
+        #[command(flatten)]
+        compile: CompileArgs,
+    },
+    /// Run the program under an interactive debugger, reading commands from
+    /// stdin. At -O1 and above it steps through the optimized IR, and only
+    /// loops have source positions.
+    Debug {
+        #[command(flatten)]
+        compile: CompileArgs,
+        /// Input for the program, which otherwise sees EOF
+        #[arg(long, conflicts_with = "input_string")]
+        input: Option<PathBuf>,
+        #[arg(long)]
+        input_string: Option<String>,
+    },
+    /// Pretty-print the program, indenting loop bodies
+    Fmt {
+        #[command(flatten)]
+        compile: CompileArgs,
+        /// The widest line, which comments may exceed
+        #[arg(long, default_value = "80")]
+        width: usize,
+        /// Spaces per level of loops
+        #[arg(long, default_value = "2")]
+        indent: usize,
+        #[arg(long)]
+        strip_comments: bool,
+    },
+    /// Print the program without comments or `+-` and `<>` pairs
+    Minify {
+        #[command(flatten)]
+        compile: CompileArgs,
+    },
+    /// Run a language server for editors, speaking LSP on stdin and stdout
+    Lsp,
+}
 
-    if args.eval && args.path.is_none() {
-        eprintln!("Error: cannot eval when reading program from stdin");
-        return ExitCode::from(2);
-    }
+#[derive(clap::Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    compile: CompileArgs,
+
+    #[arg(long)]
+    explore: bool,
+    #[arg(long)]
+    eval: bool,
+    /// Evaluate before and after each optimization pass, and compare the
+    /// output, the input consumed and the final tape
+    #[arg(long)]
+    check_opt: bool,
+    /// Evaluate, and report executed instructions and the hottest loops
+    /// before and after optimization
+    #[arg(long)]
+    profile: bool,
+    /// Print the sizes of the program as parsed, after each optimization pass
+    /// and as emitted by each backend to stderr, as text or `--stats=json`
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    stats: Option<StatsFormat>,
+    /// Evaluate, logging each step to stderr
+    #[arg(long)]
+    trace: bool,
+    /// The first step --trace logs
+    #[arg(long, default_value = "0")]
+    trace_from: u64,
+    /// How many steps --trace logs
+    #[arg(long)]
+    trace_count: Option<u64>,
+    /// Evaluate, and print the final tape to stderr
+    #[arg(long)]
+    dump_tape: bool,
+    /// Step budget for --eval (unlimited by default) and --check-opt
+    #[arg(long)]
+    max_steps: Option<u64>,
+    /// Wall-clock limit for --eval, in seconds
+    #[arg(long, value_parser = parse_timeout)]
+    timeout: Option<std::time::Duration>,
+
+    /// Input for --eval and --check-opt, instead of stdin
+    #[arg(long, conflicts_with = "input_string")]
+    input: Option<PathBuf>,
+    #[arg(long)]
+    input_string: Option<String>,
+    /// Ends a program read from stdin, the rest of stdin is its input
+    #[arg(long, default_value = "!")]
+    separator: char,
+}
+
+fn parse_timeout(arg: &str) -> Result<std::time::Duration, String> {
+    let secs: f64 = arg.parse().map_err(|e| format!("{}", e))?;
+    std::time::Duration::try_from_secs_f64(secs).map_err(|e| format!("{}", e))
+}
 
-    let code = if let Some(ref path) = args.path {
+fn read_source(args: &CompileArgs) -> String {
+    if let Some(ref path) = args.path {
         std::fs::read_to_string(path).unwrap()
     } else {
         let mut code = String::new();
         std::io::stdin().read_to_string(&mut code).unwrap();
         code
+    }
+}
+
+// Reads the file given with --input
+fn read_input(path: &std::path::Path) -> Result<Vec<u8>, ExitCode> {
+    std::fs::read(path).map_err(|e| {
+        eprintln!("Error: {}: {}", path.display(), e);
+        ExitCode::from(2)
+    })
+}
+
+// Loads a program to be compiled, where input after a `!` has no use
+fn load(args: &CompileArgs) -> Result<ir::IRProgram, ExitCode> {
+    let code = read_source(args);
+    warn_ignored_input(args, &code);
+    let prog = parse(args, &code)?;
+    lint(args, &code, &prog)?;
+    Ok(prog)
+}
+
+// Reports lints on the unoptimized program, which fail under -Werror
+fn lint(args: &CompileArgs, code: &str, prog: &ir::IRProgram) -> Result<(), ExitCode> {
+    let werror = args.warnings.contains(&Warnings::Error);
+    let source = has_positions(args).then(|| source(args, code));
+    let diagnostics = lint::lint(prog, source);
+    let severity = if werror {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    for d in &diagnostics {
+        let mut record = self::record(args, severity, d.lint.code(), d.lint.message());
+        if let Some(span) = d.span {
+            record = record.at(span, code);
+        }
+        let human = format!("{}: {}", if werror { "Error" } else { "Warning" }, d);
+        report(args, record, human);
+    }
+    if werror && !diagnostics.is_empty() {
+        return Err(ExitCode::from(2));
+    }
+    Ok(())
+}
+
+fn preprocess<'a>(args: &CompileArgs, code: &'a str) -> Result<Cow<'a, str>, ExitCode> {
+    if !args.preprocess {
+        return Ok(Cow::Borrowed(code));
+    }
+    let path = args.path.as_deref().unwrap_or(Path::new("<stdin>"));
+    match preprocess::preprocess(path, code, |p| std::fs::read_to_string(p)) {
+        Ok(code) => Ok(Cow::Owned(code)),
+        Err(e) => {
+            let record = Record::new(
+                &e.loc.file.display().to_string(),
+                Severity::Error,
+                "preprocess",
+                e.kind.to_string(),
+            );
+            report(args, record.at_line(e.loc.line), format!("Error: {}", e));
+            Err(ExitCode::from(2))
+        }
+    }
+}
+
+// The program's file, as diagnostics name it
+fn file_name(args: &CompileArgs) -> String {
+    match args.path {
+        Some(ref path) => path.display().to_string(),
+        None => "<stdin>".to_string(),
+    }
+}
+
+fn record(
+    args: &CompileArgs,
+    severity: Severity,
+    code: &'static str,
+    message: impl Display,
+) -> Record {
+    Record::new(&file_name(args), severity, code, message.to_string())
+}
+
+// Writes a diagnostic to stderr, as `human` unless --error-format says
+// otherwise
+fn report(args: &CompileArgs, record: Record, human: impl Display) {
+    match args.error_format {
+        ErrorFormat::Human => eprintln!("{}", human),
+        ErrorFormat::Json => eprintln!("{}", record.to_json()),
+    }
+}
+
+// Whether the input is brainfuck as written
+fn is_brainfuck(args: &CompileArgs) -> bool {
+    matches!(args.input_format, InputFormat::Bf) && args.syntax == SurfaceSyntax::Bf
+}
+
+// Whether the unoptimized program has a node per command of the source, which
+// RLE BF's counts and groups, and preprocessing, break
+fn has_positions(args: &CompileArgs) -> bool {
+    is_brainfuck(args) && !args.ext.contains(&Ext::Rle) && !args.preprocess
+}
+
+fn syntax(args: &CompileArgs) -> Result<syntax::Syntax, ExitCode> {
+    if args.syntax != SurfaceSyntax::Bf && !args.ext.is_empty() {
+        eprintln!("Error: --ext only applies to --syntax bf");
+        return Err(ExitCode::from(2));
+    }
+    if args.syntax != SurfaceSyntax::Bf && args.preprocess {
+        eprintln!("Error: --preprocess only applies to --syntax bf");
+        return Err(ExitCode::from(2));
+    }
+    Ok(match args.syntax {
+        SurfaceSyntax::Bf => syntax::Syntax::Brainfuck,
+        SurfaceSyntax::Ook => syntax::Syntax::Ook,
+        SurfaceSyntax::Blub => syntax::Syntax::Blub,
+        SurfaceSyntax::Tokens => {
+            // Required by clap with --syntax tokens
+            let path = args.token_map.as_ref().unwrap();
+            let config = std::fs::read_to_string(path).map_err(|e| {
+                eprintln!("Error: {}: {}", path.display(), e);
+                ExitCode::from(2)
+            })?;
+            match syntax::TokenMap::parse(&config) {
+                Ok(map) => syntax::Syntax::Tokens(map),
+                Err(e) => {
+                    eprintln!("Failed to parse token map: {:?}", e);
+                    return Err(ExitCode::from(2));
+                }
+            }
+        }
+    })
+}
+
+fn dialect(args: &CompileArgs) -> parser::Dialect {
+    parser::Dialect {
+        dump: args.ext.contains(&Ext::Hash),
+        bang: args.ext.contains(&Ext::Bang),
+        rle: args.ext.contains(&Ext::Rle),
+    }
+}
+
+// The brainfuck source without any input after a `!`
+fn source<'a>(args: &CompileArgs, code: &'a str) -> &'a str {
+    dialect(args).split(code).0
+}
+
+// The input after a `!` in brainfuck source
+fn bang_input(args: &CompileArgs, code: &str) -> Option<Vec<u8>> {
+    let input = dialect(args).split(code).1;
+    input
+        .filter(|_| is_brainfuck(args))
+        .map(|i| i.as_bytes().to_vec())
+}
+
+fn warn_ignored_input(args: &CompileArgs, code: &str) {
+    if bang_input(args, code).is_some() {
+        eprintln!("Warning: ignoring the input after `!`, which is only used when evaluating");
+    }
+}
+
+// Under -Wbounds, warns about accesses that may be off the tape of a compiled
+// program, which aren't checked at run time. These fail under -Werror.
+fn warn_bounds(args: &CompileArgs, prog: &ir::IRProgram) -> Result<(), ExitCode> {
+    if !args.warnings.contains(&Warnings::Bounds)
+        || matches!(args.arch, Arch::Ir | Arch::Bf | Arch::Decompile)
+    {
+        return Ok(());
+    }
+    let werror = args.warnings.contains(&Warnings::Error);
+    let severity = if werror {
+        Severity::Error
+    } else {
+        Severity::Warning
     };
+    let warnings = bounds::analyze(prog).warnings(args.mem_size);
+    for warning in &warnings {
+        let record = record(args, severity, "tape-bounds", warning);
+        let human = format!("{}: {}", if werror { "Error" } else { "Warning" }, warning);
+        report(args, record, human);
+    }
+    if werror && !warnings.is_empty() {
+        return Err(ExitCode::from(2));
+    }
+    Ok(())
+}
 
-    let ast_prog = match parser::Parser::parse(&code) {
-        Ok(p) => p,
+// Reads stdin up to `sep`, which is consumed but not returned, or EOF
+fn read_stdin(sep: Option<u8>) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut stdin = std::io::stdin().lock();
+    match sep {
+        Some(sep) => {
+            stdin.read_until(sep, &mut ret).unwrap();
+            if ret.last() == Some(&sep) {
+                ret.pop();
+            }
+        }
+        None => {
+            stdin.read_to_end(&mut ret).unwrap();
+        }
+    }
+    ret
+}
+
+// Parses brainfuck input, keeping the comments of --syntax bf if asked to
+fn parse_ast(args: &CompileArgs, code: &str, comments: bool) -> Result<ASTProgram, ExitCode> {
+    let syntax = syntax(args)?;
+    let res = match syntax {
+        syntax::Syntax::Brainfuck => {
+            let code = preprocess(args, source(args, code))?;
+            if comments {
+                parser::Parser::parse_comments(&code, dialect(args))
+            } else {
+                parser::Parser::parse_dialect(&code, dialect(args))
+            }
+        }
+        ref syntax => parser::Parser::parse_syntax(code, syntax),
+    };
+    res.map_err(|e| {
+        let mut record = record(args, Severity::Error, e.code(), &e);
+        match e {
+            parser::Error::UnknownToken(line, _) | parser::Error::UnpairedToken(line) => {
+                record = record.at_line(line)
+            }
+            // Spans in the preprocessor's output aren't in the file
+            _ if matches!(syntax, syntax::Syntax::Brainfuck) && !args.preprocess => {
+                let source = source(args, code);
+                if let Some(span) = parser::Parser::error_span(source, dialect(args)) {
+                    record = record.at(span, source);
+                }
+            }
+            _ => {}
+        }
+        report(args, record, format!("Failed to parse program: {:?}", e));
+        ExitCode::from(2)
+    })
+}
+
+fn parse(args: &CompileArgs, code: &str) -> Result<ir::IRProgram, ExitCode> {
+    match args.input_format {
+        InputFormat::Bf => Ok(ir::IRProgram::from_ast_program(&parse_ast(
+            args, code, false,
+        )?)),
+        InputFormat::Ir => match ir_parser::Parser::parse(code) {
+            // Textual IR may not hold the invariants the passes and backends
+            // rely on
+            Ok(p) => match ir::verify(&p) {
+                Ok(()) => Ok(p),
+                Err(e) => {
+                    let record = record(args, Severity::Error, "verify", &e);
+                    report(args, record, format!("IR verification failed: {}", e));
+                    Err(ExitCode::from(2))
+                }
+            },
+            Err(e) => {
+                let mut record = record(args, Severity::Error, e.code(), &e);
+                if let Some(line) = e.line() {
+                    record = record.at_line(line);
+                }
+                report(args, record, format!("Failed to parse IR: {:?}", e));
+                Err(ExitCode::from(2))
+            }
+        },
+    }
+}
+
+fn optimize(args: &CompileArgs, ir_prog: ir::IRProgram) -> Result<ir::IRProgram, ExitCode> {
+    if args.opt_level == Some(0) {
+        Ok(ir_prog)
+    } else {
+        // Debug builds always verify between passes, and a failure is
+        // reported rather than a panic
+        let verify = args.verify_ir || cfg!(debug_assertions);
+        optimize::optimize_checked(&ir_prog, verify).map_err(|e| {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        })
+    }
+}
+
+fn build(args: &CompileArgs, output: &std::path::Path) -> Result<ExitCode, ExitCode> {
+    let ir_prog = optimize(args, load(args)?)?;
+    warn_bounds(args, &ir_prog)?;
+    if let Err(e) = driver::build(&ir_prog, &args.arch, args.nostdlib, args.mem_size, output) {
+        eprintln!("Error: {}", e);
+        return Err(ExitCode::from(1));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run(args: &CompileArgs) -> Result<ExitCode, ExitCode> {
+    if args.path.is_none() {
+        eprintln!("Error: cannot run when reading program from stdin");
+        return Err(ExitCode::from(2));
+    }
+    let ir_prog = optimize(args, load(args)?)?;
+    warn_bounds(args, &ir_prog)?;
+    let status = match driver::run(&ir_prog, &args.arch, args.nostdlib, args.mem_size) {
+        Ok(status) => status,
         Err(e) => {
-            eprintln!("Failed to parse program: {:?}", e);
-            return ExitCode::from(2);
+            eprintln!("Error: {}", e);
+            return Err(ExitCode::from(1));
         }
     };
+    // Report death by signal the way shells do
+    let code = status.code().unwrap_or_else(|| {
+        use std::os::unix::process::ExitStatusExt;
+        128 + status.signal().unwrap_or(0)
+    });
+    Ok(ExitCode::from(code as u8))
+}
+
+fn debug(
+    compile: &mut CompileArgs,
+    input: Option<PathBuf>,
+    input_string: Option<String>,
+) -> Result<ExitCode, ExitCode> {
+    if compile.path.is_none() {
+        eprintln!("Error: cannot debug when reading program from stdin");
+        return Err(ExitCode::from(2));
+    }
+    let opt_level = *compile.opt_level.get_or_insert(0);
+    let code = read_source(compile);
+    let unoptimized = parse(compile, &code)?;
+    let ir_prog = optimize(compile, ir::IRProgram(unoptimized.0.clone()))?;
+    let source = has_positions(compile)
+        .then(|| debug::SourceMap::new(source(compile, &code), dialect(compile)));
 
-    let ir_prog = ir::IRProgram::from_ast_program(&ast_prog);
+    let input = match input {
+        Some(path) => read_input(&path)?,
+        None => input_string
+            .map(String::into_bytes)
+            .or_else(|| bang_input(compile, &code))
+            .unwrap_or_default(),
+    };
+    let mut debugger = debug::Debugger::new(
+        &ir_prog,
+        source,
+        (opt_level != 0).then_some(&unoptimized),
+        std::io::stdin().lock(),
+        std::io::stderr(),
+    )
+    .map_err(|e| {
+        eprintln!("Error: {}", e);
+        ExitCode::from(2)
+    })?;
+    let limits = eval::Limits {
+        tape_size: Some(compile.mem_size),
+        ..Default::default()
+    };
+    let res = eval::eval_with_hook(
+        &ir_prog,
+        &mut eval::StreamIO::new(&input[..], std::io::stdout()),
+        &limits,
+        &mut debugger,
+    );
+    finish(compile, res, false)
+}
 
-    let ir_prog = if args.opt_level == 0 {
-        ir_prog
+// Prints the program written out by `write`, in the syntax it was read in
+fn rewrite(
+    args: &CompileArgs,
+    write: impl Fn(&ASTProgram) -> String,
+) -> Result<ExitCode, ExitCode> {
+    if !matches!(args.input_format, InputFormat::Bf) {
+        eprintln!("Error: only brainfuck input can be rewritten");
+        return Err(ExitCode::from(2));
+    }
+    if args.syntax != SurfaceSyntax::Bf {
+        eprintln!("Error: only --syntax bf can be rewritten");
+        return Err(ExitCode::from(2));
+    }
+    let code = read_source(args);
+    let mut out = write(&parse_ast(args, &code, true)?);
+    // Keep the program's input, which isn't part of the AST
+    if let Some(input) = dialect(args).split(&code).1 {
+        out.push('!');
+        out.push_str(input);
+    }
+    print!("{}", out);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let mut args = Args::parse();
+
+    let ret = match args.command.take() {
+        Some(Command::Build { compile, output }) => build(&compile, &output),
+        Some(Command::Run { compile }) => run(&compile),
+        Some(Command::Debug {
+            mut compile,
+            input,
+            input_string,
+        }) => debug(&mut compile, input, input_string),
+        Some(Command::Fmt {
+            compile,
+            width,
+            indent,
+            strip_comments,
+        }) => {
+            let style = format::Style {
+                width,
+                indent,
+                comments: !strip_comments,
+            };
+            rewrite(&compile, |ast| format::format(&ast.0, &style))
+        }
+        Some(Command::Minify { compile }) => {
+            let rle = compile.ext.contains(&Ext::Rle);
+            rewrite(&compile, |ast| format::minify(&ast.0, rle))
+        }
+        Some(Command::Lsp) => match lsp::serve(std::io::stdin().lock(), std::io::stdout().lock()) {
+            Ok(true) => Ok(ExitCode::SUCCESS),
+            Ok(false) => Err(ExitCode::from(1)),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                Err(ExitCode::from(1))
+            }
+        },
+        None => compile(&args),
+    };
+    ret.unwrap_or_else(|code| code)
+}
+
+// Reports how an evaluation ended
+fn finish(
+    args: &CompileArgs,
+    res: std::io::Result<eval::EvalResult>,
+    dump_tape: bool,
+) -> Result<ExitCode, ExitCode> {
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let record = record(args, Severity::Error, "io-error", &e);
+            report(args, record, format!("Error: {}", e));
+            return Err(ExitCode::from(1));
+        }
+    };
+    if dump_tape {
+        let _ = res.state.dump(&mut std::io::stderr().lock());
+    }
+    let (code, message) = match res.status {
+        eval::Status::Completed | eval::Status::Stopped => return Ok(ExitCode::SUCCESS),
+        eval::Status::OutOfSteps => (
+            "out-of-steps",
+            format!("out of steps after {} steps", res.steps),
+        ),
+        eval::Status::Timeout => ("timeout", format!("timed out after {} steps", res.steps)),
+        eval::Status::TapeError(cell) => (
+            "tape-error",
+            format!(
+                "access to cell {} outside the tape after {} steps",
+                cell, res.steps
+            ),
+        ),
+    };
+    let human = format!("Error: {}", message);
+    report(args, record(args, Severity::Error, code, message), human);
+    Err(ExitCode::from(1))
+}
+
+fn eval(
+    args: &Args,
+    ir_prog: &ir::IRProgram,
+    io: &mut impl eval::IO,
+    limits: &eval::Limits,
+) -> std::io::Result<eval::EvalResult> {
+    if args.trace {
+        let stderr = std::io::BufWriter::new(std::io::stderr().lock());
+        let mut tracer = trace::Tracer::new(stderr, args.trace_from, args.trace_count);
+        eval::eval_with_hook(ir_prog, io, limits, &mut tracer)
+    } else {
+        eval::eval_with_io(ir_prog, io, limits)
+    }
+}
+
+// Evaluates the program before and, unless -O0, after optimization, with the
+// same input, and reports both profiles on stderr. Only the first run's
+// output is shown.
+fn profile(
+    args: &Args,
+    code: &str,
+    ir_prog: ir::IRProgram,
+    input: &[u8],
+    limits: &eval::Limits,
+) -> Result<ExitCode, ExitCode> {
+    let source = has_positions(&args.compile).then(|| self::source(&args.compile, code));
+    let mut profiler = profile::Profiler::default();
+    let stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    let res = eval::eval_with_hook(
+        &ir_prog,
+        &mut eval::StreamIO::new(input, stdout),
+        limits,
+        &mut profiler,
+    );
+    eprintln!("before optimization: {}", profiler.report(&ir_prog, source));
+    let ret = finish(&args.compile, res, args.dump_tape);
+    if args.compile.opt_level == Some(0) {
+        return ret;
+    }
+
+    let opt_prog = optimize(&args.compile, ir::IRProgram(ir_prog.0.clone()))?;
+    let mut profiler = profile::Profiler::default();
+    let res = eval::eval_with_hook(
+        &opt_prog,
+        &mut eval::StreamIO::new(input, std::io::sink()),
+        limits,
+        &mut profiler,
+    );
+    eprintln!(
+        "after optimization: {}",
+        profiler.report_optimized(&opt_prog, &ir_prog, source)
+    );
+    ret.and(finish(&args.compile, res, false))
+}
+
+// Reports the statistics of compiling `ir_prog` on stderr
+fn report_stats(
+    args: &CompileArgs,
+    code: &str,
+    ir_prog: &ir::IRProgram,
+    format: &StatsFormat,
+) -> Result<(), ExitCode> {
+    let ast = match args.input_format {
+        InputFormat::Bf => Some(parse_ast(args, code, false)?),
+        InputFormat::Ir => None,
+    };
+    let optimize = args.opt_level != Some(0);
+    let stats = stats::collect(
+        ast.as_ref(),
+        ir_prog,
+        optimize,
+        args.nostdlib,
+        args.mem_size,
+    );
+    match format {
+        StatsFormat::Text => eprint!("{}", stats),
+        StatsFormat::Json => eprintln!("{}", stats.to_json()),
+    }
+    Ok(())
+}
+
+fn compile(args: &Args) -> Result<ExitCode, ExitCode> {
+    let input = if let Some(ref path) = args.input {
+        Some(read_input(path)?)
+    } else {
+        args.input_string.as_ref().map(|s| s.clone().into_bytes())
+    };
+
+    let evaluates = args.eval || args.trace || args.dump_tape || args.profile || args.check_opt;
+    let code = if evaluates && args.compile.path.is_none() && input.is_none() {
+        // Program and input share stdin
+        if !args.separator.is_ascii() {
+            eprintln!("Error: the separator must be an ASCII character");
+            return Err(ExitCode::from(2));
+        }
+        String::from_utf8_lossy(&read_stdin(Some(args.separator as u8))).into_owned()
+    } else {
+        read_source(&args.compile)
+    };
+    let ir_prog = parse(&args.compile, &code)?;
+    // After any explicit input, and before stdin
+    let input = if evaluates {
+        input.or_else(|| bang_input(&args.compile, &code))
     } else {
-        optimize::optimize(&ir_prog)
+        warn_ignored_input(&args.compile, &code);
+        lint(&args.compile, &code, &ir_prog)?;
+        input
+    };
+    if let Some(ref format) = args.stats {
+        report_stats(&args.compile, &code, &ir_prog, format)?;
+    }
+
+    if args.check_opt {
+        let input = input.unwrap_or_else(|| read_stdin(None));
+        let input: Vec<ir::Value> = input.into_iter().map(|b| b as ir::Value).collect();
+        return match check::check_optimization(
+            &ir_prog,
+            &input,
+            args.max_steps.unwrap_or(100_000_000),
+        ) {
+            Ok(()) => Ok(ExitCode::SUCCESS),
+            Err(d) => {
+                eprintln!("{}", d);
+                Err(ExitCode::from(1))
+            }
+        };
+    }
+
+    let limits = eval::Limits {
+        max_steps: args.max_steps,
+        timeout: args.timeout,
+        tape_size: Some(args.compile.mem_size),
     };
+    if args.profile {
+        let input = input.unwrap_or_else(|| read_stdin(None));
+        return profile(args, &code, ir_prog, &input, &limits);
+    }
+
+    let ir_prog = optimize(&args.compile, ir_prog)?;
 
-    if args.eval {
-        eval::eval(&ir_prog);
-        return ExitCode::SUCCESS;
+    if args.eval || args.trace || args.dump_tape {
+        let res = match input {
+            Some(input) => {
+                let stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                eval(
+                    args,
+                    &ir_prog,
+                    &mut eval::StreamIO::new(&input[..], stdout),
+                    &limits,
+                )
+            }
+            None => eval(args, &ir_prog, &mut eval::stdio(), &limits),
+        };
+        return finish(&args.compile, res, args.dump_tape);
     }
     if args.explore {
         println!("{:#?}", ir_prog);
-        return ExitCode::SUCCESS;
+        return Ok(ExitCode::SUCCESS);
     }
-    match args.arch {
-        Arch::X86_64 => x86_emitter::X86Emitter::emit(&ir_prog, args.nostdlib, args.mem_size),
-        Arch::RiscV => riscv_emitter::RiscVEmitter::emit(&ir_prog, args.nostdlib, args.mem_size),
-        Arch::C => c_emitter::CEmitter::emit(&ir_prog, args.nostdlib, args.mem_size),
+    warn_bounds(&args.compile, &ir_prog)?;
+    let stdout = std::io::stdout();
+    if let Err(e) = bfc::emit(
+        &ir_prog,
+        &args.compile.arch,
+        args.compile.nostdlib,
+        args.compile.mem_size,
+        &mut stdout.lock(),
+    ) {
+        eprintln!("Failed to write output: {}", e);
+        return Err(ExitCode::from(1));
     }
-    return ExitCode::SUCCESS;
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,8 +1,9 @@
 use crate::ir::{self, IRProgram, IR};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 
-fn compress_changes(irs: &Vec<IR>) -> Vec<IR> {
-    fn recur(irs: &Vec<IR>, preserve_change: bool) -> Vec<IR> {
+fn compress_changes(irs: &[IR]) -> Vec<IR> {
+    fn recur(irs: &[IR], preserve_change: bool) -> Vec<IR> {
         let mut ret = Vec::new();
 
         let mut last_change = None;
@@ -55,19 +56,25 @@ fn simplify_loop(ins: &IR) -> IR {
     };
 
     let mut ptr_change = 0;
-    let mut delta = 0;
+    let mut delta: ir::Value = 0;
+    // Adds to the counter are moved to the end of the iteration, which is
+    // only sound if nothing reads the counter after them.
+    let mut counter_added = false;
 
     let mut ret_inner = Vec::new();
 
     let mut simplifiable = true;
     for i in irs.iter() {
-        assert!(!matches!(i, IR::MovImm(..)));
         match i {
-            IR::Putch(..) => {
+            IR::Putch(put_off) => {
+                if counter_added && ptr_change + put_off == 0 {
+                    simplifiable = false;
+                    break;
+                }
                 ret_inner.push(i.clone());
             }
-            IR::Getch(..) => {
-                if ptr_change == 0 {
+            IR::Getch(get_off) => {
+                if ptr_change + get_off == 0 {
                     simplifiable = false;
                     break;
                 } else {
@@ -83,8 +90,9 @@ fn simplify_loop(ins: &IR) -> IR {
                 }
             }
             IR::Add(add_off, amt) => {
-                if ptr_change == *add_off {
-                    delta += amt;
+                if ptr_change + add_off == 0 {
+                    delta = delta.wrapping_add(*amt);
+                    counter_added = true;
                 } else {
                     ret_inner.push(i.clone());
                 }
@@ -93,19 +101,26 @@ fn simplify_loop(ins: &IR) -> IR {
                 ptr_change += amt;
                 ret_inner.push(i.clone());
             }
-            IR::Loop(_) => {
+            // A dump shows the counter, which is only updated at the end
+            IR::Loop(_) | IR::Dump => {
                 simplifiable = false;
                 break;
             }
             IR::SimpleLoop(d, inner) => {
-                if ptr_change == 0 {
+                if ptr_change == 0
+                    || ir::may_write(inner, -ptr_change)
+                    || (counter_added && ir::may_access(inner, -ptr_change))
+                {
                     simplifiable = false;
                     break;
                 }
                 ret_inner.push(IR::SimpleLoop(*d, inner.clone()));
             }
             IR::AddMul(dst_off, amt) => {
-                assert_ne!(*dst_off, -ptr_change); // Too lazy to think through implications
+                if ptr_change + dst_off == 0 || (counter_added && ptr_change == 0) {
+                    simplifiable = false;
+                    break;
+                }
                 ret_inner.push(IR::AddMul(*dst_off, *amt));
             }
         }
@@ -118,7 +133,7 @@ fn simplify_loop(ins: &IR) -> IR {
     }
 }
 
-fn compress_muls(irs: &Vec<IR>) -> Vec<IR> {
+fn compress_muls(irs: &[IR]) -> Vec<IR> {
     let mut ret = Vec::new();
     'outer: for ir in irs {
         if let IR::SimpleLoop(delta, inner) = ir {
@@ -127,7 +142,7 @@ fn compress_muls(irs: &Vec<IR>) -> Vec<IR> {
                 continue;
             }
 
-            let mut changes = HashMap::new(); //<ir::Offset, ir::Value>
+            let mut changes = BTreeMap::new(); //<ir::Offset, ir::Value>
             let mut off = 0;
             for iir in inner {
                 match iir {
@@ -135,7 +150,8 @@ fn compress_muls(irs: &Vec<IR>) -> Vec<IR> {
                         off += amt;
                     }
                     IR::Add(add_off, amt) => {
-                        *changes.entry(off + add_off).or_insert(0) += amt;
+                        let change = changes.entry(off + add_off).or_insert(0i8);
+                        *change = change.wrapping_add(*amt);
                     }
                     _ => {
                         ret.push(IR::SimpleLoop(*delta, compress_muls(inner)));
@@ -158,14 +174,14 @@ fn compress_muls(irs: &Vec<IR>) -> Vec<IR> {
     ret
 }
 
-fn collapse_consts(irs: &Vec<IR>) -> Vec<IR> {
+fn collapse_consts(irs: &[IR]) -> Vec<IR> {
     #[derive(Clone, Debug)]
     enum Value {
         Const(i8),
         Add(i8),
     }
 
-    fn recur(irs: &Vec<IR>, state: &mut HashMap<ir::Offset, Value>, idx: ir::Offset) -> Vec<IR> {
+    fn recur(irs: &[IR], state: &mut BTreeMap<ir::Offset, Value>, idx: ir::Offset) -> Vec<IR> {
         let mut knowable = true;
         let mut ret = Vec::new();
         let mut off = 0;
@@ -186,10 +202,10 @@ fn collapse_consts(irs: &Vec<IR>) -> Vec<IR> {
                         .unwrap_or(&Value::Const(0));
                     match init {
                         Value::Add(cur) => {
-                            state.insert(idx + off + add_off, Value::Add(amt + cur));
+                            state.insert(idx + off + add_off, Value::Add(amt.wrapping_add(*cur)));
                         }
                         Value::Const(cur) => {
-                            state.insert(idx + off + add_off, Value::Const(amt + cur));
+                            state.insert(idx + off + add_off, Value::Const(amt.wrapping_add(*cur)));
                         }
                     }
                 }
@@ -200,7 +216,10 @@ fn collapse_consts(irs: &Vec<IR>) -> Vec<IR> {
                     let multiplier = state.get(&(idx + off)).unwrap_or(&Value::Const(0));
 
                     if let (Value::Const(i), Value::Const(m)) = (init, multiplier) {
-                        state.insert(idx + off + *dst_off, Value::Const(i + m * amt));
+                        state.insert(
+                            idx + off + *dst_off,
+                            Value::Const(i.wrapping_add(m.wrapping_mul(*amt))),
+                        );
                         continue;
                     }
 
@@ -266,13 +285,12 @@ fn collapse_consts(irs: &Vec<IR>) -> Vec<IR> {
                 },
                 IR::Putch(put_off) => {
                     match state.get(&(idx + off + put_off)) {
-                        Some(Value::Add(amt)) => {
-                            if *amt != 0 {
-                                ret.push(IR::Add(*put_off, *amt))
-                            }
+                        Some(Value::Add(amt)) if *amt != 0 => {
+                            ret.push(IR::Add(*put_off, *amt));
+                            state.insert(idx + off + put_off, Value::Add(0));
                         }
                         Some(Value::Const(amt)) => ret.push(IR::MovImm(*put_off, *amt)),
-                        None => {}
+                        _ => {}
                     }
                     ret.push(i.clone());
                 }
@@ -284,16 +302,32 @@ fn collapse_consts(irs: &Vec<IR>) -> Vec<IR> {
                     state.insert(idx + off + dst_off, Value::Const(*val));
                     ret.push(i.clone());
                 }
+                IR::Dump => {
+                    // Every cell may be shown, so all of them have to be
+                    // up to date, but they stay known
+                    for (loc_off, v) in &mut *state {
+                        match v {
+                            Value::Add(amt) => {
+                                if *amt != 0 {
+                                    ret.push(IR::Add(loc_off - off - idx, *amt));
+                                    *v = Value::Add(0);
+                                }
+                            }
+                            Value::Const(amt) => ret.push(IR::MovImm(loc_off - off - idx, *amt)),
+                        }
+                    }
+                    ret.push(i.clone());
+                }
             }
         }
         ret
     }
 
-    recur(irs, &mut HashMap::new(), 0)
+    recur(irs, &mut BTreeMap::new(), 0)
 }
 
-fn remove_unread_stores(irs: &Vec<IR>) -> Vec<IR> {
-    fn flush_writes(writes: &mut HashMap<ir::Offset, ir::Value>, cur: ir::Offset) -> Vec<IR> {
+fn remove_unread_stores(irs: &[IR]) -> Vec<IR> {
+    fn flush_writes(writes: &mut BTreeMap<ir::Offset, ir::Value>, cur: ir::Offset) -> Vec<IR> {
         let mut ret = Vec::new();
         for (glob_off, val) in writes.iter() {
             ret.push(IR::MovImm(glob_off - cur, *val));
@@ -301,10 +335,10 @@ fn remove_unread_stores(irs: &Vec<IR>) -> Vec<IR> {
         writes.clear();
         ret
     }
-    fn recur(irs: &Vec<IR>, idx: ir::Offset, flush: bool) -> Vec<IR> {
+    fn recur(irs: &[IR], idx: ir::Offset, flush: bool) -> Vec<IR> {
         let mut ret = Vec::new();
         let mut off = 0;
-        let mut writes = HashMap::new();
+        let mut writes = BTreeMap::new();
         let mut knowable = true;
         for ir in irs {
             if !knowable {
@@ -355,6 +389,10 @@ fn remove_unread_stores(irs: &Vec<IR>) -> Vec<IR> {
                 IR::MovImm(dst_off, amt) => {
                     writes.insert(idx + off + dst_off, *amt);
                 }
+                IR::Dump => {
+                    ret.extend(flush_writes(&mut writes, idx + off));
+                    ret.push(ir.clone());
+                }
             }
         }
         if flush {
@@ -365,20 +403,83 @@ fn remove_unread_stores(irs: &Vec<IR>) -> Vec<IR> {
     recur(irs, 0, false)
 }
 
+fn simplify_loops(irs: &[IR]) -> Vec<IR> {
+    irs.iter().map(simplify_loop).collect()
+}
+
+pub type Pass = fn(&[IR]) -> Vec<IR>;
+
+pub const PASSES: &[(&str, Pass)] = &[
+    ("compress_changes", compress_changes),
+    ("simplify_loops", simplify_loops),
+    ("compress_changes", compress_changes),
+    ("compress_muls", compress_muls),
+    ("collapse_consts", collapse_consts),
+    ("remove_unread_stores", remove_unread_stores),
+    ("compress_changes", compress_changes),
+];
+
+#[derive(Debug)]
+pub struct PassError {
+    pub pass: &'static str,
+    pub error: ir::VerifyError,
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "IR verification failed after {}: {}",
+            self.pass, self.error
+        )
+    }
+}
+
+// Runs every pass, checking `ir::verify` on the input and after each pass
+// when `verify` is set.
+pub fn optimize_checked(prog: &IRProgram, verify: bool) -> Result<IRProgram, PassError> {
+    let check = |pass, prog: &IRProgram| {
+        if verify {
+            ir::verify(prog).map_err(|error| PassError { pass, error })
+        } else {
+            Ok(())
+        }
+    };
+    check("input", prog)?;
+    let mut prog = IRProgram(prog.0.clone());
+    for (name, pass) in PASSES {
+        prog = IRProgram(pass(&prog.0));
+        check(name, &prog)?;
+    }
+    Ok(prog)
+}
+
 pub fn optimize(prog: &IRProgram) -> IRProgram {
-    let irs = &prog.0;
-    let irs = compress_changes(&irs);
-    let irs = irs.iter().map(simplify_loop).collect();
-    let irs = compress_changes(&irs);
-    let irs = compress_muls(&irs);
-    let irs = collapse_consts(&irs);
-    let irs = remove_unread_stores(&irs);
-    let irs = compress_changes(&irs);
-    IRProgram(irs)
+    match optimize_checked(prog, cfg!(debug_assertions)) {
+        Ok(prog) => prog,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// What a loop with `body` becomes when optimized, wherever it is. On its own
+// the loop would run on a blank tape, with any stores it leaves dead at the
+// end of the program. The optimizer makes no such assumptions about the body
+// of another loop, entered after a `getch`.
+pub fn optimize_loop(body: &[IR]) -> Vec<IR> {
+    let prog = IRProgram(vec![IR::Getch(0), IR::Loop(vec![IR::Loop(body.to_vec())])]);
+    optimize(&prog)
+        .0
+        .into_iter()
+        .find_map(|ir| match ir {
+            IR::Loop(inner) => Some(inner),
+            _ => None,
+        })
+        .unwrap_or_default()
 }
 
 mod test {
     #![allow(dead_code)]
+    #![allow(clippy::useless_vec)]
     use super::*;
     fn lp(inner: Vec<IR>) -> IR {
         IR::Loop(inner)
@@ -404,6 +505,9 @@ mod test {
     fn mi(off: ir::Offset, imm: ir::Value) -> IR {
         IR::MovImm(off, imm)
     }
+    fn ir(text: &str) -> Vec<IR> {
+        crate::ir_parser::Parser::parse(text).unwrap().0
+    }
 
     #[test]
     fn test_compress_changes() {
@@ -435,6 +539,109 @@ mod test {
         );
     }
 
+    // An add at the offset that undoes the pointer change is to the counter,
+    // not one at the pointer change itself
+    #[test]
+    fn test_simplify_counter_offset() {
+        assert_eq!(
+            simplify_loop(&lp(vec![pc(2), sl(-1, vec![]), a(-2, -1), pc(-2)])),
+            sl(-1, vec![pc(2), sl(-1, vec![]), pc(-2)])
+        );
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, -1), pc(2), a(2, 1), pc(-2)])),
+            sl(-1, vec![pc(2), a(2, 1), pc(-2)])
+        );
+    }
+
+    // Textual IR can put a `MovImm` or an `AddMul` in a loop before
+    // `simplify_loops` runs. Either is fine in a simple loop unless it
+    // writes the counter, which no longer changes by a constant.
+    #[test]
+    fn test_simplify_mov_imm_add_mul() {
+        assert_eq!(
+            simplify_loop(&lp(vec![mi(1, 3), a(0, -1)])),
+            sl(-1, vec![mi(1, 3)])
+        );
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, -1), mi(0, 0)])),
+            lp(vec![a(0, -1), mi(0, 0)])
+        );
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, -1), pc(1), am(1, 2), pc(-1)])),
+            sl(-1, vec![pc(1), am(1, 2), pc(-1)])
+        );
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, -1), pc(1), am(-1, 2), pc(-1)])),
+            lp(vec![a(0, -1), pc(1), am(-1, 2), pc(-1)])
+        );
+    }
+
+    // Found by the fuzzer as `"+".repeat(128) + "."`: sums of values
+    // overflowed, which panics in debug builds, instead of wrapping
+    #[test]
+    fn test_wrapping_values() {
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, 127), a(0, 1)])),
+            sl(-128, vec![])
+        );
+        assert_eq!(
+            compress_muls(&vec![sl(-1, vec![a(1, 127), a(1, 1)])]),
+            vec![am(1, -128), mi(0, 0)]
+        );
+        assert_eq!(
+            collapse_consts(&vec![a(0, 127), a(0, 1), put(0)]),
+            vec![mi(0, -128), put(0)]
+        );
+        assert_eq!(
+            collapse_consts(&vec![a(0, 16), am(1, 16), put(1)]),
+            vec![mi(1, 0), put(1)]
+        );
+    }
+
+    // Found by the fuzzer as `,-..`: the add before the first `.` was
+    // applied again before the second
+    #[test]
+    fn test_collapse_consts_putch() {
+        assert_eq!(
+            collapse_consts(&vec![get(0), a(0, -1), put(0), put(0)]),
+            vec![get(0), a(0, -1), put(0), put(0)]
+        );
+        assert_eq!(
+            collapse_consts(&vec![get(0), a(0, 1), put(0), a(0, 1), put(0)]),
+            vec![get(0), a(0, 1), put(0), a(0, 1), put(0)]
+        );
+    }
+
+    // Found by the fuzzer as `,[+<<<-[.>>>[+<<<->>>]<<<]>>>]`: an inner
+    // simple loop that writes the outer counter was kept in a simple loop
+    #[test]
+    fn test_simplify_inner_writes_counter() {
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, -1), pc(3), sl(1, vec![a(-3, -1)]), pc(-3)])),
+            lp(vec![a(0, -1), pc(3), sl(1, vec![a(-3, -1)]), pc(-3)])
+        );
+        assert_eq!(
+            simplify_loop(&lp(vec![a(0, -1), pc(3), sl(1, vec![put(-3)]), pc(-3)])),
+            lp(vec![a(0, -1), pc(3), sl(1, vec![put(-3)]), pc(-3)])
+        );
+        assert_eq!(
+            simplify_loop(&lp(vec![pc(3), sl(1, vec![put(-3)]), pc(-3), a(0, -1)])),
+            sl(-1, vec![pc(3), sl(1, vec![put(-3)]), pc(-3)])
+        );
+    }
+
+    #[test]
+    fn test_compress_muls() {
+        assert_eq!(
+            compress_muls(&ir("simpleloop -1 {\n  add +1, 5\n}")),
+            ir("addmul +1, 5\nmovimm 0, 0")
+        );
+        assert_eq!(
+            compress_muls(&ir("simpleloop -1 {\n  putch +1\n}")),
+            ir("simpleloop -1 {\n  putch +1\n}")
+        );
+    }
+
     #[test]
     fn test_remove_unread_stores() {
         // assert_eq!(remove_unread_stores(&vec![get(1), mi(0, 1), am(1, 5), put(1)]), vec![]);
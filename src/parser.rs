@@ -1,56 +1,326 @@
 use crate::ast::{ASTProgram, AST};
+use crate::syntax::Syntax;
+use std::fmt;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     UnterminatedLoop,
     UnexpectedLoopTermination,
+    // In another `Syntax`, at a 1-based line
+    UnknownToken(usize, String),
+    UnpairedToken(usize),
+    // In RLE BF
+    UnterminatedGroup,
+    UnexpectedGroupTermination,
+    // Missing after `*`, or too large
+    BadCount,
+    // Counts that make more than `MAX_NODES` IR nodes, or move the pointer by
+    // more than an `Offset` holds in total
+    TooLarge,
+}
+
+// The most IR nodes an RLE BF program may lower to
+pub const MAX_NODES: u64 = 1 << 24;
+
+impl Error {
+    // A stable name for tools, see `diagnostics`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::UnterminatedLoop => "unterminated-loop",
+            Error::UnexpectedLoopTermination => "unexpected-loop-end",
+            Error::UnknownToken(..) => "unknown-token",
+            Error::UnpairedToken(_) => "unpaired-token",
+            Error::UnterminatedGroup => "unterminated-group",
+            Error::UnexpectedGroupTermination => "unexpected-group-end",
+            Error::BadCount => "bad-count",
+            Error::TooLarge => "too-large",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnterminatedLoop => write!(f, "`[` without a matching `]`"),
+            Error::UnexpectedLoopTermination => write!(f, "`]` without a matching `[`"),
+            Error::UnknownToken(_, token) => write!(f, "unknown token `{}`", token),
+            Error::UnpairedToken(_) => write!(f, "a word without the other of its pair"),
+            Error::UnterminatedGroup => write!(f, "`(` without a matching `)`"),
+            Error::UnexpectedGroupTermination => write!(f, "`)` without a matching `(`"),
+            Error::BadCount => write!(f, "missing or too large count"),
+            Error::TooLarge => write!(f, "the program is too large"),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// A range of characters in the source, `end` exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // 1-based line and column of `start`
+    pub fn line_col(&self, code: &str) -> (usize, usize) {
+        let mut line = 1;
+        let mut col = 1;
+        for c in code.chars().take(self.start) {
+            if c == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+        }
+        (line, col)
+    }
+}
+
+// Opt-in extensions to the language
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dialect {
+    // `#` dumps the tape around the pointer
+    pub dump: bool,
+    // `!` ends the program, and anything after it is the program's input
+    pub bang: bool,
+    // RLE BF: a count may follow a command, as in `+5` or `>12`, and
+    // `(...)*n` repeats a group
+    pub rle: bool,
+}
+
+impl Dialect {
+    // Splits off the input following a `!`
+    pub fn split<'a>(&self, code: &'a str) -> (&'a str, Option<&'a str>) {
+        match code.split_once('!') {
+            Some((code, input)) if self.bang => (code, Some(input)),
+            _ => (code, None),
+        }
+    }
+}
+
 pub struct Parser {
     code: Vec<char>,
     off: usize,
+    dialect: Dialect,
+    // Whether to keep comments as `AST::Comment`
+    comments: bool,
+    // Where the loops and groups being parsed start
+    opened: Vec<usize>,
+    // Where the error, if any, is
+    error_at: Option<usize>,
 }
 
 impl Parser {
     pub fn parse(code: &str) -> Result<ASTProgram> {
-        let mut p = Self {
+        Self::parse_dialect(code, Dialect::default())
+    }
+
+    // Programs in other syntaxes have no dialect extensions
+    pub fn parse_syntax(code: &str, syntax: &Syntax) -> Result<ASTProgram> {
+        Self::parse(&syntax.translate(code)?)
+    }
+
+    pub fn parse_dialect(code: &str, dialect: Dialect) -> Result<ASTProgram> {
+        Self::parse_all(code, dialect, false)
+    }
+
+    // Like `parse_dialect`, but the text between commands is kept as
+    // `AST::Comment`s
+    pub fn parse_comments(code: &str, dialect: Dialect) -> Result<ASTProgram> {
+        Self::parse_all(code, dialect, true)
+    }
+
+    // Where in `code` parsing it fails, if it does and the error is about a
+    // single character. The input after a `!` doesn't need to be split off.
+    pub fn error_span(code: &str, dialect: Dialect) -> Option<Span> {
+        let mut p = Self::new(code, dialect, false);
+        p.parse_inner(None).err()?;
+        p.error_at.map(|start| Span {
+            start,
+            end: start + 1,
+        })
+    }
+
+    fn new(code: &str, dialect: Dialect, comments: bool) -> Self {
+        Self {
             code: code.chars().collect(),
             off: 0,
-        };
-        Ok(ASTProgram(p.parse_inner(false)?))
+            dialect,
+            comments,
+            opened: Vec::new(),
+            error_at: None,
+        }
+    }
+
+    fn parse_all(code: &str, dialect: Dialect, comments: bool) -> Result<ASTProgram> {
+        let mut p = Self::new(code, dialect, comments);
+        let ret = p.parse_inner(None)?;
+        if dialect.rle {
+            let (nodes, moved) = Self::size(&ret);
+            if nodes > MAX_NODES || moved > i32::MAX as u64 {
+                return Err(Error::TooLarge);
+            }
+        }
+        Ok(ASTProgram(ret))
+    }
+
+    // The number of IR nodes `asts` lower to, and how far they move the
+    // pointer in total in either direction, both saturating
+    fn size(asts: &[AST]) -> (u64, u64) {
+        let (mut nodes, mut moved) = (0u64, 0u64);
+        for ast in asts {
+            let (n, m) = match ast {
+                AST::Loop(inner) => {
+                    let (n, m) = Self::size(inner);
+                    (n.saturating_add(1), m)
+                }
+                AST::PtrAdvance | AST::PtrRetreat => (1, 1),
+                AST::PtrMove(amt) => (1, amt.unsigned_abs() as u64),
+                AST::Repeat(inner, count) => {
+                    let (n, m) = Self::size(inner);
+                    let count = *count as u64;
+                    (n.saturating_mul(count), m.saturating_mul(count))
+                }
+                AST::Comment(_) => (0, 0),
+                _ => (1, 0),
+            };
+            nodes = nodes.saturating_add(n);
+            moved = moved.saturating_add(m);
+        }
+        (nodes, moved)
+    }
+
+    // The spans of the loops in a well-formed program, in the order they are
+    // opened. This is also the order of loops in `IRProgram::from_ast_program`.
+    // Input after a `!` must already be split off.
+    pub fn loop_spans(code: &str) -> Vec<Span> {
+        let mut ret = Vec::new();
+        let mut open = Vec::new();
+        for (i, c) in code.chars().enumerate() {
+            match c {
+                '[' => {
+                    open.push(ret.len());
+                    ret.push(Span { start: i, end: i });
+                }
+                ']' => {
+                    if let Some(idx) = open.pop() {
+                        ret[idx].end = i + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        ret
     }
 
-    fn parse_inner(&mut self, is_loop: bool) -> Result<Vec<AST>> {
+    // Parses up to `close`, which ends a loop or group
+    fn parse_inner(&mut self, close: Option<char>) -> Result<Vec<AST>> {
         let mut ret = Vec::new();
         while self.off < self.code.len() {
             let c = self.code[self.off];
             self.off += 1;
 
-            match c {
-                '>' => ret.push(AST::PtrAdvance),
-                '<' => ret.push(AST::PtrRetreat),
-                '+' => ret.push(AST::Incr),
-                '-' => ret.push(AST::Decr),
-                '.' => ret.push(AST::Putch),
-                ',' => ret.push(AST::Getch),
-                '[' => ret.push(AST::Loop(self.parse_inner(true)?)),
-                ']' => {
-                    return if !is_loop {
-                        Err(Error::UnexpectedLoopTermination)
+            let node = match c {
+                '>' => AST::PtrAdvance,
+                '<' => AST::PtrRetreat,
+                '+' => AST::Incr,
+                '-' => AST::Decr,
+                '.' => AST::Putch,
+                ',' => AST::Getch,
+                '[' => {
+                    self.opened.push(self.off - 1);
+                    let inner = self.parse_inner(Some(']'))?;
+                    self.opened.pop();
+                    AST::Loop(inner)
+                }
+                '#' if self.dialect.dump => AST::Dump,
+                '!' if self.dialect.bang => {
+                    self.off = self.code.len();
+                    continue;
+                }
+                '(' if self.dialect.rle => {
+                    self.opened.push(self.off - 1);
+                    let group = self.parse_inner(Some(')'))?;
+                    self.opened.pop();
+                    let count = if self.code.get(self.off) == Some(&'*') {
+                        self.off += 1;
+                        self.error_at = Some(self.off - 1);
+                        self.count()?.ok_or(Error::BadCount)?
                     } else {
-                        Ok(ret)
+                        1
+                    };
+                    ret.push(AST::Repeat(group, count as usize));
+                    continue;
+                }
+                ']' | ')' if close == Some(c) => return Ok(ret),
+                ']' => {
+                    self.error_at = Some(self.off - 1);
+                    return Err(Error::UnexpectedLoopTermination);
+                }
+                ')' if self.dialect.rle => {
+                    self.error_at = Some(self.off - 1);
+                    return Err(Error::UnexpectedGroupTermination);
+                }
+                _ => {
+                    if self.comments {
+                        match ret.last_mut() {
+                            Some(AST::Comment(text)) => text.push(c),
+                            _ => ret.push(AST::Comment(c.to_string())),
+                        }
                     }
+                    continue;
                 }
-                _ => {}
-            }
+            };
+            self.push(&mut ret, node)?;
         }
-        return if is_loop {
-            Err(Error::UnterminatedLoop)
+        self.error_at = self.opened.last().copied();
+        match close {
+            Some(']') => Err(Error::UnterminatedLoop),
+            Some(_) => Err(Error::UnterminatedGroup),
+            None => Ok(ret),
+        }
+    }
+
+    // Pushes `node`, repeated by the count following it in RLE BF
+    fn push(&mut self, ret: &mut Vec<AST>, node: AST) -> Result<()> {
+        let count = if self.dialect.rle {
+            self.count()?
         } else {
-            Ok(ret)
+            None
         };
+        let Some(count) = count else {
+            ret.push(node);
+            return Ok(());
+        };
+        let node = match node {
+            AST::Incr => AST::Add(count as u8),
+            AST::Decr => AST::Add((count as u8).wrapping_neg()),
+            AST::PtrAdvance => AST::PtrMove(count),
+            AST::PtrRetreat => AST::PtrMove(-count),
+            node => AST::Repeat(vec![node], count as usize),
+        };
+        if !matches!(node, AST::Add(0) | AST::PtrMove(0)) {
+            ret.push(node);
+        }
+        Ok(())
+    }
+
+    // A decimal count at the current position
+    fn count(&mut self) -> Result<Option<i32>> {
+        let len = self.code[self.off..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        if len == 0 {
+            return Ok(None);
+        }
+        let digits: String = self.code[self.off..self.off + len].iter().collect();
+        self.error_at = Some(self.off);
+        self.off += len;
+        digits.parse().map(Some).map_err(|_| Error::BadCount)
     }
 }
 
@@ -91,3 +361,166 @@ make_test!(
     "[[[[[[[[]]]]]]]",
     Err(Error::UnterminatedLoop)
 );
+
+#[cfg(test)]
+mod dialect {
+    use super::*;
+
+    #[test]
+    fn test_dialect() {
+        let all = Dialect {
+            dump: true,
+            bang: true,
+            rle: true,
+        };
+        assert_eq!(
+            Parser::parse_dialect("+#!-#", all),
+            Ok(ASTProgram(vec![AST::Incr, AST::Dump]))
+        );
+        assert_eq!(
+            Parser::parse_dialect("[!]", all),
+            Err(Error::UnterminatedLoop)
+        );
+        assert_eq!(
+            Parser::parse("+#!-"),
+            Ok(ASTProgram(vec![AST::Incr, AST::Decr]))
+        );
+        assert_eq!(all.split(",.!in!put"), (",.", Some("in!put")));
+        assert_eq!(Dialect::default().split(",.!input"), (",.!input", None));
+    }
+}
+
+#[cfg(test)]
+mod comments {
+    use super::*;
+
+    #[test]
+    fn test_comments() {
+        let comment = |s: &str| AST::Comment(s.to_string());
+        assert_eq!(
+            Parser::parse_comments("set x, then [- y]\n", Dialect::default()),
+            Ok(ASTProgram(vec![
+                comment("set x"),
+                AST::Getch,
+                comment(" then "),
+                AST::Loop(vec![AST::Decr, comment(" y")]),
+                comment("\n"),
+            ]))
+        );
+    }
+}
+
+#[cfg(test)]
+mod rle {
+    use super::*;
+    use crate::ir::{IRProgram, IR};
+
+    fn parse(code: &str) -> Result<ASTProgram> {
+        let rle = Dialect {
+            rle: true,
+            ..Default::default()
+        };
+        Parser::parse_dialect(code, rle)
+    }
+
+    #[test]
+    fn test_counts() {
+        assert_eq!(
+            parse("+5-300>12<3 +256>0.2[-]2"),
+            Ok(ASTProgram(vec![
+                AST::Add(5),
+                AST::Add(-44_i8 as u8),
+                AST::PtrMove(12),
+                AST::PtrMove(-3),
+                AST::Repeat(vec![AST::Putch], 2),
+                AST::Repeat(vec![AST::Loop(vec![AST::Decr])], 2),
+            ]))
+        );
+        // Only in RLE BF
+        assert_eq!(
+            Parser::parse("+5(-)*2"),
+            Ok(ASTProgram(vec![AST::Incr, AST::Decr]))
+        );
+        assert_eq!(parse("+99999999999"), Err(Error::BadCount));
+    }
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(
+            parse("(>+3(.)*2)*1000(<)"),
+            Ok(ASTProgram(vec![
+                AST::Repeat(
+                    vec![
+                        AST::PtrAdvance,
+                        AST::Add(3),
+                        AST::Repeat(vec![AST::Putch], 2)
+                    ],
+                    1000
+                ),
+                AST::Repeat(vec![AST::PtrRetreat], 1),
+            ]))
+        );
+        assert_eq!(parse("(+"), Err(Error::UnterminatedGroup));
+        assert_eq!(parse("[+)"), Err(Error::UnexpectedGroupTermination));
+        assert_eq!(parse("(+]"), Err(Error::UnexpectedLoopTermination));
+        assert_eq!(parse("(+)*"), Err(Error::BadCount));
+        assert_eq!(parse("((.)*65536)*65536"), Err(Error::TooLarge));
+        assert_eq!(parse(">2000000000>2000000000"), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn test_lowering() {
+        let prog = IRProgram::from_ast_program(&parse("+1000000>2000000000(.>)*2").unwrap());
+        assert_eq!(
+            prog.0,
+            vec![
+                IR::Add(0, 64),
+                IR::PtrChange(2000000000),
+                IR::Putch(0),
+                IR::PtrChange(1),
+                IR::Putch(0),
+                IR::PtrChange(1),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod spans {
+    use super::*;
+
+    #[test]
+    fn loop_spans() {
+        let code = "+[>[-]\n<[.]]";
+        assert_eq!(
+            Parser::loop_spans(code),
+            vec![
+                Span { start: 1, end: 12 },
+                Span { start: 3, end: 6 },
+                Span { start: 8, end: 11 },
+            ]
+        );
+        assert_eq!(Parser::loop_spans(code)[2].line_col(code), (2, 2));
+    }
+
+    #[test]
+    fn error_spans() {
+        let span = |code, dialect| Parser::error_span(code, dialect).map(|s| s.start);
+        let rle = Dialect {
+            rle: true,
+            ..Default::default()
+        };
+        assert_eq!(span("+[.]", Dialect::default()), None);
+        // The innermost unterminated loop
+        assert_eq!(span("[[-]\n[.", Dialect::default()), Some(5));
+        assert_eq!(span("+[-]].", Dialect::default()), Some(4));
+        assert_eq!(span("+(-)*x", rle), Some(4));
+        assert_eq!(span("+3 -99999999999", rle), Some(4));
+        assert_eq!(span("+(-(.)", rle), Some(1));
+        assert_eq!(span("((.)*65536)*65536", rle), None);
+        assert_eq!(
+            Parser::parse("[").unwrap_err().to_string(),
+            "`[` without a matching `]`"
+        );
+    }
+}
@@ -0,0 +1,637 @@
+// A preprocessor for brainfuck written by hand, run on the source before
+// `Parser::parse`. Directives take a line each:
+//
+//   #include "lib.b"        the text of another file, relative to this one
+//   #define name(a, b) ...  a macro with parameters, up to the end of the line
+//   #macro name(a, b)       a macro over the following lines, up to `#end`
+//   #cell name 3            a name for cell 3
+//   #at 3                   the pointer is at cell 3
+//
+// and in the text:
+//
+//   @name(x, y)*2           a macro's expansion, repeated twice; the
+//                           parameters and the count are optional, and
+//                           `@a` in the macro's body is the argument for `a`
+//   @(...)*5                the text in parentheses, repeated
+//   $name                   the moves from the pointer to a named cell
+//
+// Moving to a cell needs the pointer's position, which is followed through
+// the expanded program. A loop that moves the pointer loses it until the next
+// `#at`. Other lines starting with `#` are left alone, as are `@` and `$`
+// without a name.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+// How deep macro calls may nest, which also ends runaway recursion
+const MAX_DEPTH: usize = 64;
+// The longest output, in bytes
+const MAX_OUTPUT: usize = 1 << 26;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loc {
+    pub file: PathBuf,
+    // 1-based
+    pub line: usize,
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+    Io(String),
+    IncludeCycle,
+    BadDirective,
+    UnterminatedMacro,
+    UnexpectedEnd,
+    UnknownMacro(String),
+    WrongArgCount { expected: usize, actual: usize },
+    UnterminatedArgs,
+    BadCount,
+    UnknownCell(String),
+    UnknownPosition,
+    TooDeep,
+    TooLarge,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub loc: Loc,
+    pub kind: ErrorKind,
+    // The macro calls that led to the error, innermost first
+    pub calls: Vec<(String, Loc)>,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Io(e) => write!(f, "{}", e),
+            ErrorKind::IncludeCycle => write!(f, "the file includes itself"),
+            ErrorKind::BadDirective => write!(f, "malformed directive"),
+            ErrorKind::UnterminatedMacro => write!(f, "#macro without #end"),
+            ErrorKind::UnexpectedEnd => write!(f, "#end without #macro"),
+            ErrorKind::UnknownMacro(name) => write!(f, "unknown macro `{}`", name),
+            ErrorKind::WrongArgCount { expected, actual } => write!(
+                f,
+                "{} arguments given, the macro takes {}",
+                actual, expected
+            ),
+            ErrorKind::UnterminatedArgs => write!(f, "unterminated `(`"),
+            ErrorKind::BadCount => write!(f, "`*` without a count"),
+            ErrorKind::UnknownCell(name) => write!(f, "unknown cell `{}`", name),
+            ErrorKind::UnknownPosition => write!(
+                f,
+                "the pointer's position is unknown after a loop that moves it, \
+                 use #at to give it"
+            ),
+            ErrorKind::TooDeep => write!(f, "macro calls nested too deeply"),
+            ErrorKind::TooLarge => write!(f, "the expanded program is too large"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.loc, self.kind)?;
+        for (name, loc) in &self.calls {
+            write!(f, "\n  in @{} at {}", name, loc)?;
+        }
+        Ok(())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+struct Macro {
+    params: Vec<String>,
+    body: String,
+    // Where the body starts
+    loc: Loc,
+}
+
+// What a parameter stands for: the argument's text, expanded where it was
+// written
+struct Binding {
+    text: String,
+    loc: Loc,
+    scope: Scope,
+}
+
+type Scope = Rc<HashMap<String, Binding>>;
+
+// Expands `text`, the contents of the file at `path`. `load` reads included
+// files.
+pub fn preprocess(
+    path: &Path,
+    text: &str,
+    load: impl FnMut(&Path) -> io::Result<String>,
+) -> Result<String> {
+    let mut p = Preprocessor {
+        load,
+        macros: HashMap::new(),
+        cells: HashMap::new(),
+        files: vec![normalize(path)],
+        calls: Vec::new(),
+        pos: Some(0),
+        loops: Vec::new(),
+        out: String::new(),
+    };
+    p.file(text)?;
+    Ok(p.out)
+}
+
+// `path` without `.` and, where possible, `..` components, so that the same
+// file is always spelled the same
+fn normalize(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir if ret.file_name().is_some() => {
+                ret.pop();
+            }
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Splits a directive's leading name, as in `name(a, b) rest`, off `s`
+fn split_name(s: &str) -> (&str, &str) {
+    let end = s.find(|c| !is_name_char(c)).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+struct Preprocessor<F> {
+    load: F,
+    macros: HashMap<String, Rc<Macro>>,
+    cells: HashMap<String, i64>,
+    // The files being included, outermost first
+    files: Vec<PathBuf>,
+    calls: Vec<(String, Loc)>,
+    // The pointer's cell, if known
+    pos: Option<i64>,
+    // The pointer's cell at the start of each open loop
+    loops: Vec<Option<i64>>,
+    out: String,
+}
+
+impl<F: FnMut(&Path) -> io::Result<String>> Preprocessor<F> {
+    fn error(&self, loc: &Loc, kind: ErrorKind) -> Error {
+        Error {
+            loc: loc.clone(),
+            kind,
+            calls: self.calls.iter().rev().cloned().collect(),
+        }
+    }
+
+    fn loc(&self, line: usize) -> Loc {
+        Loc {
+            file: self.files.last().unwrap().clone(),
+            line,
+        }
+    }
+
+    // Processes the text of the innermost file
+    fn file(&mut self, text: &str) -> Result<()> {
+        let root = Scope::default();
+        let mut lines = text.lines().enumerate().map(|(i, l)| (i + 1, l));
+        while let Some((lineno, line)) = lines.next() {
+            let loc = self.loc(lineno);
+            let directive = line.trim().strip_prefix('#').map(split_name);
+            match directive {
+                Some(("include", rest)) => self.include(rest, &loc)?,
+                Some(("define", rest)) => {
+                    let (name, params, body) = self.header(rest, &loc)?;
+                    self.define(name, params, body.to_string(), loc);
+                }
+                Some(("macro", rest)) => {
+                    let (name, params, rest) = self.header(rest, &loc)?;
+                    if !rest.trim().is_empty() {
+                        return Err(self.error(&loc, ErrorKind::BadDirective));
+                    }
+                    let mut body = Vec::new();
+                    loop {
+                        match lines.next() {
+                            Some((_, l)) if l.trim() == "#end" => break,
+                            Some((_, l)) => body.push(l),
+                            None => return Err(self.error(&loc, ErrorKind::UnterminatedMacro)),
+                        }
+                    }
+                    let body_loc = self.loc(lineno + 1);
+                    self.define(name, params, body.join("\n"), body_loc);
+                    // Keep the lines of the output where they were
+                    self.out.push_str(&"\n".repeat(body.len() + 1));
+                }
+                Some(("end", "")) => return Err(self.error(&loc, ErrorKind::UnexpectedEnd)),
+                Some(("cell", rest)) => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                    [name, cell] if split_name(name).1.is_empty() => {
+                        let cell = cell
+                            .parse()
+                            .map_err(|_| self.error(&loc, ErrorKind::BadDirective))?;
+                        self.cells.insert(name.to_string(), cell);
+                    }
+                    _ => return Err(self.error(&loc, ErrorKind::BadDirective)),
+                },
+                Some(("at", rest)) => {
+                    let cell = rest
+                        .trim()
+                        .parse()
+                        .map_err(|_| self.error(&loc, ErrorKind::BadDirective))?;
+                    self.pos = Some(cell);
+                }
+                _ => self.expand(line, &loc, &root)?,
+            }
+            self.out.push('\n');
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, rest: &str, loc: &Loc) -> Result<()> {
+        let name = rest
+            .trim()
+            .strip_prefix('"')
+            .and_then(|r| r.strip_suffix('"'))
+            .ok_or_else(|| self.error(loc, ErrorKind::BadDirective))?;
+        let dir = loc.file.parent().unwrap_or(Path::new(""));
+        let path = normalize(&dir.join(name));
+        if self.files.contains(&path) {
+            return Err(self.error(loc, ErrorKind::IncludeCycle));
+        }
+        let text = (self.load)(&path)
+            .map_err(|e| self.error(loc, ErrorKind::Io(format!("{}: {}", path.display(), e))))?;
+        self.files.push(path);
+        let res = self.file(&text);
+        self.files.pop();
+        res
+    }
+
+    // Parses `name(a, b) rest` or `name rest`
+    fn header<'s>(&self, s: &'s str, loc: &Loc) -> Result<(String, Vec<String>, &'s str)> {
+        let (name, rest) = split_name(s.trim_start());
+        if name.is_empty() {
+            return Err(self.error(loc, ErrorKind::BadDirective));
+        }
+        let Some(rest) = rest.strip_prefix('(') else {
+            return Ok((name.to_string(), Vec::new(), rest));
+        };
+        let (params, rest) = rest
+            .split_once(')')
+            .ok_or_else(|| self.error(loc, ErrorKind::BadDirective))?;
+        let params: Vec<String> = if params.trim().is_empty() {
+            Vec::new()
+        } else {
+            params.split(',').map(|p| p.trim().to_string()).collect()
+        };
+        if params
+            .iter()
+            .any(|p| p.is_empty() || !split_name(p).1.is_empty())
+        {
+            return Err(self.error(loc, ErrorKind::BadDirective));
+        }
+        Ok((name.to_string(), params, rest))
+    }
+
+    fn define(&mut self, name: String, params: Vec<String>, body: String, loc: Loc) {
+        self.macros
+            .insert(name, Rc::new(Macro { params, body, loc }));
+    }
+
+    fn emit(&mut self, c: char, loc: &Loc) -> Result<()> {
+        match c {
+            '>' => self.pos = self.pos.map(|p| p + 1),
+            '<' => self.pos = self.pos.map(|p| p - 1),
+            '[' => self.loops.push(self.pos),
+            ']' => {
+                // Otherwise the parser reports the unbalanced loop
+                if let Some(start) = self.loops.pop() {
+                    if start != self.pos {
+                        self.pos = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+        if self.out.len() >= MAX_OUTPUT {
+            return Err(self.error(loc, ErrorKind::TooLarge));
+        }
+        self.out.push(c);
+        Ok(())
+    }
+
+    // Expands `text`, which starts at `loc`, with the parameters in `scope`
+    fn expand(&mut self, text: &str, loc: &Loc, scope: &Scope) -> Result<()> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut loc = loc.clone();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            i += 1;
+            let name: String = chars[i..]
+                .iter()
+                .take_while(|c| is_name_char(**c))
+                .collect();
+            match c {
+                '@' if !name.is_empty() || chars.get(i) == Some(&'(') => {
+                    i += name.len();
+                    let call_loc = loc.clone();
+                    let args = if chars.get(i) == Some(&'(') {
+                        let (args, end) = self.args(&chars, i + 1, &mut loc)?;
+                        i = end;
+                        Some(args)
+                    } else {
+                        None
+                    };
+                    let count = if chars.get(i) == Some(&'*') {
+                        let (count, end) = self.count(&chars, i + 1, &loc, scope)?;
+                        i = end;
+                        count
+                    } else {
+                        1
+                    };
+                    for _ in 0..count {
+                        let len = self.out.len();
+                        self.call(&name, args.as_deref(), &call_loc, scope)?;
+                        // The rest would add nothing either, however many
+                        if self.out.len() == len {
+                            break;
+                        }
+                    }
+                }
+                '$' if !name.is_empty() => {
+                    i += name.len();
+                    let cell = *self
+                        .cells
+                        .get(&name)
+                        .ok_or_else(|| self.error(&loc, ErrorKind::UnknownCell(name)))?;
+                    let pos = self
+                        .pos
+                        .ok_or_else(|| self.error(&loc, ErrorKind::UnknownPosition))?;
+                    let step = if cell > pos { '>' } else { '<' };
+                    for _ in 0..(cell - pos).abs() {
+                        self.emit(step, &loc)?;
+                    }
+                }
+                _ => {
+                    if c == '\n' {
+                        loc.line += 1;
+                    }
+                    self.emit(c, &loc)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Parses the arguments starting at `start`, just after the `(`, and
+    // returns them and the index after the `)`. An empty name is a group,
+    // which has its text as its only argument.
+    fn args(
+        &self,
+        chars: &[char],
+        start: usize,
+        loc: &mut Loc,
+    ) -> Result<(Vec<(String, Loc)>, usize)> {
+        let open = loc.clone();
+        let mut args = Vec::new();
+        let mut arg = (String::new(), loc.clone());
+        let mut depth = 0;
+        for (i, c) in chars.iter().enumerate().skip(start) {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    if !args.is_empty() || !arg.0.trim().is_empty() {
+                        args.push(arg);
+                    }
+                    return Ok((args, i + 1));
+                }
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push(std::mem::replace(&mut arg, (String::new(), loc.clone())));
+                    continue;
+                }
+                '\n' => loc.line += 1,
+                _ => {}
+            }
+            arg.0.push(*c);
+        }
+        Err(self.error(&open, ErrorKind::UnterminatedArgs))
+    }
+
+    // Parses the count starting at `start`, just after the `*`: a number, or
+    // a parameter whose argument is one
+    fn count(
+        &self,
+        chars: &[char],
+        start: usize,
+        loc: &Loc,
+        scope: &Scope,
+    ) -> Result<(u64, usize)> {
+        let (param, start) = match chars.get(start) {
+            Some('@') => (true, start + 1),
+            _ => (false, start),
+        };
+        let word: String = chars[start..]
+            .iter()
+            .take_while(|c| is_name_char(**c))
+            .collect();
+        let text = if param {
+            scope.get(&word).map(|b| b.text.trim())
+        } else {
+            Some(word.as_str())
+        };
+        match text.and_then(|t| t.parse().ok()) {
+            Some(count) => Ok((count, start + word.len())),
+            None => Err(self.error(loc, ErrorKind::BadCount)),
+        }
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: Option<&[(String, Loc)]>,
+        loc: &Loc,
+        scope: &Scope,
+    ) -> Result<()> {
+        if self.calls.len() == MAX_DEPTH {
+            return Err(self.error(loc, ErrorKind::TooDeep));
+        }
+        // A group
+        if name.is_empty() {
+            let text: Vec<&str> = args.unwrap().iter().map(|a| a.0.as_str()).collect();
+            return self.expand(&text.join(","), loc, scope);
+        }
+        if let (Some(binding), None) = (scope.get(name), args) {
+            self.calls.push((name.to_string(), loc.clone()));
+            self.expand(&binding.text, &binding.loc, &binding.scope)?;
+            self.calls.pop();
+            return Ok(());
+        }
+        let Some(m) = self.macros.get(name).cloned() else {
+            return Err(self.error(loc, ErrorKind::UnknownMacro(name.to_string())));
+        };
+        let args = args.unwrap_or_default();
+        if args.len() != m.params.len() {
+            let kind = ErrorKind::WrongArgCount {
+                expected: m.params.len(),
+                actual: args.len(),
+            };
+            return Err(self.error(loc, kind));
+        }
+        let inner: HashMap<String, Binding> = m
+            .params
+            .iter()
+            .zip(args)
+            .map(|(param, (text, loc))| {
+                let binding = Binding {
+                    text: text.clone(),
+                    loc: loc.clone(),
+                    scope: scope.clone(),
+                };
+                (param.clone(), binding)
+            })
+            .collect();
+        self.calls.push((name.to_string(), loc.clone()));
+        self.expand(&m.body, &m.loc, &Rc::new(inner))?;
+        self.calls.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Preprocesses `main.b` from `files`
+    fn run(files: &[(&str, &str)]) -> Result<String> {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|(p, t)| (PathBuf::from(p), t.to_string()))
+            .collect();
+        let load = |path: &Path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::ErrorKind::NotFound.into())
+        };
+        preprocess(Path::new("main.b"), &files[Path::new("main.b")], load)
+    }
+
+    fn commands(s: &str) -> String {
+        s.chars().filter(|c| "+-<>.,[]".contains(*c)).collect()
+    }
+
+    fn err(file: &str, line: usize, kind: ErrorKind) -> Error {
+        Error {
+            loc: Loc {
+                file: file.into(),
+                line,
+            },
+            kind,
+            calls: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_macros() {
+        let main = "#define clear [-]\n\
+                    #define add(n) @(+)*@n\n\
+                    #macro move(from, to)\n\
+                    @from[-@to+@from]\n\
+                    #end\n\
+                    @add(3) @clear @add(2)*2 @move(>, <)";
+        assert_eq!(
+            commands(&run(&[("main.b", main)]).unwrap()),
+            "+++[-]++++>[-<+>]"
+        );
+        // Directives keep their lines
+        assert_eq!(run(&[("main.b", main)]).unwrap().lines().count(), 6);
+        // Repeating an empty expansion stops at once, whatever the count
+        let main = "#define e\n@()*99999999999999 @e*99999999999999 @(@e)*99999999999999+";
+        assert_eq!(commands(&run(&[("main.b", main)]).unwrap()), "+");
+    }
+
+    #[test]
+    fn test_cells() {
+        let main = "#cell a 0\n#cell b 3\n$b+$a[-$b+$a]$b.\n[>]\n#at 5\n$a";
+        assert_eq!(
+            commands(&run(&[("main.b", main)]).unwrap()),
+            ">>>+<<<[->>>+<<<]>>>.[>]<<<<<"
+        );
+        assert_eq!(
+            run(&[("main.b", "#cell a 0\n[>]\n$a")]),
+            Err(err("main.b", 3, ErrorKind::UnknownPosition))
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let files = [
+            ("main.b", "#include \"lib/a.b\"\n@a"),
+            ("lib/a.b", "#include \"../b.b\"\n#define a @b+"),
+            ("b.b", "#define b -"),
+        ];
+        assert_eq!(commands(&run(&files).unwrap()), "-+");
+        let files = [
+            ("main.b", "#include \"lib/a.b\""),
+            ("lib/a.b", "\n#include \"./b.b\""),
+            ("lib/b.b", "#include \"../main.b\""),
+        ];
+        assert_eq!(run(&files), Err(err("lib/b.b", 1, ErrorKind::IncludeCycle)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let main = "#define inner(x) $nowhere\n\
+                    #macro outer\n\
+                    +\n\
+                    @inner(-)\n\
+                    #end\n\
+                    @outer";
+        let e = run(&[("main.b", main)]).unwrap_err();
+        assert_eq!(e.loc.line, 1);
+        assert_eq!(e.kind, ErrorKind::UnknownCell("nowhere".to_string()));
+        assert_eq!(
+            e.to_string(),
+            "main.b:1: unknown cell `nowhere`\n  in @inner at main.b:4\n  in @outer at main.b:6"
+        );
+
+        assert_eq!(
+            run(&[("main.b", "\n@f(+)")]),
+            Err(err("main.b", 2, ErrorKind::UnknownMacro("f".to_string())))
+        );
+        assert_eq!(
+            run(&[("main.b", "#define f(a, b) @a@b\n@f(+)")]),
+            Err(err(
+                "main.b",
+                2,
+                ErrorKind::WrongArgCount {
+                    expected: 2,
+                    actual: 1
+                }
+            ))
+        );
+        assert_eq!(
+            run(&[("main.b", "#macro f\n+")]),
+            Err(err("main.b", 1, ErrorKind::UnterminatedMacro))
+        );
+        assert_eq!(
+            run(&[("main.b", "\n@(+)*2 @(+")]),
+            Err(err("main.b", 2, ErrorKind::UnterminatedArgs))
+        );
+        let e = run(&[("main.b", "#define f @f\n@f")]).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::TooDeep);
+        assert_eq!(e.calls.len(), MAX_DEPTH);
+    }
+}
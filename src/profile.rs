@@ -0,0 +1,302 @@
+// Counts how often each IR node runs during evaluation and summarizes it by
+// IR variant and by loop.
+
+use crate::eval::{Hook, State};
+use crate::ir::{IRProgram, IR};
+use crate::optimize;
+use crate::parser::{Parser, Span};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::ControlFlow;
+
+// Nodes are identified by address, so the program must not move or change
+// between evaluation and `report`.
+#[derive(Default)]
+pub struct Profiler {
+    executed: HashMap<*const IR, u64>,
+    iterations: HashMap<*const IR, u64>,
+}
+
+impl Hook for Profiler {
+    fn node(&mut self, node: &IR, _state: &mut State) -> ControlFlow<()> {
+        *self.executed.entry(node).or_default() += 1;
+        ControlFlow::Continue(())
+    }
+    fn iteration(&mut self, node: &IR, _state: &mut State) -> ControlFlow<()> {
+        *self.iterations.entry(node).or_default() += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+pub struct LoopProfile {
+    // 1-based line and column in the source
+    pub loc: Option<(usize, usize)>,
+    // The loop's source, or its IR if there is no source
+    pub text: String,
+    pub executed: u64,
+    pub iterations: u64,
+}
+
+pub struct Report {
+    pub total: u64,
+    pub variants: BTreeMap<&'static str, u64>,
+    // Most iterations first
+    pub loops: Vec<LoopProfile>,
+}
+
+// How many loops `Report` displays
+const HOTTEST: usize = 10;
+// How much of a loop's text `Report` displays
+const TEXT_LEN: usize = 60;
+
+impl Profiler {
+    // `source` is the brainfuck `prog` was translated from, before any
+    // optimization, and gives the loops their locations.
+    pub fn report(&self, prog: &IRProgram, source: Option<&str>) -> Report {
+        let spans: Vec<_> = source
+            .map_or(Vec::new(), Parser::loop_spans)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.report_spans(prog, source, &spans)
+    }
+
+    // Like `report`, for `prog` optimized from `unoptimized`. Each loop gets
+    // the location of the loop it came from.
+    pub fn report_optimized(
+        &self,
+        prog: &IRProgram,
+        unoptimized: &IRProgram,
+        source: Option<&str>,
+    ) -> Report {
+        let spans = source.map_or(Vec::new(), Parser::loop_spans);
+        let spans: Vec<_> = origins(prog, unoptimized)
+            .into_iter()
+            .map(|origin| origin.and_then(|i| spans.get(i).copied()))
+            .collect();
+        self.report_spans(prog, source, &spans)
+    }
+
+    // `spans` has the span of each loop of `prog`, in preorder
+    fn report_spans(
+        &self,
+        prog: &IRProgram,
+        source: Option<&str>,
+        spans: &[Option<Span>],
+    ) -> Report {
+        let mut report = Report {
+            total: 0,
+            variants: BTreeMap::new(),
+            loops: Vec::new(),
+        };
+        self.walk(&prog.0, source, spans, &mut report);
+        // Stable, so ties stay in program order
+        report
+            .loops
+            .sort_by_key(|l| std::cmp::Reverse(l.iterations));
+        report
+    }
+
+    fn walk(&self, irs: &[IR], source: Option<&str>, spans: &[Option<Span>], report: &mut Report) {
+        for ir in irs {
+            let executed = self.executed.get(&(ir as *const IR)).copied().unwrap_or(0);
+            report.total += executed;
+            *report.variants.entry(ir.name()).or_default() += executed;
+            if let IR::Loop(inner) | IR::SimpleLoop(_, inner) = ir {
+                let span = spans.get(report.loops.len()).copied().flatten();
+                let (loc, text) = match (source, span) {
+                    (Some(source), Some(span)) => (
+                        Some(span.line_col(source)),
+                        source
+                            .chars()
+                            .skip(span.start)
+                            .take(span.end - span.start)
+                            .collect(),
+                    ),
+                    _ => (None, ir.to_string()),
+                };
+                report.loops.push(LoopProfile {
+                    loc,
+                    text: text.split_whitespace().collect::<Vec<_>>().join(" "),
+                    executed,
+                    iterations: self
+                        .iterations
+                        .get(&(ir as *const IR))
+                        .copied()
+                        .unwrap_or(0),
+                });
+                self.walk(inner, source, spans, report);
+            }
+        }
+    }
+}
+
+// The loops of `irs` in preorder, each with how many loops it contains
+fn loops<'a>(irs: &'a [IR], out: &mut Vec<(&'a IR, usize)>) {
+    for ir in irs {
+        if let IR::Loop(inner) | IR::SimpleLoop(_, inner) = ir {
+            let i = out.len();
+            out.push((ir, 0));
+            loops(inner, out);
+            out[i].1 = out.len() - i - 1;
+        }
+    }
+}
+
+// What a loop with `body` may be optimized to: within another loop, or at the
+// top level before any `Loop`, where `remove_unread_stores` also rewrites
+// simple loops. Otherwise the optimizer treats a loop the same wherever it is.
+fn optimized_forms(body: &[IR]) -> [Option<IR>; 2] {
+    let single = |irs: Vec<IR>| match &irs[..] {
+        [ir @ (IR::Loop(_) | IR::SimpleLoop(..))] => Some(ir.clone()),
+        _ => None,
+    };
+    let nested = single(optimize::optimize_loop(body));
+    // After a `getch`, so that the loop may run
+    let prog = IRProgram(vec![IR::Getch(0), IR::Loop(body.to_vec())]);
+    let top_level = optimize::optimize(&prog)
+        .0
+        .into_iter()
+        .skip_while(|ir| !matches!(ir, IR::Loop(_) | IR::SimpleLoop(..)))
+        .collect();
+    [nested, single(top_level)]
+}
+
+// For each loop of `prog`, in preorder, the preorder index of the loop of
+// `unoptimized` that it was optimized from. The optimizer never adds or
+// reorders loops, so each loop is matched with the next one that may be
+// optimized to it. A loop that doesn't match was removed, along with any
+// loops inside it.
+pub fn origins(prog: &IRProgram, unoptimized: &IRProgram) -> Vec<Option<usize>> {
+    let mut optimized = Vec::new();
+    loops(&prog.0, &mut optimized);
+    let mut candidates = Vec::new();
+    loops(&unoptimized.0, &mut candidates);
+    let mut forms = vec![None; candidates.len()];
+
+    let mut next = 0;
+    let mut ret = Vec::new();
+    for (ir, _) in optimized {
+        let mut i = next;
+        while i < candidates.len() {
+            let (candidate, nested) = candidates[i];
+            let candidate_forms = forms[i].get_or_insert_with(|| match candidate {
+                IR::Loop(body) => optimized_forms(body),
+                _ => [Some(candidate.clone()), None],
+            });
+            if candidate_forms.contains(&Some(ir.clone())) {
+                break;
+            }
+            i += 1 + nested;
+        }
+        if i < candidates.len() {
+            ret.push(Some(i));
+            next = i + 1;
+        } else {
+            ret.push(None);
+        }
+    }
+    ret
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} instructions executed", self.total)?;
+        for (name, count) in &self.variants {
+            writeln!(f, "  {:<12}{:>14}", name, count)?;
+        }
+        writeln!(f, "hottest loops (iterations, entries):")?;
+        for l in self.loops.iter().take(HOTTEST) {
+            let loc = l
+                .loc
+                .map_or(String::new(), |(line, col)| format!("{}:{}", line, col));
+            let mut text: String = l.text.chars().take(TEXT_LEN).collect();
+            if l.text.chars().count() > TEXT_LEN {
+                text.push_str("...");
+            }
+            writeln!(
+                f,
+                "  {:>14} {:>10}  {:<9} {}",
+                l.iterations, l.executed, loc, text
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::{self, Limits, StreamIO};
+    use crate::optimize;
+
+    fn profile(prog: &IRProgram, source: Option<&str>) -> Report {
+        let mut profiler = Profiler::default();
+        let mut io = StreamIO::new(&b""[..], Vec::new());
+        let res = eval::eval_with_hook(prog, &mut io, &Limits::default(), &mut profiler).unwrap();
+        let report = profiler.report(prog, source);
+        assert_eq!(
+            report.total + report.loops.iter().map(|l| l.iterations).sum::<u64>(),
+            res.steps
+        );
+        report
+    }
+
+    #[test]
+    fn test_counts() {
+        let code = "+++[>++[-]<-]";
+        let prog = IRProgram::from_ast_program(&Parser::parse(code).unwrap());
+        let report = profile(&prog, Some(code));
+        assert_eq!(report.total, 3 + 1 + 3 * 6 + 3 * 2);
+        assert_eq!(report.variants["loop"], 1 + 3);
+        assert_eq!(report.variants["add"], 3 + 3 * 3 + 3 * 2);
+        assert_eq!(report.loops.len(), 2);
+        assert_eq!(report.loops[0].loc, Some((1, 8)));
+        assert_eq!(report.loops[0].text, "[-]");
+        assert_eq!(
+            (report.loops[0].iterations, report.loops[0].executed),
+            (6, 3)
+        );
+        assert_eq!(report.loops[1].loc, Some((1, 4)));
+        assert_eq!(report.loops[1].text, "[>++[-]<-]");
+        assert_eq!(
+            (report.loops[1].iterations, report.loops[1].executed),
+            (3, 1)
+        );
+
+        let prog = optimize::optimize(&prog);
+        let report = profile(&prog, None);
+        // The inner loop is now a `movimm`
+        assert_eq!(report.loops.len(), 1);
+        assert_eq!(report.loops[0].loc, None);
+        assert_eq!(report.loops[0].iterations, 3);
+        assert!(report.total < 3 + 1 + 3 * 6 + 3 * 2);
+    }
+
+    #[test]
+    fn test_optimized_locations() {
+        let code = "[.]\n+++[>++[-]<-]";
+        let unoptimized = IRProgram::from_ast_program(&Parser::parse(code).unwrap());
+        let prog = optimize::optimize(&unoptimized);
+        let mut profiler = Profiler::default();
+        let mut io = StreamIO::new(&b""[..], Vec::new());
+        eval::eval_with_hook(&prog, &mut io, &Limits::default(), &mut profiler).unwrap();
+        let report = profiler.report_optimized(&prog, &unoptimized, Some(code));
+        assert_eq!(report.loops.len(), 1);
+        assert_eq!(report.loops[0].loc, Some((2, 4)));
+        assert_eq!(report.loops[0].text, "[>++[-]<-]");
+        assert_eq!(report.loops[0].iterations, 3);
+    }
+
+    #[test]
+    fn test_origins() {
+        let origins = |code| {
+            let unoptimized = IRProgram::from_ast_program(&Parser::parse(code).unwrap());
+            origins(&optimize::optimize(&unoptimized), &unoptimized)
+        };
+        // The dead loop goes with the one inside it, which matches nothing
+        assert_eq!(origins("[[>]],[>]"), vec![Some(2)]);
+        assert_eq!(origins(",[->+<],[>[.<]]"), vec![Some(1), Some(2)]);
+        assert_eq!(origins(",[[-]>[.]]"), vec![Some(0), Some(2)]);
+    }
+}
@@ -1,114 +1,197 @@
+use crate::eval::DUMP_LEN;
 use crate::ir::{IRProgram, IR};
+use std::io::{self, Write};
 
-pub struct RiscVEmitter {
+pub struct RiscVEmitter<'a, W: Write> {
     label_count: usize,
+    out: &'a mut W,
 }
 
-impl RiscVEmitter {
-    pub fn emit(prog: &IRProgram, nostdlib: bool, mem_size: usize) {
-        let mut e = Self { label_count: 0 };
-        println!(".section .bss");
-        println!("arr: .skip {}", mem_size);
-        println!(".text");
+impl<'a, W: Write> RiscVEmitter<'a, W> {
+    pub fn emit(
+        prog: &IRProgram,
+        nostdlib: bool,
+        mem_size: usize,
+        out: &'a mut W,
+    ) -> io::Result<()> {
+        let mut e = Self {
+            label_count: 0,
+            out,
+        };
+        let dump = prog.has_dump();
+        writeln!(e.out, ".section .bss")?;
+        if dump {
+            // Whole windows, so a dump never reads past the end
+            writeln!(e.out, "arr: .skip {}", mem_size.next_multiple_of(16))?;
+            writeln!(e.out, "dumpbuf: .skip {}", DUMP_LEN)?;
+            writeln!(e.out, ".section .rodata")?;
+            writeln!(e.out, "hexdigits: .ascii \"0123456789abcdef\"")?;
+        } else {
+            writeln!(e.out, "arr: .skip {}", mem_size)?;
+        }
+        writeln!(e.out, ".text")?;
+        if dump {
+            e.emit_dump()?;
+        }
         if nostdlib {
-            println!(".globl _start");
-            println!("_start:");
+            writeln!(e.out, ".globl _start")?;
+            writeln!(e.out, "_start:")?;
         } else {
-            println!(".globl main");
-            println!("main:");
+            writeln!(e.out, ".globl main")?;
+            writeln!(e.out, "main:")?;
         }
-        println!("  la s1, arr");
+        writeln!(e.out, "  la s1, arr")?;
 
         for n in &prog.0 {
-            e.emit_inner(&n, nostdlib);
+            e.emit_inner(n, nostdlib)?;
         }
 
         if nostdlib {
-            println!("  li a0, 0");
-            println!("  li a7, 93");
-            println!("  ecall");
+            writeln!(e.out, "  li a0, 0")?;
+            writeln!(e.out, "  li a7, 93")?;
+            writeln!(e.out, "  ecall")?;
         } else {
-            println!("  li a0, 0");
-            println!("  call exit");
+            writeln!(e.out, "  li a0, 0")?;
+            writeln!(e.out, "  call exit")?;
         }
+        writeln!(e.out, ".section .note.GNU-stack,\"\",@progbits")?;
+        Ok(())
     }
 
-    fn emit_inner(&mut self, node: &IR, nostdlib: bool) {
+    // Prints the same line as `eval::dump_line`, with a raw write so that it
+    // works the same with and without libc. Only s1 is preserved.
+    fn emit_dump(&mut self) -> io::Result<()> {
+        writeln!(self.out, "dump:")?;
+        writeln!(self.out, "  la t0, arr")?;
+        writeln!(self.out, "  sub t1, s1, t0")?;
+        writeln!(self.out, "  andi t1, t1, -16")?; // Start of the window
+        writeln!(self.out, "  add t0, t0, t1")?;
+        writeln!(self.out, "  la t2, dumpbuf")?;
+        writeln!(self.out, "  la t3, hexdigits")?;
+        writeln!(self.out, "  li t4, 28")?;
+        writeln!(self.out, "dump_addr:")?;
+        writeln!(self.out, "  srl t5, t1, t4")?;
+        writeln!(self.out, "  andi t5, t5, 15")?;
+        writeln!(self.out, "  add t5, t3, t5")?;
+        writeln!(self.out, "  lbu t5, (t5)")?;
+        writeln!(self.out, "  sb t5, (t2)")?;
+        writeln!(self.out, "  addi t2, t2, 1")?;
+        writeln!(self.out, "  addi t4, t4, -4")?;
+        writeln!(self.out, "  bgez t4, dump_addr")?;
+        writeln!(self.out, "  li t5, 58")?; // ':'
+        writeln!(self.out, "  sb t5, (t2)")?;
+        writeln!(self.out, "  addi t2, t2, 1")?;
+        writeln!(self.out, "  li t4, 16")?;
+        writeln!(self.out, "dump_cell:")?;
+        writeln!(self.out, "  li t5, 32")?; // ' '
+        writeln!(self.out, "  bne t0, s1, dump_digits")?;
+        writeln!(self.out, "  li t5, 62")?; // '>'
+        writeln!(self.out, "dump_digits:")?;
+        writeln!(self.out, "  sb t5, (t2)")?;
+        writeln!(self.out, "  lbu t5, (t0)")?;
+        writeln!(self.out, "  srli t6, t5, 4")?;
+        writeln!(self.out, "  add t6, t3, t6")?;
+        writeln!(self.out, "  lbu t6, (t6)")?;
+        writeln!(self.out, "  sb t6, 1(t2)")?;
+        writeln!(self.out, "  andi t5, t5, 15")?;
+        writeln!(self.out, "  add t5, t3, t5")?;
+        writeln!(self.out, "  lbu t5, (t5)")?;
+        writeln!(self.out, "  sb t5, 2(t2)")?;
+        writeln!(self.out, "  addi t2, t2, 3")?;
+        writeln!(self.out, "  addi t0, t0, 1")?;
+        writeln!(self.out, "  addi t4, t4, -1")?;
+        writeln!(self.out, "  bnez t4, dump_cell")?;
+        writeln!(self.out, "  li t5, 10")?; // '\n'
+        writeln!(self.out, "  sb t5, (t2)")?;
+        writeln!(self.out, "  li a0, 2")?; // stderr
+        writeln!(self.out, "  la a1, dumpbuf")?;
+        writeln!(self.out, "  li a2, {}", DUMP_LEN)?;
+        writeln!(self.out, "  li a7, 64")?; // write
+        writeln!(self.out, "  ecall")?;
+        writeln!(self.out, "  ret")?;
+        Ok(())
+    }
+
+    fn emit_inner(&mut self, node: &IR, nostdlib: bool) -> io::Result<()> {
         match node {
             IR::PtrChange(amt) => {
-                println!("  addi s1, s1, {}", amt);
+                writeln!(self.out, "  addi s1, s1, {}", amt)?;
             }
             IR::Add(add_off, amt) => {
-                println!("  lb t0, {}(s1)", add_off);
-                println!("  addi t0, t0, {}", amt);
-                println!("  sb t0, {}(s1)", add_off);
+                writeln!(self.out, "  lb t0, {}(s1)", add_off)?;
+                writeln!(self.out, "  addi t0, t0, {}", amt)?;
+                writeln!(self.out, "  sb t0, {}(s1)", add_off)?;
             }
             IR::Putch(off) => {
-                println!("  li a0, 1");
-                println!("  mv a1, s1");
-                println!("  addi a1, a1, {}", off);
-                println!("  li a2, 1");
+                writeln!(self.out, "  li a0, 1")?;
+                writeln!(self.out, "  mv a1, s1")?;
+                writeln!(self.out, "  addi a1, a1, {}", off)?;
+                writeln!(self.out, "  li a2, 1")?;
                 if nostdlib {
-                    println!("  li a7, 64");
-                    println!("  ecall");
+                    writeln!(self.out, "  li a7, 64")?;
+                    writeln!(self.out, "  ecall")?;
                 } else {
-                    println!("  call write");
+                    writeln!(self.out, "  call write")?;
                 }
             }
             IR::Getch(off) => {
-                println!("  li a0, 0");
-                println!("  mv a1, s1");
-                println!("  addi a1, a1, {}", off);
-                println!("  li a2, 1");
+                writeln!(self.out, "  li a0, 0")?;
+                writeln!(self.out, "  mv a1, s1")?;
+                writeln!(self.out, "  addi a1, a1, {}", off)?;
+                writeln!(self.out, "  li a2, 1")?;
                 if nostdlib {
-                    println!("  li a7, 63");
-                    println!("  ecall");
+                    writeln!(self.out, "  li a7, 63")?;
+                    writeln!(self.out, "  ecall")?;
                 } else {
-                    println!("  call read");
+                    writeln!(self.out, "  call read")?;
                 }
             }
             IR::Loop(nodes) => {
                 self.label_count += 1;
                 let l = format!("label_{}", self.label_count);
-                println!("{}:", l);
-                println!("  lb t0, (s1)");
-                println!("  beqz t0, {}_done", l);
+                writeln!(self.out, "{}:", l)?;
+                writeln!(self.out, "  lb t0, (s1)")?;
+                writeln!(self.out, "  beqz t0, {}_done", l)?;
 
                 for n in nodes {
-                    self.emit_inner(n, nostdlib);
+                    self.emit_inner(n, nostdlib)?;
                 }
 
-                println!("  j {}", l);
-                println!("{}_done:", l);
+                writeln!(self.out, "  j {}", l)?;
+                writeln!(self.out, "{}_done:", l)?;
             }
 
             IR::SimpleLoop(delta, nodes) => {
                 self.label_count += 1;
                 let l = format!("label_{}", self.label_count);
-                println!("{}:", l);
-                println!("  lb t0, (s1)");
-                println!("  beqz t0, {}_done", l);
+                writeln!(self.out, "{}:", l)?;
+                writeln!(self.out, "  lb t0, (s1)")?;
+                writeln!(self.out, "  beqz t0, {}_done", l)?;
 
                 for n in nodes {
-                    self.emit_inner(n, nostdlib);
+                    self.emit_inner(n, nostdlib)?;
                 }
-                self.emit_inner(&IR::Add(0, *delta), nostdlib);
+                self.emit_inner(&IR::Add(0, *delta), nostdlib)?;
 
-                println!("  j {}", l);
-                println!("{}_done:", l);
+                writeln!(self.out, "  j {}", l)?;
+                writeln!(self.out, "{}_done:", l)?;
             }
             IR::AddMul(off, amt) => {
-                println!("  lb t0, (s1)");
-                println!("  li t1, {}", amt);
-                println!("  mul t0, t0, t1");
-                println!("  lb t1, {}(s1)", off);
-                println!("  add t0, t0, t1");
-                println!("  sb t0, {}(s1)", off);
+                writeln!(self.out, "  lb t0, (s1)")?;
+                writeln!(self.out, "  li t1, {}", amt)?;
+                writeln!(self.out, "  mul t0, t0, t1")?;
+                writeln!(self.out, "  lb t1, {}(s1)", off)?;
+                writeln!(self.out, "  add t0, t0, t1")?;
+                writeln!(self.out, "  sb t0, {}(s1)", off)?;
             }
             IR::MovImm(off, imm) => {
-                println!("  li t0, {}", imm);
-                println!("  sb t0, {}(s1)", off);
+                writeln!(self.out, "  li t0, {}", imm)?;
+                writeln!(self.out, "  sb t0, {}(s1)", off)?;
+            }
+            IR::Dump => {
+                writeln!(self.out, "  call dump")?;
             }
         }
+        Ok(())
     }
 }
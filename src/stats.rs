@@ -0,0 +1,245 @@
+use crate::ast::{ASTProgram, AST};
+use crate::ir::{IRProgram, IR};
+use crate::json::Json;
+use crate::optimize::PASSES;
+use crate::Arch;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Sizes of a program through compilation: as parsed, after each optimization
+// pass and as emitted by each backend.
+
+pub struct Stats {
+    // Nodes of the parsed program, when it is brainfuck
+    pub ast_nodes: Option<usize>,
+    // IR nodes before optimization
+    pub ir_nodes: usize,
+    // IR nodes after each optimization pass, in order
+    pub passes: Vec<(&'static str, usize)>,
+    // Nodes of each `IR` variant in the optimized program
+    pub variants: BTreeMap<&'static str, usize>,
+    pub loops: usize,
+    pub max_depth: usize,
+    // Loops that `simplify_loops` made `SimpleLoop`s, and those of them that
+    // `compress_muls` replaced with `AddMul`s and a clear
+    pub simple_loops: usize,
+    pub mul_loops: usize,
+    // Instructions emitted by each backend, `None` if it failed
+    pub emitted: Vec<(&'static str, Option<usize>)>,
+}
+
+// The backends that emit instructions, by their `--arch` names
+const BACKENDS: [(Arch, &str); 4] = [
+    (Arch::X86_64, "x86_64"),
+    (Arch::RiscV, "risc-v"),
+    (Arch::C, "c"),
+    (Arch::Bf, "bf"),
+];
+
+fn ast_nodes(asts: &[AST]) -> usize {
+    asts.iter()
+        .map(|ast| match ast {
+            AST::Loop(body) | AST::Repeat(body, _) => 1 + ast_nodes(body),
+            _ => 1,
+        })
+        .sum()
+}
+
+// Calls `f` on every node of `irs` with its loop nesting depth
+fn walk(irs: &[IR], depth: usize, f: &mut impl FnMut(&IR, usize)) {
+    for ir in irs {
+        f(ir, depth);
+        if let IR::Loop(inner) | IR::SimpleLoop(_, inner) = ir {
+            walk(inner, depth + 1, f);
+        }
+    }
+}
+
+fn count(irs: &[IR], pred: impl Fn(&IR) -> bool) -> usize {
+    let mut ret = 0;
+    walk(irs, 0, &mut |ir, _| ret += pred(ir) as usize);
+    ret
+}
+
+fn is_simple_loop(ir: &IR) -> bool {
+    matches!(ir, IR::SimpleLoop(..))
+}
+
+// The instructions in a backend's output: indented lines of assembly or C,
+// other than directives and closing braces, or brainfuck commands
+fn instructions(arch: &Arch, out: &str) -> usize {
+    match arch {
+        Arch::Bf => out.chars().filter(|c| "+-<>[].,#".contains(*c)).count(),
+        _ => out
+            .lines()
+            .filter(|l| l.starts_with(char::is_whitespace))
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('.') && *l != "}")
+            .count(),
+    }
+}
+
+// `optimize` says whether `prog` is optimized, as at any level but -O0
+pub fn collect(
+    ast: Option<&ASTProgram>,
+    prog: &IRProgram,
+    optimize: bool,
+    nostdlib: bool,
+    mem_size: usize,
+) -> Stats {
+    let mut stats = Stats {
+        ast_nodes: ast.map(|ast| ast_nodes(&ast.0)),
+        ir_nodes: count(&prog.0, |_| true),
+        passes: Vec::new(),
+        variants: BTreeMap::new(),
+        loops: count(&prog.0, |ir| matches!(ir, IR::Loop(_))),
+        max_depth: 0,
+        simple_loops: 0,
+        mul_loops: 0,
+        emitted: Vec::new(),
+    };
+    walk(&prog.0, 1, &mut |ir, depth| {
+        if let IR::Loop(_) = ir {
+            stats.max_depth = stats.max_depth.max(depth);
+        }
+    });
+
+    let mut irs = prog.0.clone();
+    for (name, pass) in PASSES.iter().filter(|_| optimize) {
+        let before = count(&irs, is_simple_loop);
+        irs = pass(&irs);
+        let after = count(&irs, is_simple_loop);
+        match *name {
+            "simplify_loops" => stats.simple_loops += after - before,
+            "compress_muls" => stats.mul_loops += before - after,
+            _ => {}
+        }
+        stats.passes.push((name, count(&irs, |_| true)));
+    }
+    walk(&irs, 0, &mut |ir, _| {
+        *stats.variants.entry(ir.name()).or_default() += 1
+    });
+
+    let prog = IRProgram(irs);
+    for (arch, name) in BACKENDS {
+        let mut out = Vec::new();
+        let emitted = crate::emit(&prog, &arch, nostdlib, mem_size, &mut out)
+            .ok()
+            .map(|()| instructions(&arch, &String::from_utf8_lossy(&out)));
+        stats.emitted.push((name, emitted));
+    }
+    stats
+}
+
+impl Stats {
+    pub fn to_json(&self) -> Json {
+        let number = |n: Option<usize>| n.map_or(Json::Null, Json::from);
+        let passes = self
+            .passes
+            .iter()
+            .map(|(name, nodes)| {
+                Json::object(vec![
+                    ("pass", (*name).into()),
+                    ("ir_nodes", (*nodes).into()),
+                ])
+            })
+            .collect();
+        let variants = self
+            .variants
+            .iter()
+            .map(|(name, n)| (*name, (*n).into()))
+            .collect();
+        let emitted = self
+            .emitted
+            .iter()
+            .map(|(name, n)| (*name, number(*n)))
+            .collect();
+        Json::object(vec![
+            ("ast_nodes", number(self.ast_nodes)),
+            ("ir_nodes", self.ir_nodes.into()),
+            ("passes", Json::Array(passes)),
+            ("variants", Json::object(variants)),
+            ("loops", self.loops.into()),
+            ("max_depth", self.max_depth.into()),
+            ("simple_loops", self.simple_loops.into()),
+            ("mul_loops", self.mul_loops.into()),
+            ("emitted", Json::object(emitted)),
+        ])
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(n) = self.ast_nodes {
+            writeln!(f, "{} AST nodes", n)?;
+        }
+        writeln!(f, "{} IR nodes", self.ir_nodes)?;
+        for (name, nodes) in &self.passes {
+            writeln!(f, "  after {:<22}{:>10}", name, nodes)?;
+        }
+        writeln!(f, "optimized IR nodes by variant:")?;
+        for (name, n) in &self.variants {
+            writeln!(f, "  {:<12}{:>14}", name, n)?;
+        }
+        writeln!(
+            f,
+            "{} loops, nested {} deep; {} made simpleloop, {} of those addmul",
+            self.loops, self.max_depth, self.simple_loops, self.mul_loops
+        )?;
+        writeln!(f, "emitted instructions:")?;
+        for (name, n) in &self.emitted {
+            match n {
+                Some(n) => writeln!(f, "  {:<12}{:>14}", name, n)?,
+                None => writeln!(f, "  {:<12}{:>14}", name, "failed")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn collect(code: &str, optimize: bool) -> Stats {
+        let ast = Parser::parse(code).unwrap();
+        let prog = IRProgram::from_ast_program(&ast);
+        super::collect(Some(&ast), &prog, optimize, false, 30000)
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = collect(",[->++>+<<]>[[-]>.<]", true);
+        assert_eq!(stats.ast_nodes, Some(17));
+        assert_eq!(stats.ir_nodes, 17);
+        assert_eq!(stats.loops, 3);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.simple_loops, 2);
+        assert_eq!(stats.mul_loops, 2);
+        assert_eq!(stats.passes.len(), PASSES.len());
+        let total: usize = stats.variants.values().sum();
+        assert_eq!(stats.passes.last().unwrap().1, total);
+        assert_eq!(stats.variants.get("addmul"), Some(&2));
+        assert_eq!(stats.variants.get("loop"), Some(&1));
+        assert!(stats.emitted.iter().all(|(_, n)| n.is_some()));
+        assert_eq!(stats.emitted[0].0, "x86_64");
+
+        let stats = collect("+[>+]", false);
+        assert!(stats.passes.is_empty());
+        assert_eq!(stats.variants.get("add"), Some(&2));
+        assert_eq!(
+            stats.to_json().get("emitted").unwrap().get("bf"),
+            Some(&Json::Number(5.0))
+        );
+    }
+
+    #[test]
+    fn test_instructions() {
+        let asm = ".text\nmain:\n  push %rbx\n  .p2align 4\nlabel_1:\n  jmp label_1\n";
+        assert_eq!(instructions(&Arch::X86_64, asm), 2);
+        let c = "int main() {\n  while (arr[idx]) {\n  putchar(arr[idx]);\n  }\nreturn 0;\n}\n";
+        assert_eq!(instructions(&Arch::C, c), 2);
+        assert_eq!(instructions(&Arch::Bf, "+[>-]\nx"), 5);
+    }
+}
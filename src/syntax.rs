@@ -0,0 +1,235 @@
+// Surface syntaxes that are brainfuck spelled differently. Each maps its
+// tokens one-to-one onto brainfuck's commands, so programs are translated to
+// brainfuck and everything from the parser on is shared.
+
+use crate::parser::{Error, Result};
+
+// The commands, in the order of the tables below
+const COMMANDS: [char; 8] = ['>', '<', '+', '-', '.', ',', '[', ']'];
+
+// The punctuation of each command's pair of Ook! words
+const OOK: [&str; 8] = [".?", "?.", "..", "!!", "!.", ".!", "!?", "?!"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Syntax {
+    Brainfuck,
+    // Commands are pairs of `Ook.`, `Ook?` and `Ook!`, e.g. `Ook. Ook?` is `>`
+    Ook,
+    // Ook! with `Blub` for `Ook`
+    Blub,
+    Tokens(TokenMap),
+}
+
+impl Syntax {
+    // Text that isn't a token is a comment, as in brainfuck
+    pub fn translate(&self, code: &str) -> Result<String> {
+        match self {
+            Syntax::Brainfuck => Ok(code.to_string()),
+            Syntax::Ook => translate_words(code, "Ook"),
+            Syntax::Blub => translate_words(code, "Blub"),
+            Syntax::Tokens(map) => Ok(map.translate(code)),
+        }
+    }
+}
+
+fn translate_words(code: &str, word: &str) -> Result<String> {
+    let mut ret = String::new();
+    let mut line = 1;
+    // The line and punctuation of the first word of a pair
+    let mut first: Option<(usize, char)> = None;
+    let mut i = 0;
+    while i < code.len() {
+        let rest = &code[i..];
+        let punct = rest
+            .strip_prefix(word)
+            .and_then(|r| r.chars().next())
+            .filter(|c| matches!(c, '.' | '?' | '!'));
+        let Some(punct) = punct else {
+            let c = rest.chars().next().unwrap();
+            if c == '\n' {
+                line += 1;
+            }
+            i += c.len_utf8();
+            continue;
+        };
+        i += word.len() + 1;
+        match first.take() {
+            None => first = Some((line, punct)),
+            Some((_, p)) => {
+                let pair: String = [p, punct].iter().collect();
+                match OOK.iter().position(|o| *o == pair) {
+                    Some(idx) => ret.push(COMMANDS[idx]),
+                    None => {
+                        let token = format!("{}{} {}{}", word, p, word, punct);
+                        return Err(Error::UnknownToken(line, token));
+                    }
+                }
+            }
+        }
+    }
+    match first {
+        Some((line, _)) => Err(Error::UnpairedToken(line)),
+        None => Ok(ret),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    // Not a command followed by its token
+    BadLine(usize),
+    UnknownCommand(usize, String),
+    DuplicateCommand(usize, char),
+    DuplicateToken(usize, String),
+    MissingCommand(char),
+}
+
+// User-defined tokens for the eight commands
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenMap {
+    // Longest token first, so that the longest match wins
+    tokens: Vec<(String, char)>,
+}
+
+impl TokenMap {
+    // Each line of `config` is a command, whitespace and its token, which may
+    // contain spaces but no line breaks. Blank lines and lines starting with
+    // `;` are ignored.
+    pub fn parse(config: &str) -> std::result::Result<Self, ConfigError> {
+        let mut tokens: Vec<(String, char)> = Vec::new();
+        for (i, line) in config.lines().enumerate() {
+            let lineno = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let (cmd, token) = match line.split_once(char::is_whitespace) {
+                Some((cmd, token)) => (cmd, token.trim()),
+                None => return Err(ConfigError::BadLine(lineno)),
+            };
+            let cmd = match cmd.parse::<char>() {
+                Ok(c) if COMMANDS.contains(&c) => c,
+                _ => return Err(ConfigError::UnknownCommand(lineno, cmd.to_string())),
+            };
+            if tokens.iter().any(|(_, c)| *c == cmd) {
+                return Err(ConfigError::DuplicateCommand(lineno, cmd));
+            }
+            if tokens.iter().any(|(t, _)| t == token) {
+                return Err(ConfigError::DuplicateToken(lineno, token.to_string()));
+            }
+            tokens.push((token.to_string(), cmd));
+        }
+        if let Some(c) = COMMANDS.iter().find(|c| !tokens.iter().any(|t| t.1 == **c)) {
+            return Err(ConfigError::MissingCommand(*c));
+        }
+        tokens.sort_by_key(|(t, _)| std::cmp::Reverse(t.len()));
+        Ok(Self { tokens })
+    }
+
+    fn translate(&self, code: &str) -> String {
+        let mut ret = String::new();
+        let mut i = 0;
+        while i < code.len() {
+            let rest = &code[i..];
+            match self
+                .tokens
+                .iter()
+                .find(|(t, _)| rest.starts_with(t.as_str()))
+            {
+                Some((token, cmd)) => {
+                    ret.push(*cmd);
+                    i += token.len();
+                }
+                None => i += rest.chars().next().unwrap().len_utf8(),
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+
+    // Spells brainfuck `code` with `tokens`, in the order of `COMMANDS`
+    fn spell(code: &str, tokens: &[&str; 8], sep: &str) -> String {
+        let words: Vec<&str> = code
+            .chars()
+            .filter_map(|c| COMMANDS.iter().position(|cmd| *cmd == c))
+            .map(|i| tokens[i])
+            .collect();
+        words.join(sep)
+    }
+
+    fn ook(word: &str) -> [String; 8] {
+        OOK.map(|p| {
+            let mut chars = p.chars();
+            let (a, b) = (chars.next().unwrap(), chars.next().unwrap());
+            format!("{}{} {}{}", word, a, word, b)
+        })
+    }
+
+    #[test]
+    fn test_programs() {
+        let map =
+            "; Spelled out\n> right\n< left\n+ inc\n- dec\n. put char\n, get\n[ while\n] end\n";
+        let map = Syntax::Tokens(TokenMap::parse(map).unwrap());
+        let words = [
+            "right", "left", "inc", "dec", "put char", "get", "while", "end",
+        ];
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let code = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            let expected = || Parser::parse(&code);
+            for (syntax, word) in [(Syntax::Ook, "Ook"), (Syntax::Blub, "Blub")] {
+                let tokens = ook(word);
+                let tokens = tokens.each_ref().map(String::as_str);
+                let code = spell(&code, &tokens, "\n");
+                assert_eq!(Parser::parse_syntax(&code, &syntax), expected());
+            }
+            let code = spell(&code, &words, " ");
+            assert_eq!(Parser::parse_syntax(&code, &map), expected());
+        }
+    }
+
+    #[test]
+    fn test_ook() {
+        // Anything but the words is a comment, and pairs may span lines
+        assert_eq!(
+            Syntax::Ook.translate("Ook. Ook?  Ook.\nOok. hi Ook! Ook."),
+            Ok(">+.".to_string())
+        );
+        assert_eq!(
+            Syntax::Ook.translate("Ook. Ook.\nOok? Ook?"),
+            Err(Error::UnknownToken(2, "Ook? Ook?".to_string()))
+        );
+        assert_eq!(
+            Syntax::Blub.translate("Blub. Blub.\nBlub!"),
+            Err(Error::UnpairedToken(2))
+        );
+    }
+
+    #[test]
+    fn test_token_map() {
+        let map = TokenMap::parse("> a\n< b\n+ ab\n- c\n. d\n, e\n[ f\n] g").unwrap();
+        // The longest token wins
+        assert_eq!(map.translate("aab b?"), ">+<");
+        assert_eq!(
+            TokenMap::parse("> a\n< a"),
+            Err(ConfigError::DuplicateToken(2, "a".to_string()))
+        );
+        assert_eq!(
+            TokenMap::parse("> a\n> b"),
+            Err(ConfigError::DuplicateCommand(2, '>'))
+        );
+        assert_eq!(
+            TokenMap::parse("x a"),
+            Err(ConfigError::UnknownCommand(1, "x".to_string()))
+        );
+        assert_eq!(TokenMap::parse(">"), Err(ConfigError::BadLine(1)));
+        assert_eq!(
+            TokenMap::parse("> a"),
+            Err(ConfigError::MissingCommand('<'))
+        );
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+#![allow(clippy::unnecessary_cast)]
 
 use crate::eval;
 use crate::ir;
@@ -13,19 +14,20 @@ struct TestIO {
 }
 
 impl eval::IO for TestIO {
-    fn putchar(&mut self, val: ir::Value) {
+    fn putchar(&mut self, val: ir::Value) -> std::io::Result<()> {
         assert!(
             self.output_idx < self.output.len(),
             "Produced too much output"
         );
         assert_eq!(val, self.output[self.output_idx]);
         self.output_idx += 1;
+        Ok(())
     }
-    fn getchar(&mut self) -> ir::Value {
+    fn getchar(&mut self) -> std::io::Result<Option<ir::Value>> {
         assert!(self.input_idx < self.input.len(), "Consumed too much input");
         let ret = self.input[self.input_idx];
         self.input_idx += 1;
-        ret
+        Ok(Some(ret))
     }
 }
 
@@ -53,19 +55,21 @@ impl TestIO {
 }
 
 fn test_unopt_program_io(code: &str, input: &str, output: &str) {
-    let ast_prog = parser::Parser::parse(&code).unwrap();
+    let ast_prog = parser::Parser::parse(code).unwrap();
     let ir_prog = ir::IRProgram::from_ast_program(&ast_prog);
     let mut io = TestIO::new(input, output);
-    eval::eval_with_io(&ir_prog, &mut io);
+    let res = eval::eval_with_io(&ir_prog, &mut io, &eval::Limits::default()).unwrap();
+    assert_eq!(res.status, eval::Status::Completed);
     io.done();
 }
 
 fn test_opt_program_io(code: &str, input: &str, output: &str) {
-    let ast_prog = parser::Parser::parse(&code).unwrap();
+    let ast_prog = parser::Parser::parse(code).unwrap();
     let ir_prog = ir::IRProgram::from_ast_program(&ast_prog);
     let ir_prog = optimize::optimize(&ir_prog);
     let mut io = TestIO::new(input, output);
-    eval::eval_with_io(&ir_prog, &mut io);
+    let res = eval::eval_with_io(&ir_prog, &mut io, &eval::Limits::default()).unwrap();
+    assert_eq!(res.status, eval::Status::Completed);
     io.done();
 }
 
@@ -96,6 +100,7 @@ make_test!(two_cells, "++>+++<.>.", "", "\x02\x03");
 make_test!(double_add_mul, "++++[->++++[->++++<]<]>>.", "", "\x40");
 make_test!(dead_loops, "[.]>>>>>>>>>>>>>>>>>>>>>[,]", "", "");
 make_test!(simple_const_add_mul, "+++++>+[-<+>]<.", "", "\x06");
+make_test!(put_counter, "+++[-.]", "", "\x02\x01\x00");
 make_test!(simple_get_add_mul, ",>+[-<+>]<.", "\x05", "\x06");
 
 make_test!(
@@ -104,3 +109,132 @@ make_test!(
     "\x05",
     &((-20 as i8 as u8) as char).to_string()
 );
+
+#[cfg(test)]
+mod deterministic {
+    use super::*;
+    use clap::ValueEnum;
+    use std::hash::{Hash, Hasher};
+
+    fn compile_hash(code: &str, arch: &crate::Arch, nostdlib: bool) -> u64 {
+        let ast_prog = parser::Parser::parse(code).unwrap();
+        let ir_prog = optimize::optimize(&ir::IRProgram::from_ast_program(&ast_prog));
+        let mut out = Vec::new();
+        crate::emit(&ir_prog, arch, nostdlib, 30000, &mut out).unwrap();
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        out.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_programs() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let code = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            for arch in crate::Arch::value_variants() {
+                for nostdlib in [false, true] {
+                    let first = compile_hash(&code, arch, nostdlib);
+                    for _ in 0..4 {
+                        assert_eq!(compile_hash(&code, arch, nostdlib), first);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod limits {
+    use super::*;
+    use std::time::Duration;
+
+    fn eval(code: &str, limits: &eval::Limits) -> eval::EvalResult {
+        let ast_prog = parser::Parser::parse(code).unwrap();
+        let ir_prog = ir::IRProgram::from_ast_program(&ast_prog);
+        let mut io = TestIO::new("", "");
+        eval::eval_with_io(&ir_prog, &mut io, limits).unwrap()
+    }
+
+    #[test]
+    fn completed() {
+        let res = eval("++[-]", &eval::Limits::default());
+        assert_eq!(res.status, eval::Status::Completed);
+        // Two adds, the loop, two iterations of one subtraction each
+        assert_eq!(res.steps, 7);
+    }
+
+    #[test]
+    fn out_of_steps() {
+        let limits = eval::Limits {
+            max_steps: Some(1000),
+            ..Default::default()
+        };
+        let res = eval("+[]", &limits);
+        assert_eq!(res.status, eval::Status::OutOfSteps);
+        assert_eq!(res.steps, 1000);
+    }
+
+    #[test]
+    fn timeout() {
+        let limits = eval::Limits {
+            timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        assert_eq!(eval("+[]", &limits).status, eval::Status::Timeout);
+    }
+
+    #[test]
+    fn tape_error() {
+        let limits = eval::Limits {
+            tape_size: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(eval("<+", &limits).status, eval::Status::TapeError(-1));
+        assert_eq!(eval(">>>>+", &limits).status, eval::Status::TapeError(4));
+        // Moving off the tape is fine as long as nothing is accessed there
+        assert_eq!(eval("<>+", &limits).status, eval::Status::Completed);
+    }
+}
+
+#[cfg(test)]
+mod io {
+    use super::*;
+    use std::io::{self, Write};
+
+    fn eval(code: &str, io: &mut impl eval::IO) -> io::Result<eval::EvalResult> {
+        let ast_prog = parser::Parser::parse(code).unwrap();
+        let ir_prog = ir::IRProgram::from_ast_program(&ast_prog);
+        eval::eval_with_io(&ir_prog, io, &eval::Limits::default())
+    }
+
+    #[test]
+    fn stream() {
+        let mut io = eval::StreamIO::new(&b"\xff"[..], Vec::new());
+        // Byte 0xff, then EOF, which is also -1 but reported separately
+        eval(",.,.", &mut io).unwrap();
+        assert_eq!(io.output, b"\xff\xff");
+
+        let mut io = eval::StreamIO::new(&b""[..], Vec::new());
+        let res = eval("+,", &mut io).unwrap();
+        assert_eq!(res.state.cells(), [(0, eval::EOF)].into());
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_error() {
+        // Without the error this would never stop
+        let mut io = eval::StreamIO::new(&b""[..], Closed);
+        let e = eval("+[.]", &mut io).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,136 @@
+// Logs every step of an evaluation: the IR node about to run, or the loop
+// about to iterate, with the pointer and the cells the node touches.
+
+use crate::eval::{Hook, State};
+use crate::ir::{Offset, IR};
+use std::io::Write;
+use std::ops::ControlFlow;
+
+pub struct Tracer<W: Write> {
+    out: W,
+    step: u64,
+    // Only steps in `from..to` are logged
+    from: u64,
+    to: u64,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, from: u64, count: Option<u64>) -> Self {
+        Self {
+            out,
+            step: 0,
+            from,
+            to: count.map_or(u64::MAX, |c| from.saturating_add(c)),
+        }
+    }
+
+    // Tracing is best effort, so write errors are dropped
+    fn log(&mut self, what: &str, cells: &[Offset], state: &State) {
+        let step = self.step;
+        self.step += 1;
+        if step < self.from || step >= self.to {
+            return;
+        }
+        let mut line = format!("{:>10} ptr {:<6} {:<20}", step, state.ptr(), what);
+        for off in cells {
+            let cell = state.ptr() + off;
+            line += &format!(" [{}]={}", cell, state.get(cell) as u8);
+        }
+        let _ = writeln!(self.out, "{}", line.trim_end());
+    }
+}
+
+// The cells `node` reads or writes, relative to the pointer. Loops only
+// count the cell they test.
+fn touched(node: &IR) -> Vec<Offset> {
+    match node {
+        IR::Loop(_) | IR::SimpleLoop(..) => vec![0],
+        IR::PtrChange(_) | IR::Dump => vec![],
+        IR::Add(off, _) | IR::Putch(off) | IR::Getch(off) | IR::MovImm(off, _) => vec![*off],
+        IR::AddMul(off, _) => vec![0, *off],
+    }
+}
+
+// One line describing `node`, without the body of loops
+pub fn header(node: &IR) -> String {
+    match node {
+        IR::SimpleLoop(delta, _) => format!("simpleloop {}", delta),
+        IR::Loop(_) => "loop".to_string(),
+        _ => node.to_string().trim_end().to_string(),
+    }
+}
+
+impl<W: Write> Hook for Tracer<W> {
+    fn node(&mut self, node: &IR, state: &mut State) -> ControlFlow<()> {
+        self.log(&header(node), &touched(node), state);
+        ControlFlow::Continue(())
+    }
+    fn iteration(&mut self, node: &IR, state: &mut State) -> ControlFlow<()> {
+        self.log(&format!("{} iteration", node.name()), &[0], state);
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eval::{self, Limits, StreamIO};
+    use crate::ir_parser::Parser;
+
+    fn trace(code: &str, from: u64, count: Option<u64>) -> String {
+        let prog = Parser::parse(code).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), from, count);
+        let mut io = StreamIO::new(&b""[..], Vec::new());
+        eval::eval_with_hook(&prog, &mut io, &Limits::default(), &mut tracer).unwrap();
+        String::from_utf8(tracer.out).unwrap()
+    }
+
+    #[test]
+    fn test_trace() {
+        let code = "add 0, 2\nsimpleloop -1 {\n  addmul +1, 3\n}\nptrchange +1\n";
+        assert_eq!(
+            trace(code, 0, None),
+            [
+                "         0 ptr 0      add 0, 2             [0]=0",
+                "         1 ptr 0      simpleloop -1        [0]=2",
+                "         2 ptr 0      simpleloop iteration [0]=2",
+                "         3 ptr 0      addmul +1, 3         [0]=2 [1]=0",
+                "         4 ptr 0      simpleloop iteration [0]=1",
+                "         5 ptr 0      addmul +1, 3         [0]=1 [1]=6",
+                "         6 ptr 0      ptrchange +1",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            trace(code, 4, Some(2)),
+            [
+                "         4 ptr 0      simpleloop iteration [0]=1",
+                "         5 ptr 0      addmul +1, 3         [0]=1 [1]=6",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_dump() {
+        let prog =
+            Parser::parse("ptrchange -2\nmovimm 0, 7\nmovimm +18, -1\nptrchange +1\n").unwrap();
+        let mut io = StreamIO::new(&b""[..], Vec::new());
+        let res = eval::eval_with_io(&prog, &mut io, &Limits::default()).unwrap();
+        let mut out = Vec::new();
+        res.state.dump(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            [
+                "tape (pointer at -1):",
+                "     -16:    0     0     0     0     0     0     0     0     0     0     0     0     0     0     7  [  0]",
+                "       0:    0     0     0     0     0     0     0     0     0     0     0     0     0     0     0     0",
+                "      16:  255",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -1,116 +1,205 @@
+use crate::eval::DUMP_LEN;
 use crate::ir::{IRProgram, IR};
+use std::io::{self, Write};
 
-pub struct X86Emitter {
+pub struct X86Emitter<'a, W: Write> {
     label_count: usize,
+    out: &'a mut W,
 }
 
-impl X86Emitter {
-    pub fn emit(prog: &IRProgram, nostdlib: bool, mem_size: usize) {
-        let mut e = Self { label_count: 0 };
-        println!(".section .bss");
-        println!("arr: .skip {}", mem_size);
-        println!(".text");
+impl<'a, W: Write> X86Emitter<'a, W> {
+    pub fn emit(
+        prog: &IRProgram,
+        nostdlib: bool,
+        mem_size: usize,
+        out: &'a mut W,
+    ) -> io::Result<()> {
+        let mut e = Self {
+            label_count: 0,
+            out,
+        };
+        let dump = prog.has_dump();
+        writeln!(e.out, ".section .bss")?;
+        if dump {
+            // Whole windows, so a dump never reads past the end
+            writeln!(e.out, "arr: .skip {}", mem_size.next_multiple_of(16))?;
+            writeln!(e.out, "dumpbuf: .skip {}", DUMP_LEN)?;
+            writeln!(e.out, ".section .rodata")?;
+            writeln!(e.out, "hexdigits: .ascii \"0123456789abcdef\"")?;
+        } else {
+            writeln!(e.out, "arr: .skip {}", mem_size)?;
+        }
+        writeln!(e.out, ".text")?;
+        if dump {
+            e.emit_dump()?;
+        }
         if nostdlib {
-            println!("putch:");
-            println!("  mov $1, %rax"); // Write
-            println!("  mov $1, %rdi"); // stdout
-            println!("  movq %rbx, %rsi"); // ptr
-            println!("  mov $1, %rdx"); // 1
-            println!("  syscall");
-            println!("  ret");
+            // Both take the cell's address in %rsi
+            writeln!(e.out, "putch:")?;
+            writeln!(e.out, "  mov $1, %rax")?; // Write
+            writeln!(e.out, "  mov $1, %rdi")?; // stdout
+            writeln!(e.out, "  mov $1, %rdx")?; // 1
+            writeln!(e.out, "  syscall")?;
+            writeln!(e.out, "  ret")?;
 
-            println!("getch:");
-            println!("  mov $0, %rax"); // Read
-            println!("  mov $0, %rdi"); // stdin
-            println!("  movq %rbx, %rsi"); // ptr
-            println!("  mov $1, %rdx"); // 1
-            println!("  syscall");
-            println!("  ret");
+            writeln!(e.out, "getch:")?;
+            writeln!(e.out, "  mov $0, %rax")?; // Read
+            writeln!(e.out, "  mov $0, %rdi")?; // stdin
+            writeln!(e.out, "  mov $1, %rdx")?; // 1
+            writeln!(e.out, "  syscall")?;
+            writeln!(e.out, "  ret")?;
 
-            println!(".globl _start");
-            println!("_start:");
+            writeln!(e.out, ".globl _start")?;
+            writeln!(e.out, "_start:")?;
         } else {
-            println!(".globl main");
-            println!("main:");
+            writeln!(e.out, ".globl main")?;
+            writeln!(e.out, "main:")?;
+            writeln!(e.out, "  push %rbx")?; // Callee-saved, and aligns the stack
         }
-        println!("  movq $arr, %rbx");
+        writeln!(e.out, "  lea arr(%rip), %rbx")?;
 
         for n in &prog.0 {
-            e.emit_inner(&n, nostdlib);
+            e.emit_inner(n, nostdlib)?;
         }
 
-        println!("  mov $60, %rax"); // exit
-        println!("  mov $0, %rdi"); // 0 success
-        println!("  syscall");
+        if nostdlib {
+            writeln!(e.out, "  mov $60, %rax")?; // exit
+            writeln!(e.out, "  mov $0, %rdi")?; // 0 success
+            writeln!(e.out, "  syscall")?;
+        } else {
+            // Go through libc's exit so buffered output gets flushed
+            writeln!(e.out, "  mov $0, %rdi")?;
+            writeln!(e.out, "  call exit")?;
+        }
+        writeln!(e.out, ".section .note.GNU-stack,\"\",@progbits")?;
+        Ok(())
+    }
+
+    // Prints the same line as `eval::dump_line`, with a raw write so that it
+    // works the same with and without libc. Only %rbx is preserved.
+    fn emit_dump(&mut self) -> io::Result<()> {
+        writeln!(self.out, "dump:")?;
+        writeln!(self.out, "  lea arr(%rip), %rsi")?;
+        writeln!(self.out, "  mov %rbx, %rdx")?;
+        writeln!(self.out, "  sub %rsi, %rdx")?;
+        writeln!(self.out, "  and $-16, %rdx")?; // Start of the window
+        writeln!(self.out, "  add %rdx, %rsi")?;
+        writeln!(self.out, "  lea dumpbuf(%rip), %rdi")?;
+        writeln!(self.out, "  lea hexdigits(%rip), %r8")?;
+        writeln!(self.out, "  mov $8, %ecx")?;
+        writeln!(self.out, "dump_addr:")?;
+        writeln!(self.out, "  rol $4, %edx")?;
+        writeln!(self.out, "  mov %edx, %eax")?;
+        writeln!(self.out, "  and $15, %eax")?;
+        writeln!(self.out, "  movb (%r8,%rax), %al")?;
+        writeln!(self.out, "  movb %al, (%rdi)")?;
+        writeln!(self.out, "  inc %rdi")?;
+        writeln!(self.out, "  dec %ecx")?;
+        writeln!(self.out, "  jnz dump_addr")?;
+        writeln!(self.out, "  movb $58, (%rdi)")?; // ':'
+        writeln!(self.out, "  inc %rdi")?;
+        writeln!(self.out, "  mov $16, %ecx")?;
+        writeln!(self.out, "dump_cell:")?;
+        writeln!(self.out, "  movb $32, (%rdi)")?; // ' '
+        writeln!(self.out, "  cmp %rsi, %rbx")?;
+        writeln!(self.out, "  jne dump_digits")?;
+        writeln!(self.out, "  movb $62, (%rdi)")?; // '>'
+        writeln!(self.out, "dump_digits:")?;
+        writeln!(self.out, "  movzbl (%rsi), %eax")?;
+        writeln!(self.out, "  mov %eax, %edx")?;
+        writeln!(self.out, "  shr $4, %edx")?;
+        writeln!(self.out, "  movb (%r8,%rdx), %dl")?;
+        writeln!(self.out, "  movb %dl, 1(%rdi)")?;
+        writeln!(self.out, "  and $15, %eax")?;
+        writeln!(self.out, "  movb (%r8,%rax), %al")?;
+        writeln!(self.out, "  movb %al, 2(%rdi)")?;
+        writeln!(self.out, "  add $3, %rdi")?;
+        writeln!(self.out, "  inc %rsi")?;
+        writeln!(self.out, "  dec %ecx")?;
+        writeln!(self.out, "  jnz dump_cell")?;
+        writeln!(self.out, "  movb $10, (%rdi)")?; // '\n'
+        writeln!(self.out, "  mov $1, %rax")?; // Write
+        writeln!(self.out, "  mov $2, %rdi")?; // stderr
+        writeln!(self.out, "  lea dumpbuf(%rip), %rsi")?;
+        writeln!(self.out, "  mov ${}, %rdx", DUMP_LEN)?;
+        writeln!(self.out, "  syscall")?;
+        writeln!(self.out, "  ret")?;
+        Ok(())
     }
 
-    fn emit_inner(&mut self, node: &IR, nostdlib: bool) {
+    fn emit_inner(&mut self, node: &IR, nostdlib: bool) -> io::Result<()> {
         match node {
             IR::PtrChange(amt) => {
-                println!("  add ${}, %rbx", amt);
+                writeln!(self.out, "  add ${}, %rbx", amt)?;
             }
             IR::Add(add_off, amt) => {
-                println!("  movb {}(%rbx), %dil", add_off);
-                println!("  add ${}, %dil", amt);
-                println!("  movb %dil, {}(%rbx)", add_off);
+                writeln!(self.out, "  movb {}(%rbx), %dil", add_off)?;
+                writeln!(self.out, "  add ${}, %dil", amt)?;
+                writeln!(self.out, "  movb %dil, {}(%rbx)", add_off)?;
             }
             IR::Putch(off) => {
                 if nostdlib {
-                    println!("  call putch"); // Read
+                    writeln!(self.out, "  lea {}(%rbx), %rsi", off)?;
+                    writeln!(self.out, "  call putch")?;
                 } else {
-                    println!("  movb {}(%rbx), %dil", off);
-                    println!("  call putchar");
+                    writeln!(self.out, "  movb {}(%rbx), %dil", off)?;
+                    writeln!(self.out, "  call putchar")?;
                 }
             }
             IR::Getch(off) => {
                 if nostdlib {
-                    println!("  call gettch"); // Read
+                    writeln!(self.out, "  lea {}(%rbx), %rsi", off)?;
+                    writeln!(self.out, "  call getch")?;
                 } else {
-                    println!("  call getchar");
-                    println!("  movb %al, {}(%rbx)", off);
+                    writeln!(self.out, "  call getchar")?;
+                    writeln!(self.out, "  movb %al, {}(%rbx)", off)?;
                 }
             }
             IR::Loop(nodes) => {
                 self.label_count += 1;
                 let l = format!("label_{}", self.label_count);
-                println!("{}:", l);
-                println!("  movb (%rbx), %dil");
-                println!("  cmp $0, %dil");
-                println!("  je {}_done", l);
+                writeln!(self.out, "{}:", l)?;
+                writeln!(self.out, "  movb (%rbx), %dil")?;
+                writeln!(self.out, "  cmp $0, %dil")?;
+                writeln!(self.out, "  je {}_done", l)?;
 
                 for n in nodes {
-                    self.emit_inner(n, nostdlib);
+                    self.emit_inner(n, nostdlib)?;
                 }
 
-                println!("  jmp {}", l);
-                println!("{}_done:", l);
+                writeln!(self.out, "  jmp {}", l)?;
+                writeln!(self.out, "{}_done:", l)?;
             }
             IR::SimpleLoop(delta, nodes) => {
                 self.label_count += 1;
                 let l = format!("label_{}", self.label_count);
-                println!("{}:", l);
-                println!("  movb (%rbx), %dil");
-                println!("  cmp $0, %dil");
-                println!("  je {}_done", l);
+                writeln!(self.out, "{}:", l)?;
+                writeln!(self.out, "  movb (%rbx), %dil")?;
+                writeln!(self.out, "  cmp $0, %dil")?;
+                writeln!(self.out, "  je {}_done", l)?;
 
                 for n in nodes {
-                    self.emit_inner(n, nostdlib);
+                    self.emit_inner(n, nostdlib)?;
                 }
 
-                self.emit_inner(&IR::Add(0, *delta), nostdlib);
-                println!("  jmp {}", l);
-                println!("{}_done:", l);
+                self.emit_inner(&IR::Add(0, *delta), nostdlib)?;
+                writeln!(self.out, "  jmp {}", l)?;
+                writeln!(self.out, "{}_done:", l)?;
             }
             IR::AddMul(off, amt) => {
-                println!("  movb (%rbx), %dil");
-                println!("  imul ${}, %rdi", amt);
-                println!("  movb {}(%rbx), %sil", off);
-                println!("  add %sil, %dil");
-                println!("  movb %dil, {}(%rbx)", off);
+                writeln!(self.out, "  movb (%rbx), %dil")?;
+                writeln!(self.out, "  imul ${}, %rdi", amt)?;
+                writeln!(self.out, "  movb {}(%rbx), %sil", off)?;
+                writeln!(self.out, "  add %sil, %dil")?;
+                writeln!(self.out, "  movb %dil, {}(%rbx)", off)?;
             }
             IR::MovImm(off, imm) => {
-                println!("  movb ${}, {}(%rbx)", imm, off);
+                writeln!(self.out, "  movb ${}, {}(%rbx)", imm, off)?;
+            }
+            IR::Dump => {
+                writeln!(self.out, "  call dump")?;
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,793 @@
+// Runs every program in `programs/` that has fixtures in `tests/fixtures/`
+// (`<name>.out` and optionally `<name>.in`) through each backend and compares
+// the output. Backends whose toolchain is missing are skipped.
+//
+// Programs that never finish also have `<name>.steps`. Their `.out` is the
+// start of the output: evaluation stops after that many steps, and compiled
+// programs are killed once they have printed it.
+//
+// sh.b has no fixtures on purpose. It reads commands until EOF and then loops
+// forever, and what it does after EOF depends on each backend's EOF handling.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const BFC: &str = env!("CARGO_BIN_EXE_bfc");
+
+struct Fixture {
+    name: String,
+    program: PathBuf,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    steps: Option<u64>,
+}
+
+impl Fixture {
+    // Arguments that bound the evaluation of a program that never finishes
+    fn step_args(&self) -> Vec<String> {
+        match self.steps {
+            Some(n) => vec!["--max-steps".to_string(), n.to_string()],
+            None => vec![],
+        }
+    }
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut ret = Vec::new();
+    for entry in std::fs::read_dir(root.join("tests/fixtures")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|e| e != "out") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        ret.push(Fixture {
+            program: root.join("programs").join(format!("{}.b", name)),
+            input: std::fs::read(path.with_extension("in")).unwrap_or_default(),
+            output: std::fs::read(&path).unwrap(),
+            steps: std::fs::read_to_string(path.with_extension("steps"))
+                .ok()
+                .map(|s| s.trim().parse().unwrap()),
+            name,
+        });
+    }
+    ret.sort_by(|a, b| a.name.cmp(&b.name));
+    assert!(!ret.is_empty());
+    ret
+}
+
+fn have(tool: &str) -> bool {
+    let found = Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+    if !found {
+        eprintln!("{} not found, skipping", tool);
+    }
+    found
+}
+
+fn run(cmd: &mut Command, input: &[u8]) -> Vec<u8> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit without reading all of its input
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        cmd,
+        output.status
+    );
+    output.stdout
+}
+
+// Runs the program of `f` with `cmd`, stopping one that never finishes once it
+// has printed as much as `f` expects
+fn run_fixture(cmd: &mut Command, f: &Fixture, input: &[u8]) -> Vec<u8> {
+    if f.steps.is_none() {
+        return run(cmd, input);
+    }
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let mut output = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .take(f.output.len() as u64)
+        .read_to_end(&mut output)
+        .unwrap();
+    let _ = child.kill();
+    child.wait().unwrap();
+    output
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Compiles every fixture with `bfc <args>`, builds the result with `build`
+// and compares the output of running it with `exec`.
+fn check_backend(
+    backend: &str,
+    args: &[&str],
+    build: impl Fn(&Path, &Path) -> Command,
+    exec: impl Fn(&Path) -> Command,
+) {
+    let dir = scratch(backend);
+    for f in fixtures() {
+        let src = dir.join(format!("{}.src", f.name));
+        let exe = dir.join(&f.name);
+        let code = run(Command::new(BFC).args(args).arg(&f.program), &[]);
+        std::fs::write(&src, code).unwrap();
+        run(&mut build(&src, &exe), &[]);
+        let output = run_fixture(&mut exec(&exe), &f, &f.input);
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "{} on {}",
+            backend,
+            f.name
+        );
+    }
+}
+
+fn check_eval(opt: &str) {
+    for f in fixtures() {
+        let output = run_fixture(
+            Command::new(BFC)
+                .args([opt, "--eval"])
+                .args(f.step_args())
+                .arg(&f.program),
+            &f,
+            &f.input,
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "eval {} on {}",
+            opt,
+            f.name
+        );
+    }
+}
+
+#[test]
+fn eval_unoptimized() {
+    check_eval("-O0");
+}
+
+#[test]
+fn eval_optimized() {
+    check_eval("-O1");
+}
+
+#[test]
+fn c() {
+    if !have("cc") {
+        return;
+    }
+    check_backend(
+        "c",
+        &["--arch", "c"],
+        |src, exe| {
+            let mut cmd = Command::new("cc");
+            cmd.args(["-x", "c"]).arg(src).arg("-o").arg(exe);
+            cmd
+        },
+        |exe| Command::new(exe),
+    );
+}
+
+// Brainfuck output, from the bf backend or `bfc minify`, is checked by
+// evaluating it. It may take more steps than the program it came from.
+fn check_bf(backend: &str, args: &[&str]) {
+    let dir = scratch(backend);
+    for f in fixtures() {
+        let src = dir.join(format!("{}.b", f.name));
+        let code = run(Command::new(BFC).args(args).arg(&f.program), &[]);
+        std::fs::write(&src, code).unwrap();
+        let mut cmd = Command::new(BFC);
+        cmd.arg("--eval").arg(&src);
+        if let Some(n) = f.steps {
+            cmd.args(["--max-steps", &(n * 2).to_string()]);
+        }
+        let output = run_fixture(&mut cmd, &f, &f.input);
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "{} on {}",
+            backend,
+            f.name
+        );
+    }
+}
+
+#[test]
+fn bf() {
+    check_bf("bf", &["--arch", "bf"]);
+}
+
+#[test]
+fn minify() {
+    check_bf("minify", &["minify"]);
+}
+
+fn check_x86_64(nostdlib: bool) {
+    if std::env::consts::ARCH != "x86_64" || !have("cc") {
+        return;
+    }
+    let mut args = vec!["--arch", "x86_64"];
+    if nostdlib {
+        args.push("--nostdlib");
+    }
+    check_backend(
+        if nostdlib {
+            "x86_64-nostdlib"
+        } else {
+            "x86_64"
+        },
+        &args,
+        |src, exe| {
+            let mut cmd = Command::new("cc");
+            if nostdlib {
+                cmd.arg("-nostdlib");
+            }
+            cmd.args(["-x", "assembler"]).arg(src).arg("-o").arg(exe);
+            cmd
+        },
+        |exe| Command::new(exe),
+    );
+}
+
+#[test]
+fn x86_64() {
+    check_x86_64(false);
+}
+
+#[test]
+fn x86_64_nostdlib() {
+    check_x86_64(true);
+}
+
+fn check_riscv(nostdlib: bool) {
+    const CC: &str = "riscv64-linux-gnu-gcc";
+    const QEMU: &str = "qemu-riscv64";
+    if !have(CC) || !have(QEMU) {
+        return;
+    }
+    let mut args = vec!["--arch", "risc-v"];
+    if nostdlib {
+        args.push("--nostdlib");
+    }
+    check_backend(
+        if nostdlib { "riscv-nostdlib" } else { "riscv" },
+        &args,
+        |src, exe| {
+            let mut cmd = Command::new(CC);
+            if nostdlib {
+                cmd.arg("-nostdlib");
+            }
+            cmd.args(["-static", "-x", "assembler"])
+                .arg(src)
+                .arg("-o")
+                .arg(exe);
+            cmd
+        },
+        |exe| {
+            let mut cmd = Command::new(QEMU);
+            cmd.arg(exe);
+            cmd
+        },
+    );
+}
+
+#[test]
+fn riscv() {
+    check_riscv(false);
+}
+
+#[test]
+fn riscv_nostdlib() {
+    check_riscv(true);
+}
+
+#[test]
+fn build_subcommand() {
+    if !have("cc") {
+        return;
+    }
+    let dir = scratch("build");
+    for f in fixtures() {
+        let exe = dir.join(&f.name);
+        run(
+            Command::new(BFC)
+                .args(["build", "--arch", "c"])
+                .arg(&f.program)
+                .arg("-o")
+                .arg(&exe),
+            &[],
+        );
+        let output = run_fixture(&mut Command::new(&exe), &f, &f.input);
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "build on {}",
+            f.name
+        );
+    }
+}
+
+#[test]
+fn build_compiler_fails() {
+    // A compiler that exits without reading the code, which is too big to
+    // fit in the pipe
+    let dir = scratch("fail");
+    let cc = dir.join("cc");
+    std::fs::write(&cc, "#!/bin/sh\necho 'cc: no' >&2\nexit 3\n").unwrap();
+    let mode = std::os::unix::fs::PermissionsExt::from_mode(0o755);
+    std::fs::set_permissions(&cc, mode).unwrap();
+    let program = dir.join("big.b");
+    std::fs::write(&program, ".>".repeat(100_000)).unwrap();
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+    let output = Command::new(BFC)
+        .args(["build", "--arch", "c"])
+        .arg(&program)
+        .arg("-o")
+        .arg(dir.join("big"))
+        .env("PATH", path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cc: no\n"), "{}", stderr);
+    assert!(stderr.contains("cc failed: exit status: 3"), "{}", stderr);
+}
+
+#[test]
+fn run_subcommand() {
+    if !have("cc") {
+        return;
+    }
+    for f in fixtures() {
+        let output = run_fixture(
+            Command::new(BFC)
+                .args(["run", "--arch", "c"])
+                .arg(&f.program),
+            &f,
+            &f.input,
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "run on {}",
+            f.name
+        );
+    }
+}
+
+#[test]
+fn eval_from_stdin() {
+    for f in fixtures() {
+        let mut stdin = std::fs::read(&f.program).unwrap();
+        // Comments may contain the default separator
+        let sep = if stdin.contains(&b'!') { b'~' } else { b'!' };
+        assert!(!stdin.contains(&sep));
+        stdin.push(sep);
+        stdin.extend(&f.input);
+        let output = run_fixture(
+            Command::new(BFC)
+                .arg("--eval")
+                .args(f.step_args())
+                .arg("--separator")
+                .arg((sep as char).to_string()),
+            &f,
+            &stdin,
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "eval from stdin on {}",
+            f.name
+        );
+
+        let output = run_fixture(
+            Command::new(BFC)
+                .arg("--eval")
+                .args(f.step_args())
+                .arg("--input-string")
+                .arg(String::from_utf8(f.input.clone()).unwrap()),
+            &f,
+            &std::fs::read(&f.program).unwrap(),
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&f.output),
+            "eval with --input-string on {}",
+            f.name
+        );
+    }
+}
+
+#[test]
+fn dump_extension() {
+    // Dumps at cell 2, then at cell 17 in the second window
+    let code = ",>,>+++#<<.>.>[->>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<]>>>>>>>>>>>>>>>#!ab";
+    let program = scratch("dump").join("dump.b");
+    std::fs::write(&program, code).unwrap();
+    let expected_err = concat!(
+        "00000000: 61 62>03 00 00 00 00 00 00 00 00 00 00 00 00 00\n",
+        "00000010: 00>03 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n",
+    );
+
+    let eval = |opt: &str| {
+        Command::new(BFC)
+            .args([opt, "--eval", "--ext", "hash", "--ext", "bang"])
+            .arg(&program)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    };
+    for opt in ["-O0", "-O1"] {
+        let output = eval(opt);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ab");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            expected_err,
+            "{}",
+            opt
+        );
+    }
+
+    let mut backends: Vec<(&str, &[&str])> = Vec::new();
+    if have("cc") {
+        backends.push(("c", &["--arch", "c"]));
+        if std::env::consts::ARCH == "x86_64" {
+            backends.push(("x86_64", &["--arch", "x86_64"]));
+            backends.push(("x86_64-nostdlib", &["--arch", "x86_64", "--nostdlib"]));
+        }
+    }
+    for (name, args) in backends {
+        let exe = scratch("dump").join(name);
+        let output = Command::new(BFC)
+            .args(["build", "--ext", "hash", "--ext", "bang", "-o"])
+            .arg(&exe)
+            .args(args)
+            .arg(&program)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "build {}", name);
+        // Compiled programs can't see the input after `!`
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Warning"));
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"ab").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", name);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ab", "{}", name);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            expected_err,
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn lint_werror() {
+    let program = scratch("lint").join("hang.b");
+    std::fs::write(&program, "++\n[>+<]").unwrap();
+    let compile = |args: &[&str]| {
+        Command::new(BFC)
+            .args(["--arch", "c"])
+            .args(args)
+            .arg(&program)
+            .output()
+            .unwrap()
+    };
+    let expected = "2:1: this loop never terminates: [>+<]\n";
+    let output = compile(&[]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Warning: {}", expected)),
+        "{}",
+        stderr
+    );
+
+    let output = compile(&["-Werror"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Error: {}", expected)),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn lint_programs() {
+    // Comment loops aren't reported, so every bundled program passes -Werror
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for entry in std::fs::read_dir(root.join("programs")).unwrap() {
+        let path = entry.unwrap().path();
+        let output = Command::new(BFC)
+            .args(["--arch", "c", "-Werror"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", path.display());
+        assert!(output.stderr.is_empty(), "{}", path.display());
+    }
+}
+
+#[test]
+fn bounds_warnings() {
+    let program = scratch("bounds").join("left.b");
+    std::fs::write(&program, "+[<+]").unwrap();
+    let compile = |args: &[&str]| {
+        Command::new(BFC)
+            .args(["--arch", "c"])
+            .args(args)
+            .arg(&program)
+            .output()
+            .unwrap()
+    };
+    let expected = "the program may move left of cell 0\n";
+    // Only reported under -Wbounds
+    let output = compile(&[]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let output = compile(&["-Wbounds"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Warning: {}", expected)),
+        "{}",
+        stderr
+    );
+
+    let output = compile(&["-Wbounds", "-Werror"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Error: {}", expected)),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn error_format_json() {
+    let dir = scratch("json");
+    let output = |name: &str, code: &str, args: &[&str]| {
+        let program = dir.join(name);
+        std::fs::write(&program, code).unwrap();
+        let output = Command::new(BFC)
+            .args(["--error-format", "json"])
+            .args(args)
+            .arg(&program)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+    let file = |name| format!("\"file\":\"{}\"", dir.join(name).display());
+
+    let (status, stderr) = output("open.b", "+\n[[.]", &["--arch", "c"]);
+    assert_eq!(status, Some(2));
+    assert_eq!(
+        stderr,
+        format!(
+            "{{{},\"severity\":\"error\",\"code\":\"unterminated-loop\",\
+             \"message\":\"`[` without a matching `]`\",\"line\":2,\"column\":1,\
+             \"span\":{{\"start\":2,\"end\":3}}}}\n",
+            file("open.b")
+        )
+    );
+
+    let (status, stderr) = output("hang.b", "++\n[>+<]", &["--arch", "c", "-Werror"]);
+    assert_eq!(status, Some(2));
+    assert!(
+        stderr.contains("\"severity\":\"error\",\"code\":\"infinite-loop\""),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("\"span\":{\"start\":3,\"end\":8}"),
+        "{}",
+        stderr
+    );
+
+    let (status, stderr) = output("left.b", "<.", &["--eval"]);
+    assert_eq!(status, Some(1));
+    assert_eq!(
+        stderr,
+        format!(
+            "{{{},\"severity\":\"error\",\"code\":\"tape-error\",\
+             \"message\":\"access to cell -1 outside the tape after 1 steps\",\
+             \"line\":null,\"column\":null,\"span\":null}}\n",
+            file("left.b")
+        )
+    );
+}
+
+#[test]
+fn lsp_session() {
+    let mut input = Vec::new();
+    for body in [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.b","languageId":"bf","version":1,"text":"[["}}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ] {
+        write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    let mut child = Command::new(BFC)
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("\"code\":\"unterminated-loop\""),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with(r#"{"jsonrpc":"2.0","id":2,"result":null}"#));
+}
+
+#[test]
+fn stats_json() {
+    let program = scratch("stats").join("mul.b");
+    std::fs::write(&program, ",[->++<]>.").unwrap();
+    let output = Command::new(BFC)
+        .args(["--arch", "c", "--stats=json"])
+        .arg(&program)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let json = bfc::json::Json::parse(stderr.lines().next().unwrap()).unwrap();
+    assert_eq!(json.get("ast_nodes").and_then(|n| n.as_usize()), Some(9));
+    assert_eq!(json.get("mul_loops").and_then(|n| n.as_usize()), Some(1));
+    let emitted = json.get("emitted").unwrap();
+    assert!(emitted.get("x86_64").and_then(|n| n.as_usize()).unwrap() > 0);
+}
+
+#[test]
+fn unverified_ir_input() {
+    let program = scratch("verify").join("zero.ir");
+    std::fs::write(&program, "ptrchange 0\n").unwrap();
+    let compile = |args: &[&str]| {
+        Command::new(BFC)
+            .args(["--input-format", "ir", "--arch", "ir"])
+            .args(args)
+            .arg(&program)
+            .output()
+            .unwrap()
+    };
+    // Textual IR is always verified, at any optimization level
+    for args in [&[][..], &["--verify-ir"], &["-O0"]] {
+        let output = compile(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with("IR verification failed: "), "{}", stderr);
+    }
+}
+
+#[test]
+fn check_tape() {
+    // The optimizer drops both stores, since nothing reads them, and the
+    // tape is still found to match
+    let program = scratch("check").join("store.b");
+    std::fs::write(&program, "+>+").unwrap();
+    let output = Command::new(BFC)
+        .args(["--check-opt", "--input-string", ""])
+        .arg(&program)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    // Including the final tape, the bundled programs are optimized correctly
+    for f in fixtures() {
+        run(
+            Command::new(BFC)
+                .arg("--check-opt")
+                .args(f.step_args())
+                .arg(&f.program),
+            &f.input,
+        );
+    }
+}
+
+#[test]
+fn missing_input() {
+    let dir = scratch("input");
+    let program = dir.join("echo.b");
+    std::fs::write(&program, ",.").unwrap();
+    let missing = dir.join("missing.in");
+    for args in [&["--eval"][..], &["debug"]] {
+        let output = Command::new(BFC)
+            .args(args)
+            .arg("--input")
+            .arg(&missing)
+            .arg(&program)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.starts_with(&format!("Error: {}: ", missing.display())),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn invalid_timeout() {
+    let program = scratch("timeout").join("empty.b");
+    std::fs::write(&program, "").unwrap();
+    for timeout in ["-1", "NaN", "inf", "x"] {
+        let output = Command::new(BFC)
+            .args(["--eval", "--input-string", ""])
+            .arg(format!("--timeout={}", timeout))
+            .arg(&program)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{}", timeout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("invalid value"), "{}", stderr);
+    }
+}
+
+#[test]
+fn missing_token_map() {
+    let dir = scratch("tokens");
+    let program = dir.join("empty.txt");
+    std::fs::write(&program, "").unwrap();
+    let missing = dir.join("missing.map");
+    let output = Command::new(BFC)
+        .args(["--syntax", "tokens", "--arch", "c", "--token-map"])
+        .arg(&missing)
+        .arg(&program)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.starts_with(&format!("Error: {}: ", missing.display())),
+        "{}",
+        stderr
+    );
+}